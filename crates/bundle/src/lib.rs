@@ -93,8 +93,24 @@ impl Bundle {
         Ok(())
     }
 
+    /// Adds the source to the first source section. A patch that is already a
+    /// source of the package is updated where it is instead of added again.
     pub fn add_source(&mut self, node: SourceNode) -> miette::Result<()> {
-        if let Some(src_section) = self.package_document.sources.first_mut() {
+        let existing_patch = match &node {
+            SourceNode::Patch(patch) => self
+                .package_document
+                .sources
+                .iter_mut()
+                .flat_map(|section| section.sources.iter_mut())
+                .find(
+                    |src| matches!(src, SourceNode::Patch(p) if p.bundle_path == patch.bundle_path),
+                ),
+            _ => None,
+        };
+
+        if let Some(existing_patch) = existing_patch {
+            *existing_patch = node;
+        } else if let Some(src_section) = self.package_document.sources.first_mut() {
             src_section.sources.push(node);
         } else {
             let src_section = SourceSection {
@@ -116,6 +132,11 @@ impl Bundle {
         self.package_document.name.clone()
     }
 
+    /// The package.lock recording the resolved revisions of the sources.
+    pub fn get_lock_path(&self) -> PathBuf {
+        self.path.join("package.lock")
+    }
+
     pub fn get_mogrify_manifest(&self) -> Option<PathBuf> {
         let file_path = self.path.join("manifest.mog");
         if file_path.exists() {
@@ -187,7 +208,14 @@ pub struct Package {
     #[builder(default)]
     pub dependencies: Vec<Dependency>,
 
-    #[knuffel(children(name = "build"))]
+    /// Fingerprints of the OpenPGP keys allowed to sign the archives of this package
+    #[knuffel(children(name = "trusted-key"), unwrap(argument))]
+    #[builder(default)]
+    pub trusted_keys: Vec<String>,
+
+    // Build sections are matched by their node name (configure, cmake, build, ...) so this
+    // has to stay the last children field to only receive the nodes not consumed above
+    #[knuffel(children)]
     #[builder(default)]
     build_section: Vec<BuildSection>,
 }
@@ -216,6 +244,18 @@ impl Package {
         self.build_section.push(section);
     }
 
+    /// CMake and Meson always configure out of tree so they imply a seperate build directory
+    pub fn uses_seperate_build_dir(&self) -> bool {
+        if self.seperate_build_dir {
+            return true;
+        }
+
+        matches!(
+            self.get_build_section(),
+            Some(BuildSection::Cmake(_)) | Some(BuildSection::Meson(_))
+        )
+    }
+
     pub fn to_node(&self) -> kdl::KdlNode {
         let mut node = kdl::KdlNode::new("package");
         let doc = node.ensure_children();
@@ -298,6 +338,12 @@ impl Package {
             doc.nodes_mut().push(dep_node);
         }
 
+        for trusted_key in &self.trusted_keys {
+            let mut trusted_key_node = kdl::KdlNode::new("trusted-key");
+            trusted_key_node.insert(0, trusted_key.as_str());
+            doc.nodes_mut().push(trusted_key_node);
+        }
+
         node
     }
 
@@ -340,6 +386,12 @@ impl Package {
             self.maintainer = Some(maintainer.clone());
         }
 
+        for trusted_key in &other.trusted_keys {
+            if !self.trusted_keys.contains(trusted_key) {
+                self.trusted_keys.push(trusted_key.clone());
+            }
+        }
+
         if let Some(build_section) = &other.get_build_section() {
             let self_build = self.ensure_build_section();
             let final_build = match build_section {
@@ -368,14 +420,82 @@ impl Package {
                             },
                         }))
                     }
-                    BuildSection::NoBuild => Ok(BuildSection::Configure(other_configure.clone())),
+                    BuildSection::NoBuild(_) => {
+                        Ok(BuildSection::Configure(other_configure.clone()))
+                    }
                     x => Err(BundleError::NonMergableBuildSections(
                         x.to_string(),
                         build_section.clone().to_string(),
                     )),
                 },
-                BuildSection::CMake => todo!(),
-                BuildSection::Meson => todo!(),
+                BuildSection::Cmake(other_cmake) => match self_build {
+                    BuildSection::Cmake(c) => Ok(BuildSection::Cmake(CMakeBuildSection {
+                        defines: c
+                            .defines
+                            .into_iter()
+                            .chain(other_cmake.defines.clone())
+                            .collect(),
+                        generator: if let Some(generator) = &other_cmake.generator {
+                            Some(generator.clone())
+                        } else {
+                            c.generator
+                        },
+                        build_type: if let Some(build_type) = &other_cmake.build_type {
+                            Some(build_type.clone())
+                        } else {
+                            c.build_type
+                        },
+                        toolchain_file: if let Some(toolchain_file) = &other_cmake.toolchain_file {
+                            Some(toolchain_file.clone())
+                        } else {
+                            c.toolchain_file
+                        },
+                    })),
+                    BuildSection::NoBuild(_) => Ok(BuildSection::Cmake(other_cmake.clone())),
+                    x => Err(BundleError::NonMergableBuildSections(
+                        x.to_string(),
+                        build_section.to_string(),
+                    )),
+                },
+                BuildSection::Meson(other_meson) => match self_build {
+                    BuildSection::Meson(m) => Ok(BuildSection::Meson(MesonBuildSection {
+                        options: m
+                            .options
+                            .into_iter()
+                            .chain(other_meson.options.clone())
+                            .collect(),
+                        buildtype: if let Some(buildtype) = &other_meson.buildtype {
+                            Some(buildtype.clone())
+                        } else {
+                            m.buildtype
+                        },
+                        prefix: if let Some(prefix) = &other_meson.prefix {
+                            Some(prefix.clone())
+                        } else {
+                            m.prefix
+                        },
+                        libdir: if let Some(libdir) = &other_meson.libdir {
+                            Some(libdir.clone())
+                        } else {
+                            m.libdir
+                        },
+                        cross_file: if let Some(cross_file) = &other_meson.cross_file {
+                            Some(cross_file.clone())
+                        } else {
+                            m.cross_file
+                        },
+                        native_file: if let Some(native_file) = &other_meson.native_file {
+                            Some(native_file.clone())
+                        } else {
+                            m.native_file
+                        },
+                    })),
+                    BuildSection::NoBuild(_) => Ok(BuildSection::Meson(other_meson.clone())),
+                    x => Err(BundleError::NonMergableBuildSections(
+                        x.to_string(),
+                        build_section.to_string(),
+                    )),
+                },
                 BuildSection::Build(other_scripts) => match self_build {
                     BuildSection::Build(s) => Ok(BuildSection::Build(ScriptBuildSection {
                         scripts: s
@@ -389,13 +509,22 @@ impl Package {
                             .chain(other_scripts.install_directives.clone())
                             .collect(),
                     })),
-                    BuildSection::NoBuild => Ok(BuildSection::Build(other_scripts.clone())),
+                    BuildSection::NoBuild(_) => Ok(BuildSection::Build(other_scripts.clone())),
                     x => Err(BundleError::NonMergableBuildSections(
                         x.to_string(),
                         build_section.to_string(),
                     )),
                 },
-                BuildSection::NoBuild => Ok(BuildSection::NoBuild),
+                BuildSection::NoBuild(other_no_build) => match self_build {
+                    BuildSection::NoBuild(n) => Ok(BuildSection::NoBuild(NoBuildSection {
+                        install_directives: n
+                            .install_directives
+                            .into_iter()
+                            .chain(other_no_build.install_directives.clone())
+                            .collect(),
+                    })),
+                    _ => Ok(BuildSection::NoBuild(other_no_build.clone())),
+                },
             }?;
 
             self.build_section = vec![final_build];
@@ -515,9 +644,30 @@ pub struct ArchiveSource {
 
     #[knuffel(property)]
     pub signature_url: Option<String>,
+
+    /// Other urls serving the same archive, tried in order when src fails
+    #[knuffel(children(name = "mirror"), unwrap(argument))]
+    pub mirrors: Vec<String>,
 }
 
 impl ArchiveSource {
+    /// The url of the detached signature of the archive if it has one.
+    pub fn get_signature_url(&self) -> Option<String> {
+        self.get_signature_url_for(&self.src)
+    }
+
+    /// The url of the detached signature when the archive was downloaded
+    /// from `url` which can be one of its mirrors.
+    pub fn get_signature_url_for(&self, url: &str) -> Option<String> {
+        if let Some(signature_url) = &self.signature_url {
+            Some(signature_url.clone())
+        } else {
+            self.signature_url_extension
+                .as_ref()
+                .map(|ext| format!("{}{}", url, ext))
+        }
+    }
+
     pub fn to_node(&self) -> kdl::KdlNode {
         let mut node = kdl::KdlNode::new("archive");
         node.insert(0, self.src.as_str());
@@ -528,11 +678,19 @@ impl ArchiveSource {
             node.insert("sha256", sha256.as_str());
         }
         if let Some(signature_ext) = &self.signature_url_extension {
-            node.insert("singature-url-extension", signature_ext.as_str());
+            node.insert("signature-url-extension", signature_ext.as_str());
         }
         if let Some(sig_url) = &self.signature_url {
             node.insert("signature-url", sig_url.as_str());
         }
+        if !self.mirrors.is_empty() {
+            let doc = node.ensure_children();
+            for mirror in &self.mirrors {
+                let mut mirror_node = kdl::KdlNode::new("mirror");
+                mirror_node.insert(0, mirror.as_str());
+                doc.nodes_mut().push(mirror_node);
+            }
+        }
         node
     }
 }
@@ -545,6 +703,10 @@ pub struct GitSource {
     pub branch: Option<String>,
     #[knuffel(property)]
     pub tag: Option<String>,
+    /// Pins the source to this commit. Branches and tags without it are
+    /// resolved when fetching and the commit is written to the package.lock
+    #[knuffel(property)]
+    pub commit: Option<String>,
     #[knuffel(property)]
     pub archive: Option<bool>,
     #[knuffel(property)]
@@ -556,17 +718,27 @@ pub struct GitSource {
 }
 
 impl GitSource {
-    pub fn get_repo_prefix(&self) -> String {
+    /// The name of the repository without the .git suffix.
+    pub fn get_repo_name(&self) -> String {
         let repo_prefix_part = self
             .repository
             .rsplit_once('/')
             .unwrap_or(("", &self.repository))
             .1;
-        let repo_prefix = if let Some(split_sucess) = repo_prefix_part.split_once('.') {
+        if let Some(split_sucess) = repo_prefix_part.split_once('.') {
             split_sucess.0.to_string()
         } else {
             repo_prefix_part.to_string()
-        };
+        }
+    }
+
+    /// The name of the archive made from the repository at the given commit.
+    pub fn get_commit_prefix(&self, commit: &str) -> String {
+        format!("{}-{}", self.get_repo_name(), commit)
+    }
+
+    pub fn get_repo_prefix(&self) -> String {
+        let repo_prefix = self.get_repo_name();
 
         if let Some(tag) = &self.tag {
             format!("{}-{}", repo_prefix, tag)
@@ -586,6 +758,9 @@ impl GitSource {
         if let Some(tag) = &self.tag {
             node.insert("tag", tag.as_str());
         }
+        if let Some(commit) = &self.commit {
+            node.insert("commit", commit.as_str());
+        }
         if let Some(archive) = self.archive.clone() {
             node.insert("archive", archive);
         }
@@ -596,6 +771,85 @@ impl GitSource {
     }
 }
 
+/// The commits the git sources of a package resolved to when they were
+/// fetched. It lives as package.lock next to the package.kdl so builds of a
+/// branch stay reproducible until the lock is updated.
+#[derive(Debug, Default, knuffel::Decode, Clone, PartialEq)]
+pub struct PackageLock {
+    #[knuffel(children(name = "git"))]
+    pub git: Vec<LockedGitSource>,
+}
+
+#[derive(Debug, knuffel::Decode, Clone, PartialEq)]
+pub struct LockedGitSource {
+    #[knuffel(argument)]
+    pub repository: String,
+    #[knuffel(property)]
+    pub branch: Option<String>,
+    #[knuffel(property)]
+    pub tag: Option<String>,
+    #[knuffel(property)]
+    pub commit: String,
+}
+
+impl PackageLock {
+    pub fn open<P: AsRef<Path>>(path: P) -> miette::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = read_to_string(path).into_diagnostic()?;
+        Ok(knuffel::parse::<PackageLock>(
+            &path.to_string_lossy(),
+            &contents,
+        )?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> BundleResult<()> {
+        let mut f = File::create(path.as_ref())?;
+        f.write_all(self.to_document().to_string().as_bytes())?;
+        Ok(())
+    }
+
+    /// The locked commit of the git source if it was resolved before.
+    pub fn get_commit(&self, git: &GitSource) -> Option<&str> {
+        self.git
+            .iter()
+            .find(|l| l.repository == git.repository && l.branch == git.branch && l.tag == git.tag)
+            .map(|l| l.commit.as_str())
+    }
+
+    pub fn set_commit(&mut self, git: &GitSource, commit: &str) {
+        self.git.retain(|l| {
+            !(l.repository == git.repository && l.branch == git.branch && l.tag == git.tag)
+        });
+        self.git.push(LockedGitSource {
+            repository: git.repository.clone(),
+            branch: git.branch.clone(),
+            tag: git.tag.clone(),
+            commit: commit.to_owned(),
+        });
+    }
+
+    pub fn to_document(&self) -> kdl::KdlDocument {
+        let mut doc = kdl::KdlDocument::new();
+        for locked in &self.git {
+            let mut node = kdl::KdlNode::new("git");
+            node.insert(0, locked.repository.as_str());
+            if let Some(branch) = &locked.branch {
+                node.insert("branch", branch.as_str());
+            }
+            if let Some(tag) = &locked.tag {
+                node.insert("tag", tag.as_str());
+            }
+            node.insert("commit", locked.commit.as_str());
+            doc.nodes_mut().push(node);
+        }
+        doc
+    }
+}
+
 #[derive(Debug, knuffel::Decode, Clone, Serialize, Deserialize)]
 pub struct FileSource {
     #[knuffel(argument)]
@@ -733,24 +987,31 @@ impl OverlaySource {
     }
 }
 
-#[derive(Debug, Default, knuffel::Decode, Clone, Serialize, Deserialize)]
+#[derive(Debug, knuffel::Decode, Clone, Serialize, Deserialize)]
 pub enum BuildSection {
     Configure(ConfigureBuildSection),
-    CMake,
-    Meson,
+    // knuffel derives the node name from the variant name in kebab-case, `CMake` would
+    // only match `c-make` nodes
+    Cmake(CMakeBuildSection),
+    Meson(MesonBuildSection),
     Build(ScriptBuildSection),
-    #[default]
-    NoBuild,
+    NoBuild(NoBuildSection),
+}
+
+impl Default for BuildSection {
+    fn default() -> Self {
+        BuildSection::NoBuild(NoBuildSection::default())
+    }
 }
 
 impl ToString for BuildSection {
     fn to_string(&self) -> String {
         match &self {
             BuildSection::Configure(_) => "configure",
-            BuildSection::CMake => "cmake",
-            BuildSection::Meson => "meson",
+            BuildSection::Cmake(_) => "cmake",
+            BuildSection::Meson(_) => "meson",
             BuildSection::Build(_) => "build",
-            BuildSection::NoBuild => "no-build",
+            BuildSection::NoBuild(_) => "no-build",
         }
         .to_string()
     }
@@ -768,6 +1029,88 @@ pub struct ConfigureBuildSection {
     pub linker: Option<String>,
 }
 
+#[derive(Debug, Default, knuffel::Decode, Clone, Serialize, Deserialize)]
+pub struct CMakeBuildSection {
+    #[knuffel(children(name = "define"))]
+    pub defines: Vec<CMakeDefineNode>,
+    #[knuffel(child, unwrap(argument))]
+    pub generator: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub build_type: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub toolchain_file: Option<String>,
+}
+
+/// A cache entry passed to cmake as `-D<name>[:<type>]=<value>`
+#[derive(Debug, Default, knuffel::Decode, Clone, Serialize, Deserialize)]
+pub struct CMakeDefineNode {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(argument)]
+    pub value: String,
+    #[knuffel(property(name = "type"))]
+    pub define_type: Option<String>,
+}
+
+impl CMakeDefineNode {
+    pub fn to_arg(&self) -> String {
+        if let Some(define_type) = &self.define_type {
+            format!("-D{}:{}={}", self.name, define_type, self.value)
+        } else {
+            format!("-D{}={}", self.name, self.value)
+        }
+    }
+
+    pub fn to_node(&self) -> kdl::KdlNode {
+        let mut node = kdl::KdlNode::new("define");
+        node.insert(0, self.name.as_str());
+        node.insert(1, self.value.as_str());
+        if let Some(define_type) = &self.define_type {
+            node.insert("type", define_type.as_str());
+        }
+        node
+    }
+}
+
+#[derive(Debug, Default, knuffel::Decode, Clone, Serialize, Deserialize)]
+pub struct MesonBuildSection {
+    #[knuffel(children(name = "option"))]
+    pub options: Vec<MesonOptionNode>,
+    #[knuffel(child, unwrap(argument))]
+    pub buildtype: Option<String>,
+    /// Overrides the package prefix for meson only
+    #[knuffel(child, unwrap(argument))]
+    pub prefix: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub libdir: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub cross_file: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub native_file: Option<String>,
+}
+
+/// A project option passed to meson as `-D<name>=<value>`
+#[derive(Debug, Default, knuffel::Decode, Clone, Serialize, Deserialize)]
+pub struct MesonOptionNode {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(argument)]
+    pub value: String,
+}
+
+impl MesonOptionNode {
+    pub fn to_arg(&self) -> String {
+        format!("-D{}={}", self.name, self.value)
+    }
+
+    pub fn to_node(&self) -> kdl::KdlNode {
+        let mut node = kdl::KdlNode::new("option");
+        node.insert(0, self.name.as_str());
+        node.insert(1, self.value.as_str());
+        node
+    }
+}
+
 #[derive(Debug, Default, knuffel::Decode, Clone, Serialize, Deserialize)]
 pub struct ScriptBuildSection {
     #[knuffel(children(name = "script"))]
@@ -776,6 +1119,14 @@ pub struct ScriptBuildSection {
     pub install_directives: Vec<InstallDirectiveNode>,
 }
 
+/// Packages that only stage files. Without install directives the unpacked sources are
+/// copied into the prototype directory as they are.
+#[derive(Debug, Default, knuffel::Decode, Clone, Serialize, Deserialize)]
+pub struct NoBuildSection {
+    #[knuffel(children(name = "install"))]
+    pub install_directives: Vec<InstallDirectiveNode>,
+}
+
 #[derive(Debug, Default, knuffel::Decode, Clone, Serialize, Deserialize)]
 pub struct InstallDirectiveNode {
     #[knuffel(property)]
@@ -792,10 +1143,16 @@ pub struct InstallDirectiveNode {
 
 impl InstallDirectiveNode {
     pub fn to_node(&self) -> kdl::KdlNode {
-        let mut node = kdl::KdlNode::new("package-directory");
+        let mut node = kdl::KdlNode::new("install");
         node.insert("src", self.src.as_str());
         node.insert("target", self.target.as_str());
         node.insert("name", self.name.as_str());
+        if let Some(pattern) = &self.pattern {
+            node.insert("pattern", pattern.as_str());
+        }
+        if let Some(fmatch) = &self.fmatch {
+            node.insert("match", fmatch.as_str());
+        }
         node
     }
 }
@@ -880,8 +1237,56 @@ impl BuildSection {
 
                 node
             }
-            BuildSection::CMake => todo!(),
-            BuildSection::Meson => todo!(),
+            BuildSection::Cmake(c) => {
+                let mut node = kdl::KdlNode::new("cmake");
+                let doc = node.ensure_children();
+                for define in &c.defines {
+                    doc.nodes_mut().push(define.to_node());
+                }
+
+                if let Some(generator) = &c.generator {
+                    let mut n = kdl::KdlNode::new("generator");
+                    n.insert(0, generator.clone());
+                    doc.nodes_mut().push(n);
+                }
+
+                if let Some(build_type) = &c.build_type {
+                    let mut n = kdl::KdlNode::new("build-type");
+                    n.insert(0, build_type.clone());
+                    doc.nodes_mut().push(n);
+                }
+
+                if let Some(toolchain_file) = &c.toolchain_file {
+                    let mut n = kdl::KdlNode::new("toolchain-file");
+                    n.insert(0, toolchain_file.clone());
+                    doc.nodes_mut().push(n);
+                }
+
+                node
+            }
+            BuildSection::Meson(m) => {
+                let mut node = kdl::KdlNode::new("meson");
+                let doc = node.ensure_children();
+                for option in &m.options {
+                    doc.nodes_mut().push(option.to_node());
+                }
+
+                for (name, value) in [
+                    ("buildtype", &m.buildtype),
+                    ("prefix", &m.prefix),
+                    ("libdir", &m.libdir),
+                    ("cross-file", &m.cross_file),
+                    ("native-file", &m.native_file),
+                ] {
+                    if let Some(value) = value {
+                        let mut n = kdl::KdlNode::new(name);
+                        n.insert(0, value.clone());
+                        doc.nodes_mut().push(n);
+                    }
+                }
+
+                node
+            }
             BuildSection::Build(s) => {
                 let mut node = kdl::KdlNode::new("build");
                 let doc = node.ensure_children();
@@ -895,7 +1300,16 @@ impl BuildSection {
 
                 node
             }
-            BuildSection::NoBuild => kdl::KdlNode::new("no-build"),
+            BuildSection::NoBuild(n) => {
+                let mut node = kdl::KdlNode::new("no-build");
+                if !n.install_directives.is_empty() {
+                    let doc = node.ensure_children();
+                    for install_directive in &n.install_directives {
+                        doc.nodes_mut().push(install_directive.to_node());
+                    }
+                }
+                node
+            }
         }
     }
 }
@@ -962,6 +1376,225 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn cmake_section_roundtrip() -> miette::Result<()> {
+        let doc = r#"
+            name "library/cmake-sample"
+            project-name "cmake-sample"
+            cmake {
+                define "BUILD_SHARED_LIBS" "ON" type="BOOL"
+                define "ENABLE_TESTS" "OFF"
+                generator "Ninja"
+                build-type "Release"
+                toolchain-file "aarch64.cmake"
+            }
+        "#;
+        let pkg = knuffel::parse::<Package>("package.kdl", doc)?;
+        let section = pkg.get_build_section();
+        let Some(BuildSection::Cmake(cmake)) = section else {
+            panic!("expected a cmake build section got {:?}", section);
+        };
+        assert_eq!(cmake.defines.len(), 2);
+        assert_eq!(cmake.defines[0].to_arg(), "-DBUILD_SHARED_LIBS:BOOL=ON");
+        assert_eq!(cmake.defines[1].to_arg(), "-DENABLE_TESTS=OFF");
+        assert!(pkg.uses_seperate_build_dir());
+
+        let reparsed = knuffel::parse::<Package>("package.kdl", &pkg.to_document().to_string())?;
+        let Some(BuildSection::Cmake(reparsed)) = reparsed.get_build_section() else {
+            panic!("cmake section did not survive a roundtrip");
+        };
+        assert_eq!(reparsed.generator.as_deref(), Some("Ninja"));
+        assert_eq!(reparsed.build_type.as_deref(), Some("Release"));
+        assert_eq!(reparsed.toolchain_file.as_deref(), Some("aarch64.cmake"));
+        assert_eq!(reparsed.defines[0].define_type.as_deref(), Some("BOOL"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn meson_section_roundtrip() -> miette::Result<()> {
+        let doc = r#"
+            name "library/glib"
+            project-name "glib"
+            meson {
+                option "tests" "false"
+                option "introspection" "disabled"
+                buildtype "release"
+                libdir "lib/amd64"
+                cross-file "aarch64-illumos.ini"
+            }
+        "#;
+        let pkg = knuffel::parse::<Package>("package.kdl", doc)?;
+        let reparsed = knuffel::parse::<Package>("package.kdl", &pkg.to_document().to_string())?;
+        let Some(BuildSection::Meson(meson)) = reparsed.get_build_section() else {
+            panic!("meson section did not survive a roundtrip");
+        };
+        assert_eq!(
+            meson
+                .options
+                .iter()
+                .map(|o| o.to_arg())
+                .collect::<Vec<String>>(),
+            vec!["-Dtests=false", "-Dintrospection=disabled"]
+        );
+        assert_eq!(meson.buildtype.as_deref(), Some("release"));
+        assert_eq!(meson.libdir.as_deref(), Some("lib/amd64"));
+        assert_eq!(meson.cross_file.as_deref(), Some("aarch64-illumos.ini"));
+        assert_eq!(meson.native_file, None);
+        assert!(reparsed.uses_seperate_build_dir());
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_build_section_roundtrip() -> miette::Result<()> {
+        let doc = r#"
+            name "system/smf-manifests"
+            project-name "smf-manifests"
+            source {
+                file "etcd.xml"
+            }
+            no-build {
+                install src="etcd.xml" target="lib/svc/manifest/database/etcd.xml" name="manifest"
+                install src="conf" target="etc/etcd" name="config" match="*.yaml"
+            }
+        "#;
+        let pkg = knuffel::parse::<Package>("package.kdl", doc)?;
+        let reparsed = knuffel::parse::<Package>("package.kdl", &pkg.to_document().to_string())?;
+        let Some(BuildSection::NoBuild(no_build)) = reparsed.get_build_section() else {
+            panic!("no-build section did not survive a roundtrip");
+        };
+        assert_eq!(no_build.install_directives.len(), 2);
+        assert_eq!(
+            no_build.install_directives[0].target,
+            "lib/svc/manifest/database/etcd.xml"
+        );
+        assert_eq!(
+            no_build.install_directives[1].fmatch.as_deref(),
+            Some("*.yaml")
+        );
+
+        let plain = knuffel::parse::<Package>(
+            "package.kdl",
+            r#"
+            name "system/prebuilt"
+            project-name "prebuilt"
+            no-build
+        "#,
+        )?;
+        assert!(matches!(
+            plain.ensure_build_section(),
+            BuildSection::NoBuild(n) if n.install_directives.is_empty()
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_build_without_sources_roundtrip() -> miette::Result<()> {
+        let doc = r#"
+            name "system/site-config"
+            project-name "site-config"
+            no-build {
+                install src="files/motd" target="etc/motd" name="motd"
+            }
+        "#;
+        let pkg = knuffel::parse::<Package>("package.kdl", doc)?;
+        assert!(pkg.sources.is_empty());
+
+        let reparsed = knuffel::parse::<Package>("package.kdl", &pkg.to_document().to_string())?;
+        assert!(reparsed.sources.is_empty());
+        let Some(BuildSection::NoBuild(no_build)) = reparsed.get_build_section() else {
+            panic!("no-build section did not survive a roundtrip");
+        };
+        assert_eq!(no_build.install_directives.len(), 1);
+        assert_eq!(no_build.install_directives[0].src, "files/motd");
+
+        Ok(())
+    }
+
+    #[test]
+    fn package_lock_roundtrip() -> miette::Result<()> {
+        let doc = r#"
+            name "developer/gcc-10"
+            project-name "gcc"
+            source {
+                git "https://github.com/illumos/gcc.git" branch="il-10_3_0-arm64"
+                git "https://github.com/illumos/illumos-gate.git" commit="0123456789abcdef"
+            }
+        "#;
+        let pkg = knuffel::parse::<Package>("package.kdl", doc)?;
+        let reparsed = knuffel::parse::<Package>("package.kdl", &pkg.to_document().to_string())?;
+        let gits = reparsed.sources[0]
+            .sources
+            .iter()
+            .filter_map(|s| match s {
+                SourceNode::Git(g) => Some(g),
+                _ => None,
+            })
+            .collect::<Vec<&GitSource>>();
+        assert_eq!(gits[1].commit.as_deref(), Some("0123456789abcdef"));
+        assert_eq!(gits[0].get_commit_prefix("abc"), "gcc-abc");
+
+        let mut lock = PackageLock::default();
+        lock.set_commit(gits[0], "1111");
+        lock.set_commit(gits[0], "2222");
+        let relocked =
+            knuffel::parse::<PackageLock>("package.lock", &lock.to_document().to_string())?;
+        assert_eq!(relocked, lock);
+        assert_eq!(relocked.git.len(), 1);
+        assert_eq!(relocked.get_commit(gits[0]), Some("2222"));
+        assert_eq!(relocked.get_commit(gits[1]), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn archive_signature_and_mirror_roundtrip() -> miette::Result<()> {
+        let doc = r#"
+            name "library/zlib"
+            project-name "zlib"
+            source {
+                archive "https://zlib.net/fossils/zlib-1.2.13.tar.gz" signature-url-extension=".asc" {
+                    mirror "https://github.com/madler/zlib/releases/download/v1.2.13/zlib-1.2.13.tar.gz"
+                }
+                archive "https://example.org/other.tar.xz" signature-url="https://example.org/sigs/other.sig"
+            }
+            trusted-key "5ED46A6721D365587791E2AA783FCD8E58BCAFBA"
+        "#;
+        let pkg = knuffel::parse::<Package>("package.kdl", doc)?;
+        let reparsed = knuffel::parse::<Package>("package.kdl", &pkg.to_document().to_string())?;
+        let archives = reparsed.sources[0]
+            .sources
+            .iter()
+            .filter_map(|s| match s {
+                SourceNode::Archive(a) => Some(a),
+                _ => None,
+            })
+            .collect::<Vec<&ArchiveSource>>();
+        assert_eq!(
+            archives[0].get_signature_url().as_deref(),
+            Some("https://zlib.net/fossils/zlib-1.2.13.tar.gz.asc")
+        );
+        assert_eq!(
+            archives[0]
+                .get_signature_url_for(&archives[0].mirrors[0])
+                .as_deref(),
+            Some("https://github.com/madler/zlib/releases/download/v1.2.13/zlib-1.2.13.tar.gz.asc")
+        );
+        assert_eq!(
+            archives[1].get_signature_url().as_deref(),
+            Some("https://example.org/sigs/other.sig")
+        );
+        assert!(archives[1].mirrors.is_empty());
+        assert_eq!(
+            reparsed.trusted_keys,
+            vec![String::from("5ED46A6721D365587791E2AA783FCD8E58BCAFBA")]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn parse_binutils_gdb() -> miette::Result<()> {
         let bundle_path = Path::new("../packages/binutils-gdb");
@@ -2,6 +2,7 @@ use bundle::Package;
 use miette::{Diagnostic, IntoDiagnostic};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fs::{read_to_string, File},
     io::Write,
     path::{Path, PathBuf},
@@ -28,6 +29,14 @@ pub enum GateError {
     NoSuchPackage(String),
     #[error("distribution type {0} is not known use one of 'tarball', 'ips'")]
     UnknownDistributionType(String),
+    #[error("mirror layout {0} is not known use one of 'name', 'sha256', 'sha512'")]
+    UnknownMirrorLayout(String),
+    #[error("the packages of the gate depend on each other in a cycle: {0}")]
+    #[diagnostic(
+        code(gate::dependency_cycle),
+        help("remove one of the dependency nodes in the cycle so the packages can be built one after the other")
+    )]
+    DependencyCycle(String),
 }
 
 type GateResult<T> = std::result::Result<T, GateError>;
@@ -49,6 +58,15 @@ pub struct Gate {
     pub default_transforms: Vec<Transform>,
     #[knuffel(child, unwrap(argument))]
     pub publisher: String,
+    /// OpenPGP keyring relative to the gate file used to verify archive signatures
+    #[knuffel(child, unwrap(argument))]
+    pub keyring: Option<String>,
+    /// Mirrors tried for every archive after the urls of the archive itself
+    #[knuffel(children(name = "mirror"))]
+    pub mirrors: Vec<Mirror>,
+    /// Platforms the packages of the gate can be cross compiled for
+    #[knuffel(children(name = "target"))]
+    pub targets: Vec<Target>,
 }
 
 impl Default for Gate {
@@ -62,6 +80,9 @@ impl Default for Gate {
             packages: vec![],
             default_transforms: vec![],
             publisher: String::from("userland"),
+            keyring: None,
+            mirrors: vec![],
+            targets: vec![],
         }
     }
 }
@@ -103,6 +124,40 @@ impl Gate {
         gate_packages.first().map(|p| p.clone())
     }
 
+    pub fn get_keyring_path(&self) -> Option<PathBuf> {
+        self.keyring
+            .as_ref()
+            .map(|keyring| self.path.parent().unwrap_or(Path::new("./")).join(keyring))
+    }
+
+    /// Finds the target by its name or triple. A relative sysroot is resolved
+    /// against the directory of the gate file.
+    pub fn get_target(&self, name: &str) -> Option<Target> {
+        let mut target = self
+            .targets
+            .iter()
+            .find(|t| t.name == name || t.triple == name)?
+            .clone();
+        target.sysroot = target.sysroot.map(|sysroot| {
+            self.path
+                .parent()
+                .unwrap_or(Path::new("./"))
+                .join(sysroot)
+                .to_string_lossy()
+                .to_string()
+        });
+        Some(target)
+    }
+
+    /// The lock file next to the gate file recording the inputs of its packages.
+    pub fn get_lock_path(&self) -> PathBuf {
+        self.path.with_extension("lock")
+    }
+
+    pub fn packages(&self) -> &[Package] {
+        &self.packages
+    }
+
     pub fn to_document(&self) -> kdl::KdlDocument {
         let node = self.to_node();
         node.children().unwrap_or(&kdl::KdlDocument::new()).clone()
@@ -138,6 +193,20 @@ impl Gate {
             doc.nodes_mut().push(tr_node);
         }
 
+        for mirror in &self.mirrors {
+            doc.nodes_mut().push(mirror.to_node());
+        }
+
+        for target in &self.targets {
+            doc.nodes_mut().push(target.to_node());
+        }
+
+        if let Some(keyring) = &self.keyring {
+            let mut keyring_node = kdl::KdlNode::new("keyring");
+            keyring_node.insert(0, keyring.as_str());
+            doc.nodes_mut().push(keyring_node);
+        }
+
         node
     }
 
@@ -149,6 +218,77 @@ impl Gate {
     }
 }
 
+/// Orders the packages so that every package comes after the packages of the
+/// list it depends on. Dependencies on packages outside of the list are
+/// expected to be installed on the build host and do not change the order.
+/// Packages without dependencies between them keep their order in the list.
+pub fn build_order(packages: &[Package]) -> GateResult<Vec<&Package>> {
+    let index: HashMap<&str, usize> = packages
+        .iter()
+        .enumerate()
+        .map(|(idx, p)| (p.name.as_str(), idx))
+        .collect();
+
+    let dependencies: Vec<Vec<usize>> = packages
+        .iter()
+        .map(|p| {
+            p.dependencies
+                .iter()
+                .filter_map(|d| index.get(d.name.as_str()).copied())
+                .collect()
+        })
+        .collect();
+
+    let mut placed = vec![false; packages.len()];
+    let mut order = Vec::with_capacity(packages.len());
+
+    while order.len() < packages.len() {
+        let next = (0..packages.len())
+            .find(|&idx| !placed[idx] && dependencies[idx].iter().all(|&dep| placed[dep]));
+
+        match next {
+            Some(idx) => {
+                placed[idx] = true;
+                order.push(&packages[idx]);
+            }
+            None => {
+                return Err(GateError::DependencyCycle(find_cycle(
+                    packages,
+                    &dependencies,
+                    &placed,
+                )))
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+/// Follows the unbuildable dependencies from the first package that could not
+/// be placed until a package repeats. Only called when every remaining
+/// package still has a dependency that is not placed, so the walk always
+/// ends in a cycle.
+fn find_cycle(packages: &[Package], dependencies: &[Vec<usize>], placed: &[bool]) -> String {
+    let mut path: Vec<usize> = vec![];
+    let mut current = (0..packages.len()).find(|&idx| !placed[idx]).unwrap();
+
+    while !path.contains(&current) {
+        path.push(current);
+        current = *dependencies[current]
+            .iter()
+            .find(|&&dep| !placed[dep])
+            .unwrap();
+    }
+
+    let start = path.iter().position(|&idx| idx == current).unwrap();
+    path[start..]
+        .iter()
+        .chain(std::iter::once(&current))
+        .map(|&idx| packages[idx].name.as_str())
+        .collect::<Vec<&str>>()
+        .join(" -> ")
+}
+
 #[derive(Debug, knuffel::Decode, Clone, Serialize, Deserialize)]
 pub struct Transform {
     #[knuffel(arguments)]
@@ -158,6 +298,11 @@ pub struct Transform {
 }
 
 impl Transform {
+    /// The transform file the transform includes.
+    pub fn get_include(&self) -> Option<&str> {
+        self.include.as_deref()
+    }
+
     pub fn to_string(&self) -> String {
         let mut lines = self.actions.clone();
         if let Some(include_prop) = &self.include {
@@ -181,6 +326,185 @@ impl Transform {
     }
 }
 
+/// A distfiles mirror serving archives below its url either by their file
+/// name or by their checksum.
+#[derive(Debug, knuffel::Decode, Clone, Serialize, Deserialize)]
+pub struct Mirror {
+    #[knuffel(argument)]
+    pub url: String,
+    #[knuffel(property(name = "by"), default, str)]
+    pub layout: MirrorLayout,
+}
+
+impl Mirror {
+    /// The url of an archive on this mirror. Mirrors keyed by a checksum can
+    /// only serve archives that declare that checksum.
+    pub fn archive_url(
+        &self,
+        file_name: &str,
+        sha256: Option<&str>,
+        sha512: Option<&str>,
+    ) -> Option<String> {
+        let key = match self.layout {
+            MirrorLayout::FileName => Some(file_name),
+            MirrorLayout::Sha256 => sha256,
+            MirrorLayout::Sha512 => sha512,
+        }?;
+        Some(format!("{}/{}", self.url.trim_end_matches('/'), key))
+    }
+
+    pub fn to_node(&self) -> kdl::KdlNode {
+        let mut node = kdl::KdlNode::new("mirror");
+        node.insert(0, self.url.as_str());
+        if !matches!(self.layout, MirrorLayout::FileName) {
+            node.insert("by", self.layout.to_string().as_str());
+        }
+        node
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MirrorLayout {
+    FileName,
+    Sha256,
+    Sha512,
+}
+
+impl Default for MirrorLayout {
+    fn default() -> Self {
+        Self::FileName
+    }
+}
+
+impl FromStr for MirrorLayout {
+    type Err = GateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" | "file-name" => Ok(Self::FileName),
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            x => Err(GateError::UnknownMirrorLayout(x.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for MirrorLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MirrorLayout::FileName => write!(f, "name"),
+            MirrorLayout::Sha256 => write!(f, "sha256"),
+            MirrorLayout::Sha512 => write!(f, "sha512"),
+        }
+    }
+}
+
+/// Cross compilation settings for one platform.
+#[derive(Debug, knuffel::Decode, Clone, Serialize, Deserialize)]
+pub struct Target {
+    #[knuffel(argument)]
+    pub name: String,
+    #[knuffel(child, unwrap(argument))]
+    pub triple: String,
+    /// Root of the headers and libraries of the target
+    #[knuffel(child, unwrap(argument))]
+    pub sysroot: Option<String>,
+    /// Prefix of the cross tools, the triple followed by a dash if not set
+    #[knuffel(child, unwrap(argument))]
+    pub tool_prefix: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub cflags: Option<String>,
+    #[knuffel(child, unwrap(argument))]
+    pub ldflags: Option<String>,
+}
+
+impl Target {
+    pub fn get_tool_prefix(&self) -> String {
+        self.tool_prefix
+            .clone()
+            .unwrap_or_else(|| format!("{}-", self.triple))
+    }
+
+    /// The variables pointing compilers and build systems at the cross tools
+    /// and the sysroot of the target.
+    pub fn environment(&self) -> Vec<(String, String)> {
+        let prefix = self.get_tool_prefix();
+        let mut env = [
+            ("CC", "gcc"),
+            ("CXX", "g++"),
+            ("CPP", "cpp"),
+            ("LD", "ld"),
+            ("AS", "as"),
+            ("AR", "ar"),
+            ("NM", "nm"),
+            ("RANLIB", "ranlib"),
+            ("STRIP", "strip"),
+            ("OBJCOPY", "objcopy"),
+        ]
+        .into_iter()
+        .map(|(var, tool)| (var.to_string(), format!("{}{}", prefix, tool)))
+        .collect::<Vec<(String, String)>>();
+
+        let sysroot_flag = self
+            .sysroot
+            .as_ref()
+            .map(|sysroot| format!("--sysroot={}", sysroot));
+        let join = |flags: [Option<&String>; 2]| {
+            flags
+                .into_iter()
+                .flatten()
+                .map(|f| f.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        };
+        let cflags = join([sysroot_flag.as_ref(), self.cflags.as_ref()]);
+        let ldflags = join([sysroot_flag.as_ref(), self.ldflags.as_ref()]);
+        for (var, value) in [
+            ("CFLAGS", &cflags),
+            ("CXXFLAGS", &cflags),
+            ("LDFLAGS", &ldflags),
+        ] {
+            if !value.is_empty() {
+                env.push((var.to_string(), value.clone()));
+            }
+        }
+
+        env.push((String::from("TARGET_TRIPLE"), self.triple.clone()));
+        if let Some(sysroot) = &self.sysroot {
+            env.push((String::from("TARGET_SYSROOT"), sysroot.clone()));
+            env.push((String::from("PKG_CONFIG_SYSROOT_DIR"), sysroot.clone()));
+            env.push((
+                String::from("PKG_CONFIG_LIBDIR"),
+                format!(
+                    "{0}/usr/lib/pkgconfig:{0}/usr/share/pkgconfig",
+                    sysroot.trim_end_matches('/')
+                ),
+            ));
+        }
+        env
+    }
+
+    pub fn to_node(&self) -> kdl::KdlNode {
+        let mut node = kdl::KdlNode::new("target");
+        node.insert(0, self.name.as_str());
+        let doc = node.ensure_children();
+        for (name, value) in [
+            ("triple", Some(&self.triple)),
+            ("sysroot", self.sysroot.as_ref()),
+            ("tool-prefix", self.tool_prefix.as_ref()),
+            ("cflags", self.cflags.as_ref()),
+            ("ldflags", self.ldflags.as_ref()),
+        ] {
+            if let Some(value) = value {
+                let mut child = kdl::KdlNode::new(name);
+                child.insert(0, value.as_str());
+                doc.nodes_mut().push(child);
+            }
+        }
+        node
+    }
+}
+
 #[derive(Debug, knuffel::Decode, Clone, Serialize, Deserialize)]
 pub struct Distribution {
     #[knuffel(property(name = "type"), default, str)]
@@ -230,3 +554,135 @@ impl ToString for DistributionType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bundle::{Dependency, PackageBuilder};
+
+    fn package(name: &str, dependencies: &[&str]) -> Package {
+        PackageBuilder::default()
+            .name(name)
+            .project_name(name)
+            .dependencies(
+                dependencies
+                    .iter()
+                    .map(|d| Dependency {
+                        name: d.to_string(),
+                        dev: false,
+                        kind: None,
+                    })
+                    .collect::<Vec<Dependency>>(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn build_order_follows_dependencies() {
+        let packages = vec![
+            package(
+                "network/storage/garage",
+                &["database/sqlite-3", "developer/gcc"],
+            ),
+            package("developer/gcc", &["developer/gnu-binutils"]),
+            package("developer/gnu-binutils", &[]),
+            package("database/etcd", &[]),
+        ];
+
+        let order = build_order(&packages)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(
+            order,
+            vec![
+                "developer/gnu-binutils",
+                "developer/gcc",
+                "network/storage/garage",
+                "database/etcd",
+            ]
+        );
+    }
+
+    #[test]
+    fn mirror_archive_urls() {
+        let by_name = Mirror {
+            url: String::from("https://distfiles.example.org/"),
+            layout: MirrorLayout::FileName,
+        };
+        let by_hash = Mirror {
+            url: String::from("https://cache.example.org/sha256"),
+            layout: MirrorLayout::Sha256,
+        };
+
+        assert_eq!(
+            by_name.archive_url("zlib-1.2.13.tar.gz", None, None),
+            Some(String::from(
+                "https://distfiles.example.org/zlib-1.2.13.tar.gz"
+            ))
+        );
+        assert_eq!(by_hash.archive_url("zlib-1.2.13.tar.gz", None, None), None);
+        assert_eq!(
+            by_hash.archive_url("zlib-1.2.13.tar.gz", Some("b3a2"), None),
+            Some(String::from("https://cache.example.org/sha256/b3a2"))
+        );
+        assert_eq!(
+            "sha512".parse::<MirrorLayout>().unwrap().to_string(),
+            "sha512"
+        );
+        assert!("md5".parse::<MirrorLayout>().is_err());
+    }
+
+    #[test]
+    fn build_order_detects_cycles() {
+        let packages = vec![
+            package("library/a", &[]),
+            package("library/b", &["library/c"]),
+            package("library/c", &["library/d"]),
+            package("library/d", &["library/b"]),
+        ];
+
+        match build_order(&packages) {
+            Err(GateError::DependencyCycle(cycle)) => {
+                assert_eq!(cycle, "library/b -> library/c -> library/d -> library/b")
+            }
+            x => panic!("expected a dependency cycle got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn target_environment() {
+        let target = Target {
+            name: String::from("aarch64"),
+            triple: String::from("aarch64-unknown-solaris2.11"),
+            sysroot: Some(String::from("/opt/cross/aarch64/sysroot/")),
+            tool_prefix: None,
+            cflags: Some(String::from("-O2")),
+            ldflags: None,
+        };
+        let env = target.environment().into_iter().collect::<HashMap<_, _>>();
+
+        assert_eq!(env["CC"], "aarch64-unknown-solaris2.11-gcc");
+        assert_eq!(env["LD"], "aarch64-unknown-solaris2.11-ld");
+        assert_eq!(env["CFLAGS"], "--sysroot=/opt/cross/aarch64/sysroot/ -O2");
+        assert_eq!(env["LDFLAGS"], "--sysroot=/opt/cross/aarch64/sysroot/");
+        assert_eq!(
+            env["PKG_CONFIG_LIBDIR"],
+            "/opt/cross/aarch64/sysroot/usr/lib/pkgconfig:/opt/cross/aarch64/sysroot/usr/share/pkgconfig"
+        );
+
+        let target = Target {
+            tool_prefix: Some(String::from("/opt/cross/bin/aarch64-")),
+            sysroot: None,
+            cflags: None,
+            ..target
+        };
+        let env = target.environment().into_iter().collect::<HashMap<_, _>>();
+        assert_eq!(env["CXX"], "/opt/cross/bin/aarch64-g++");
+        assert!(!env.contains_key("CFLAGS"));
+        assert!(!env.contains_key("TARGET_SYSROOT"));
+    }
+}
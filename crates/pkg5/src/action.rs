@@ -0,0 +1,346 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+#[derive(Error, Debug, Diagnostic)]
+pub enum ActionError {
+    #[error("empty action")]
+    #[diagnostic(code(pkg5::action::empty))]
+    Empty,
+    #[error("unterminated quote in value of attribute {attribute} in action: {line}")]
+    #[diagnostic(code(pkg5::action::unterminated_quote))]
+    UnterminatedQuote { attribute: String, line: String },
+    #[error("attribute {attribute} has no value in action: {line}")]
+    #[diagnostic(code(pkg5::action::missing_value))]
+    MissingValue { attribute: String, line: String },
+    #[error("unexpected text {text} in action: {line}")]
+    #[diagnostic(code(pkg5::action::unexpected_text))]
+    UnexpectedText { text: String, line: String },
+}
+
+type ActionResult<T> = std::result::Result<T, ActionError>;
+
+/// The type of an action. Types pkg(5) does not know about are kept as
+/// [`ActionKind::Other`] so manifests with them can still be rewritten.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ActionKind {
+    Set,
+    File,
+    Dir,
+    Link,
+    Hardlink,
+    Depend,
+    License,
+    Legacy,
+    Group,
+    User,
+    Driver,
+    Signature,
+    Other(String),
+}
+
+impl ActionKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ActionKind::Set => "set",
+            ActionKind::File => "file",
+            ActionKind::Dir => "dir",
+            ActionKind::Link => "link",
+            ActionKind::Hardlink => "hardlink",
+            ActionKind::Depend => "depend",
+            ActionKind::License => "license",
+            ActionKind::Legacy => "legacy",
+            ActionKind::Group => "group",
+            ActionKind::User => "user",
+            ActionKind::Driver => "driver",
+            ActionKind::Signature => "signature",
+            ActionKind::Other(kind) => kind,
+        }
+    }
+
+    /// The attribute identifying an action of this type within a package.
+    pub fn key_attr(&self) -> Option<&'static str> {
+        match self {
+            ActionKind::Set | ActionKind::Driver => Some("name"),
+            ActionKind::File | ActionKind::Dir | ActionKind::Link | ActionKind::Hardlink => {
+                Some("path")
+            }
+            ActionKind::Depend => Some("fmri"),
+            ActionKind::License => Some("license"),
+            ActionKind::Legacy => Some("pkg"),
+            ActionKind::Group => Some("groupname"),
+            ActionKind::User => Some("username"),
+            ActionKind::Signature => Some("value"),
+            ActionKind::Other(_) => None,
+        }
+    }
+
+    pub fn is_filesystem(&self) -> bool {
+        matches!(
+            self,
+            ActionKind::File | ActionKind::Dir | ActionKind::Link | ActionKind::Hardlink
+        )
+    }
+}
+
+impl From<&str> for ActionKind {
+    fn from(kind: &str) -> Self {
+        match kind {
+            "set" => ActionKind::Set,
+            "file" => ActionKind::File,
+            "dir" => ActionKind::Dir,
+            "link" => ActionKind::Link,
+            "hardlink" => ActionKind::Hardlink,
+            "depend" => ActionKind::Depend,
+            "license" => ActionKind::License,
+            "legacy" => ActionKind::Legacy,
+            "group" => ActionKind::Group,
+            "user" => ActionKind::User,
+            "driver" => ActionKind::Driver,
+            "signature" => ActionKind::Signature,
+            other => ActionKind::Other(other.to_owned()),
+        }
+    }
+}
+
+impl Display for ActionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A single action line of an IPS manifest in the generic form
+/// `type [payload] key=value ...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Action {
+    pub kind: ActionKind,
+    pub payload: Option<String>,
+    pub attributes: BTreeMap<String, Vec<String>>,
+}
+
+impl Action {
+    pub fn new(kind: ActionKind) -> Self {
+        Self {
+            kind,
+            payload: None,
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    pub fn parse(line: &str) -> ActionResult<Self> {
+        let mut chars = line.trim().char_indices().peekable();
+        let line = line.trim();
+
+        let mut kind = String::new();
+        while let Some((_, c)) = chars.next_if(|(_, c)| !c.is_whitespace()) {
+            kind.push(c);
+        }
+
+        if kind.is_empty() {
+            return Err(ActionError::Empty);
+        }
+
+        let mut action = Action::new(ActionKind::from(kind.as_str()));
+
+        loop {
+            while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+
+            let Some(&(start, _)) = chars.peek() else {
+                break;
+            };
+
+            let mut key = String::new();
+            while let Some((_, c)) = chars.next_if(|(_, c)| !c.is_whitespace() && *c != '=') {
+                key.push(c);
+            }
+
+            if chars.next_if(|(_, c)| *c == '=').is_none() {
+                // Only the first bare word of an action can be its payload
+                // e.g. the hash of a file action.
+                if action.payload.is_none() && action.attributes.is_empty() {
+                    action.payload = Some(key);
+                    continue;
+                }
+                return Err(ActionError::MissingValue {
+                    attribute: key,
+                    line: line.to_owned(),
+                });
+            }
+
+            if key.is_empty() {
+                return Err(ActionError::UnexpectedText {
+                    text: line[start..].to_owned(),
+                    line: line.to_owned(),
+                });
+            }
+
+            let mut value = String::new();
+            if let Some((_, quote)) = chars.next_if(|(_, c)| *c == '"' || *c == '\'') {
+                let mut terminated = false;
+                while let Some((_, c)) = chars.next() {
+                    if c == '\\' && chars.peek().map(|(_, n)| *n == quote).unwrap_or(false) {
+                        value.push(quote);
+                        chars.next();
+                    } else if c == quote {
+                        terminated = true;
+                        break;
+                    } else {
+                        value.push(c);
+                    }
+                }
+                if !terminated {
+                    return Err(ActionError::UnterminatedQuote {
+                        attribute: key,
+                        line: line.to_owned(),
+                    });
+                }
+                if let Some(&(pos, c)) = chars.peek() {
+                    if !c.is_whitespace() {
+                        return Err(ActionError::UnexpectedText {
+                            text: line[pos..].to_owned(),
+                            line: line.to_owned(),
+                        });
+                    }
+                }
+            } else {
+                while let Some((_, c)) = chars.next_if(|(_, c)| !c.is_whitespace()) {
+                    value.push(c);
+                }
+            }
+
+            action.add_attr(&key, &value);
+        }
+
+        Ok(action)
+    }
+
+    /// Returns the value of the key attribute e.g. the path of a file.
+    pub fn key(&self) -> Option<&str> {
+        self.kind.key_attr().and_then(|attr| self.get(attr))
+    }
+
+    /// Returns the first value of an attribute.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .get(key)
+            .and_then(|values| values.first())
+            .map(|v| v.as_str())
+    }
+
+    pub fn values(&self, key: &str) -> &[String] {
+        self.attributes
+            .get(key)
+            .map(|values| values.as_slice())
+            .unwrap_or(&[])
+    }
+
+    pub fn has_attr(&self, key: &str) -> bool {
+        self.attributes.contains_key(key)
+    }
+
+    pub fn add_attr(&mut self, key: &str, value: &str) {
+        self.attributes
+            .entry(key.to_owned())
+            .or_default()
+            .push(value.to_owned());
+    }
+
+    pub fn set_attr(&mut self, key: &str, value: &str) {
+        self.attributes
+            .insert(key.to_owned(), vec![value.to_owned()]);
+    }
+
+    pub fn set_attr_values(&mut self, key: &str, values: Vec<String>) {
+        if values.is_empty() {
+            self.attributes.remove(key);
+        } else {
+            self.attributes.insert(key.to_owned(), values);
+        }
+    }
+
+    pub fn remove_attr(&mut self, key: &str) -> Option<Vec<String>> {
+        self.attributes.remove(key)
+    }
+}
+
+/// Quotes a value the same way pkg(5) does when printing an action.
+pub(crate) fn quote_value(value: &str) -> String {
+    if value.is_empty() || value.contains([' ', '\t', '\'', '"']) {
+        if !value.contains('"') {
+            format!("\"{}\"", value)
+        } else if !value.contains('\'') {
+            format!("'{}'", value)
+        } else {
+            format!("\"{}\"", value.replace('"', "\\\""))
+        }
+    } else {
+        value.to_owned()
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(payload) = &self.payload {
+            write!(f, " {}", payload)?;
+        }
+        for (key, values) in &self.attributes {
+            for value in values {
+                write!(f, " {}={}", key, quote_value(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_file_action_with_payload() {
+        let action = Action::parse(
+            "file 0acf5e2d path=usr/bin/ls owner=root group=bin mode=0555 facet.doc=all",
+        )
+        .unwrap();
+
+        assert_eq!(action.kind, ActionKind::File);
+        assert_eq!(action.key(), Some("usr/bin/ls"));
+        assert_eq!(action.payload.as_deref(), Some("0acf5e2d"));
+        assert_eq!(action.get("path"), Some("usr/bin/ls"));
+        assert_eq!(action.get("mode"), Some("0555"));
+    }
+
+    #[test]
+    fn parse_quoted_and_multi_valued_attributes() {
+        let action = Action::parse(
+            r#"set name=pkg.summary value="a \"quoted\" summary" value='second one'"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            action.values("value"),
+            &["a \"quoted\" summary".to_owned(), "second one".to_owned()]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_broken_actions() {
+        assert!(Action::parse("   ").is_err());
+        assert!(Action::parse("set name=\"pkg.fmri value=foo").is_err());
+        assert!(Action::parse("dir path=usr stray").is_err());
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        let line = r#"license license_file license="CDDL 1.0" path=a'b'"#;
+        let action = Action::parse(line).unwrap();
+        let printed = action.to_string();
+        assert_eq!(
+            printed,
+            r#"license license_file license="CDDL 1.0" path="a'b'""#
+        );
+        assert_eq!(Action::parse(&printed).unwrap(), action);
+    }
+}
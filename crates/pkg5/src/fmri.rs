@@ -0,0 +1,112 @@
+use std::{fmt::Display, str::FromStr};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+#[derive(Error, Debug, Diagnostic)]
+pub enum FmriError {
+    #[error("{0} is not a valid package FMRI")]
+    #[diagnostic(
+        code(pkg5::fmri::invalid),
+        help("FMRIs look like pkg://publisher/name@version or pkg:/name@version")
+    )]
+    Invalid(String),
+}
+
+/// A package FMRI like `pkg://userland/library/zlib@1.2.13,5.11-2023.0.0.1`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Fmri {
+    pub publisher: Option<String>,
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl Fmri {
+    pub fn new(name: &str, version: Option<&str>) -> Self {
+        Self {
+            publisher: None,
+            name: name.to_owned(),
+            version: version.map(|v| v.to_owned()),
+        }
+    }
+
+    /// The version without the timestamp added on publication.
+    pub fn version_without_timestamp(&self) -> Option<&str> {
+        self.version
+            .as_deref()
+            .map(|v| v.split_once(':').map(|(v, _)| v).unwrap_or(v))
+    }
+}
+
+impl FromStr for Fmri {
+    type Err = FmriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix("pkg:").unwrap_or(s);
+        let (publisher, rest) = if let Some(rest) = rest.strip_prefix("//") {
+            let (publisher, rest) = rest
+                .split_once('/')
+                .ok_or(FmriError::Invalid(s.to_owned()))?;
+            (Some(publisher.to_owned()), rest)
+        } else {
+            (None, rest.trim_start_matches('/'))
+        };
+
+        let (name, version) = match rest.split_once('@') {
+            Some((name, version)) if !version.is_empty() => (name, Some(version.to_owned())),
+            Some(_) => return Err(FmriError::Invalid(s.to_owned())),
+            None => (rest, None),
+        };
+
+        if name.is_empty() || publisher.as_deref() == Some("") {
+            return Err(FmriError::Invalid(s.to_owned()));
+        }
+
+        Ok(Self {
+            publisher,
+            name: name.to_owned(),
+            version,
+        })
+    }
+}
+
+impl Display for Fmri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.publisher {
+            Some(publisher) => write!(f, "pkg://{}/{}", publisher, self.name)?,
+            None => write!(f, "pkg:/{}", self.name)?,
+        }
+        if let Some(version) = &self.version {
+            write!(f, "@{}", version)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fmris() {
+        let fmri: Fmri = "pkg://userland/library/zlib@1.2.13,5.11-2023.0.0.1:20231010T101010Z"
+            .parse()
+            .unwrap();
+        assert_eq!(fmri.publisher.as_deref(), Some("userland"));
+        assert_eq!(fmri.name, "library/zlib");
+        assert_eq!(
+            fmri.version_without_timestamp(),
+            Some("1.2.13,5.11-2023.0.0.1")
+        );
+
+        let fmri: Fmri = "pkg:/library/zlib".parse().unwrap();
+        assert_eq!(fmri.to_string(), "pkg:/library/zlib");
+        assert_eq!(fmri.version, None);
+
+        let fmri: Fmri = "library/zlib@1.2".parse().unwrap();
+        assert_eq!(fmri.to_string(), "pkg:/library/zlib@1.2");
+
+        assert!("pkg:/".parse::<Fmri>().is_err());
+        assert!("pkg:/zlib@".parse::<Fmri>().is_err());
+    }
+}
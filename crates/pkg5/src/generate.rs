@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    fs::{read_dir, read_link, symlink_metadata},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    action::{Action, ActionKind},
+    manifest::Manifest,
+};
+
+#[derive(Error, Debug, Diagnostic)]
+pub enum GenerateError {
+    #[error(transparent)]
+    #[diagnostic(code(pkg5::io))]
+    IOError(#[from] std::io::Error),
+    #[error("{0} is not a directory")]
+    #[diagnostic(code(pkg5::generate::not_a_directory))]
+    NotADirectory(String),
+    #[error("{0} is neither a file, directory or symlink and cannot be packaged")]
+    #[diagnostic(code(pkg5::generate::unsupported_file_type))]
+    UnsupportedFileType(String),
+}
+
+type GenerateResult<T> = std::result::Result<T, GenerateError>;
+
+const DEFAULT_OWNER: &str = "root";
+const DEFAULT_GROUP: &str = "bin";
+
+/// Generates the actions for all files below `proto_dir` like
+/// `pkgsend generate` does. Files are owned by root:bin with the mode they
+/// have in the prototype directory. Files sharing an inode become
+/// hardlinks to the first of them in path order.
+pub fn generate_manifest<P: AsRef<Path>>(proto_dir: P) -> GenerateResult<Manifest> {
+    let proto_dir = proto_dir.as_ref();
+    if !proto_dir.is_dir() {
+        return Err(GenerateError::NotADirectory(
+            proto_dir.display().to_string(),
+        ));
+    }
+
+    let mut manifest = Manifest::new();
+    let mut inodes: HashMap<(u64, u64), String> = HashMap::new();
+    walk(proto_dir, Path::new(""), &mut manifest, &mut inodes)?;
+    Ok(manifest)
+}
+
+fn walk(
+    proto_dir: &Path,
+    relative: &Path,
+    manifest: &mut Manifest,
+    inodes: &mut HashMap<(u64, u64), String>,
+) -> GenerateResult<()> {
+    let mut entries = read_dir(proto_dir.join(relative))?
+        .map(|entry| entry.map(|e| e.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for name in entries {
+        let rel_path = relative.join(&name);
+        let full_path = proto_dir.join(&rel_path);
+        let path = rel_path.to_string_lossy().to_string();
+        let meta = symlink_metadata(&full_path)?;
+        let file_type = meta.file_type();
+        let mode = format!("{:04o}", meta.permissions().mode() & 0o7777);
+
+        if file_type.is_symlink() {
+            let mut action = Action::new(ActionKind::Link);
+            action.set_attr("path", &path);
+            action.set_attr("target", &read_link(&full_path)?.to_string_lossy());
+            manifest.push(action);
+        } else if file_type.is_dir() {
+            let mut action = Action::new(ActionKind::Dir);
+            action.set_attr("path", &path);
+            action.set_attr("owner", DEFAULT_OWNER);
+            action.set_attr("group", DEFAULT_GROUP);
+            action.set_attr("mode", &mode);
+            manifest.push(action);
+            walk(proto_dir, &rel_path, manifest, inodes)?;
+        } else if file_type.is_file() {
+            let inode = (meta.dev(), meta.ino());
+            if let Some(target) = inodes.get(&inode).filter(|_| meta.nlink() > 1) {
+                let mut action = Action::new(ActionKind::Hardlink);
+                action.set_attr("path", &path);
+                action.set_attr("target", &relative_target(&rel_path, Path::new(target)));
+                manifest.push(action);
+                continue;
+            }
+            if meta.nlink() > 1 {
+                inodes.insert(inode, path.clone());
+            }
+            let mut action = Action::new(ActionKind::File);
+            action.payload = Some(path.clone());
+            action.set_attr("path", &path);
+            action.set_attr("owner", DEFAULT_OWNER);
+            action.set_attr("group", DEFAULT_GROUP);
+            action.set_attr("mode", &mode);
+            manifest.push(action);
+        } else {
+            return Err(GenerateError::UnsupportedFileType(
+                full_path.display().to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Hardlink targets are relative to the directory of the link.
+fn relative_target(link: &Path, target: &Path) -> String {
+    let link_dir = link.parent().unwrap_or(Path::new(""));
+    let link_components = link_dir.components().collect::<Vec<Component>>();
+    let target_components = target.components().collect::<Vec<Component>>();
+    let common = link_components
+        .iter()
+        .zip(target_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..link_components.len() {
+        relative.push("..");
+    }
+    for component in &target_components[common..] {
+        relative.push(component);
+    }
+    relative.to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::{create_dir_all, hard_link, set_permissions, write, Permissions},
+        os::unix::fs::symlink,
+    };
+
+    #[test]
+    fn generate_from_prototype() {
+        let proto = tempfile::tempdir().unwrap();
+        let root = proto.path();
+        create_dir_all(root.join("usr/bin")).unwrap();
+        create_dir_all(root.join("usr/lib")).unwrap();
+        set_permissions(root.join("usr"), Permissions::from_mode(0o755)).unwrap();
+        set_permissions(root.join("usr/bin"), Permissions::from_mode(0o755)).unwrap();
+        set_permissions(root.join("usr/lib"), Permissions::from_mode(0o755)).unwrap();
+        write(root.join("usr/bin/foo"), "foo").unwrap();
+        set_permissions(root.join("usr/bin/foo"), Permissions::from_mode(0o555)).unwrap();
+        hard_link(root.join("usr/bin/foo"), root.join("usr/lib/foo-helper")).unwrap();
+        write(root.join("usr/lib/libfoo.so.1"), "lib").unwrap();
+        set_permissions(
+            root.join("usr/lib/libfoo.so.1"),
+            Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        symlink("libfoo.so.1", root.join("usr/lib/libfoo.so")).unwrap();
+
+        let manifest = generate_manifest(root).unwrap();
+
+        assert_eq!(
+            manifest.to_pkgfmt(),
+            r#"dir  path=usr owner=root group=bin mode=0755
+dir  path=usr/bin owner=root group=bin mode=0755
+file path=usr/bin/foo owner=root group=bin mode=0555
+dir  path=usr/lib owner=root group=bin mode=0755
+hardlink path=usr/lib/foo-helper target=../bin/foo
+link path=usr/lib/libfoo.so target=libfoo.so.1
+file path=usr/lib/libfoo.so.1 owner=root group=bin mode=0644
+"#
+        );
+        let file = manifest.actions_of(ActionKind::File).next().unwrap();
+        assert_eq!(file.payload.as_deref(), Some("usr/bin/foo"));
+    }
+
+    #[test]
+    fn relative_hardlink_targets() {
+        assert_eq!(
+            relative_target(Path::new("usr/bin/a"), Path::new("usr/bin/b")),
+            "b"
+        );
+        assert_eq!(
+            relative_target(Path::new("a"), Path::new("usr/lib/b")),
+            "usr/lib/b"
+        );
+        assert_eq!(
+            relative_target(Path::new("usr/sbin/a"), Path::new("usr/lib/b")),
+            "../lib/b"
+        );
+    }
+
+    #[test]
+    fn generate_requires_a_directory() {
+        assert!(matches!(
+            generate_manifest("/does/not/exist"),
+            Err(GenerateError::NotADirectory(_))
+        ));
+    }
+}
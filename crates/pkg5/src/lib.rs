@@ -0,0 +1,10 @@
+mod action;
+pub mod fmri;
+pub mod generate;
+pub mod manifest;
+pub mod mogrify;
+pub mod repository;
+
+pub use action::{Action, ActionError, ActionKind};
+pub use fmri::Fmri;
+pub use manifest::{Manifest, ManifestLine};
@@ -0,0 +1,400 @@
+use std::{cmp::Ordering, fmt::Display, fs::read_to_string, path::Path, str::FromStr};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::action::{quote_value, Action, ActionError, ActionKind};
+
+#[derive(Error, Debug, Diagnostic)]
+pub enum ManifestError {
+    #[error(transparent)]
+    #[diagnostic(code(pkg5::io))]
+    IOError(#[from] std::io::Error),
+    #[error("line {line}: {error}")]
+    #[diagnostic(code(pkg5::manifest::invalid_action))]
+    InvalidAction { line: usize, error: ActionError },
+}
+
+type ManifestResult<T> = std::result::Result<T, ManifestError>;
+
+/// Lines longer than this are wrapped by pkgfmt.
+const MAX_LINE_LEN: usize = 80;
+const CONTINUATION_INDENT: &str = "    ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestLine {
+    Action(Action),
+    /// Comments, blank lines and transform directives are kept verbatim.
+    Text(String),
+}
+
+/// An IPS manifest (p5m) as a list of actions and the comments between them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub lines: Vec<ManifestLine>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse_file<P: AsRef<Path>>(path: P) -> ManifestResult<Self> {
+        let contents = read_to_string(path)?;
+        contents.parse()
+    }
+
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.lines.iter().filter_map(|line| match line {
+            ManifestLine::Action(action) => Some(action),
+            ManifestLine::Text(_) => None,
+        })
+    }
+
+    pub fn actions_mut(&mut self) -> impl Iterator<Item = &mut Action> {
+        self.lines.iter_mut().filter_map(|line| match line {
+            ManifestLine::Action(action) => Some(action),
+            ManifestLine::Text(_) => None,
+        })
+    }
+
+    pub fn actions_of(&self, kind: ActionKind) -> impl Iterator<Item = &Action> {
+        self.actions().filter(move |action| action.kind == kind)
+    }
+
+    pub fn push(&mut self, action: Action) {
+        self.lines.push(ManifestLine::Action(action));
+    }
+
+    /// Keeps only the actions for which `f` returns true. Text lines are kept.
+    pub fn retain_actions<F: FnMut(&Action) -> bool>(&mut self, mut f: F) {
+        self.lines.retain(|line| match line {
+            ManifestLine::Action(action) => f(action),
+            ManifestLine::Text(_) => true,
+        })
+    }
+
+    /// Returns the value of a `set` action with the given name.
+    pub fn get_attr(&self, name: &str) -> Option<&str> {
+        self.actions_of(ActionKind::Set)
+            .find(|action| action.get("name") == Some(name))
+            .and_then(|action| action.get("value"))
+    }
+
+    pub fn fmri(&self) -> Option<&str> {
+        self.get_attr("pkg.fmri")
+    }
+
+    /// Writes the manifest in the canonical format of pkgfmt(1). Comments
+    /// before the first action stay at the top, all other comments move with
+    /// the action following them.
+    pub fn to_pkgfmt(&self) -> String {
+        let mut header = vec![];
+        let mut blocks: Vec<(Vec<&str>, &Action)> = vec![];
+        let mut pending = vec![];
+
+        for line in &self.lines {
+            match line {
+                ManifestLine::Text(text) => pending.push(text.as_str()),
+                ManifestLine::Action(action) => {
+                    if blocks.is_empty() && header.is_empty() {
+                        header = std::mem::take(&mut pending);
+                    }
+                    blocks.push((std::mem::take(&mut pending), action));
+                }
+            }
+        }
+
+        blocks.sort_by(|(_, a), (_, b)| compare_actions(a, b));
+
+        let mut out = String::new();
+        for line in header {
+            out.push_str(line);
+            out.push('\n');
+        }
+        for (comments, action) in blocks {
+            for line in comments {
+                out.push_str(line);
+                out.push('\n');
+            }
+            out.push_str(&format_action(action));
+            out.push('\n');
+        }
+        for line in pending {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = ManifestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut manifest = Manifest::new();
+        let mut pending = String::new();
+        let mut start_line = 0;
+
+        for (idx, raw_line) in s.lines().enumerate() {
+            let trimmed = raw_line.trim();
+            if pending.is_empty() {
+                start_line = idx + 1;
+                if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with('<') {
+                    manifest.lines.push(ManifestLine::Text(raw_line.to_owned()));
+                    continue;
+                }
+            }
+            if let Some(continued) = trimmed.strip_suffix('\\') {
+                pending.push_str(continued);
+                pending.push(' ');
+                continue;
+            }
+            pending.push_str(trimmed);
+            let action = Action::parse(&pending).map_err(|error| ManifestError::InvalidAction {
+                line: start_line,
+                error,
+            })?;
+            manifest.push(action);
+            pending.clear();
+        }
+
+        if !pending.is_empty() {
+            let action = Action::parse(&pending).map_err(|error| ManifestError::InvalidAction {
+                line: start_line,
+                error,
+            })?;
+            manifest.push(action);
+        }
+
+        Ok(manifest)
+    }
+}
+
+impl Display for Manifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                ManifestLine::Action(action) => writeln!(f, "{}", action)?,
+                ManifestLine::Text(text) => writeln!(f, "{}", text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Groups actions like pkgfmt: set actions first, then filesystem actions,
+/// then users and groups, then legacy and license and depend last.
+fn type_order(kind: &ActionKind) -> u8 {
+    match kind {
+        ActionKind::Set => 1,
+        ActionKind::Driver | ActionKind::Group | ActionKind::User => 3,
+        ActionKind::Legacy | ActionKind::License => 4,
+        ActionKind::Depend => 5,
+        _ => 2,
+    }
+}
+
+fn set_order(action: &Action) -> u8 {
+    match action.get("name") {
+        Some("pkg.fmri") => 0,
+        Some(name) if name.starts_with("pkg.") => 1,
+        _ => 2,
+    }
+}
+
+fn compare_actions(a: &Action, b: &Action) -> Ordering {
+    type_order(&a.kind)
+        .cmp(&type_order(&b.kind))
+        .then_with(|| {
+            if a.kind == ActionKind::Set && b.kind == ActionKind::Set {
+                set_order(a).cmp(&set_order(b))
+            } else {
+                Ordering::Equal
+            }
+        })
+        .then_with(|| {
+            // Filesystem actions are sorted by path regardless of their type.
+            if a.kind.is_filesystem() && b.kind.is_filesystem() {
+                Ordering::Equal
+            } else {
+                a.kind.as_str().cmp(b.kind.as_str())
+            }
+        })
+        .then_with(|| a.key().cmp(&b.key()))
+        .then_with(|| a.get("type").cmp(&b.get("type")))
+        .then_with(|| variants(a).cmp(&variants(b)))
+}
+
+fn variants(action: &Action) -> Vec<(&String, &Vec<String>)> {
+    action
+        .attributes
+        .iter()
+        .filter(|(name, _)| name.starts_with("variant."))
+        .collect()
+}
+
+/// Order of the attributes within an action. The key attribute comes first,
+/// facets and variants last.
+fn attr_order(action: &Action, name: &str, values: &[String]) -> u8 {
+    if Some(name) == action.kind.key_attr() {
+        return 0;
+    }
+    if name.starts_with("variant.") {
+        return 7;
+    }
+    if name.starts_with("facet.") {
+        return 6;
+    }
+    if values.len() > 1 {
+        return 5;
+    }
+    match (&action.kind, name) {
+        (ActionKind::Depend, "type") => 1,
+        (ActionKind::Driver, "perms") => 1,
+        (ActionKind::Driver, "clone_perms") => 2,
+        (ActionKind::Driver, "privs") => 3,
+        (ActionKind::Driver, "policy") => 4,
+        (ActionKind::Driver, "devlink") => 5,
+        (ActionKind::Driver, "alias") => 6,
+        (ActionKind::Driver, _) | (ActionKind::User, _) => 4,
+        (_, "target") => 1,
+        (_, "owner") => 2,
+        (_, "group") => 3,
+        (_, "mode") => 3,
+        _ => 4,
+    }
+}
+
+fn format_action(action: &Action) -> String {
+    let mut parts = vec![];
+
+    // The payload of a file is its path unless it was given explicitly.
+    if let Some(payload) = &action.payload {
+        if Some(payload.as_str()) != action.get("path") {
+            parts.push(payload.clone());
+        }
+    }
+
+    let mut attributes = action.attributes.iter().collect::<Vec<_>>();
+    attributes.sort_by(|(a_name, a_values), (b_name, b_values)| {
+        attr_order(action, a_name, a_values)
+            .cmp(&attr_order(action, b_name, b_values))
+            .then_with(|| a_name.cmp(b_name))
+    });
+    let first_attr = parts.len();
+    for (name, values) in attributes {
+        for value in values {
+            parts.push(format!("{}={}", name, quote_value(value)));
+        }
+    }
+
+    // Align paths of dir actions with those of file and link.
+    let mut out = if action.kind == ActionKind::Dir {
+        String::from("dir ")
+    } else {
+        action.kind.to_string()
+    };
+    let mut line_len = out.len();
+    for (idx, part) in parts.iter().enumerate() {
+        // The payload and the key attribute always stay on the first line.
+        let keep = idx <= first_attr;
+        if !keep && line_len + 1 + part.len() > MAX_LINE_LEN - 2 {
+            out.push_str(" \\\n");
+            out.push_str(CONTINUATION_INDENT);
+            out.push_str(part);
+            line_len = CONTINUATION_INDENT.len() + part.len();
+        } else {
+            out.push(' ');
+            out.push_str(part);
+            line_len += 1 + part.len();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keeps_comments_and_continuations() {
+        let manifest: Manifest = r#"#
+# header
+#
+set name=pkg.fmri value=pkg:/library/foo@1.0,5.11-2023.0.0.1
+<transform file path=usr/bin/.* -> default mode 0555>
+file path=usr/bin/foo \
+    owner=root group=bin
+"#
+        .parse()
+        .unwrap();
+
+        assert_eq!(manifest.lines.len(), 6);
+        assert_eq!(
+            manifest.fmri(),
+            Some("pkg:/library/foo@1.0,5.11-2023.0.0.1")
+        );
+        let file = manifest.actions_of(ActionKind::File).next().unwrap();
+        assert_eq!(file.get("group"), Some("bin"));
+    }
+
+    #[test]
+    fn parse_reports_line_of_invalid_action() {
+        let err = "set name=a value=b\n\nfile path=\"usr\n"
+            .parse::<Manifest>()
+            .unwrap_err();
+        assert!(matches!(err, ManifestError::InvalidAction { line: 3, .. }));
+    }
+
+    #[test]
+    fn pkgfmt_sorts_and_wraps() {
+        let manifest: Manifest = r#"# header
+
+depend fmri=pkg:/library/zlib type=require
+license COPYING license=MIT
+file usr/bin/foo path=usr/bin/foo mode=0555 group=bin owner=root
+set name=info.classification value="org.opensolaris.category.2008:System/Libraries"
+dir path=usr/bin owner=root group=bin mode=0755
+# the library
+link target=libfoo.so.1 path=usr/lib/libfoo.so
+set name=pkg.summary value="foo library"
+set name=pkg.fmri value=pkg:/library/foo@1.0,5.11-2023.0.0.1
+file path=usr/share/man/man1/foo.1 facet.doc.man=all mangler.man.stability=uncommitted variant.arch=i386
+"#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            manifest.to_pkgfmt(),
+            r#"# header
+
+set name=pkg.fmri value=pkg:/library/foo@1.0,5.11-2023.0.0.1
+set name=pkg.summary value="foo library"
+set name=info.classification \
+    value=org.opensolaris.category.2008:System/Libraries
+dir  path=usr/bin owner=root group=bin mode=0755
+file path=usr/bin/foo owner=root group=bin mode=0555
+# the library
+link path=usr/lib/libfoo.so target=libfoo.so.1
+file path=usr/share/man/man1/foo.1 mangler.man.stability=uncommitted \
+    facet.doc.man=all variant.arch=i386
+license COPYING license=MIT
+depend fmri=pkg:/library/zlib type=require
+"#
+        );
+    }
+
+    #[test]
+    fn pkgfmt_output_parses_back() {
+        let input = "set name=pkg.fmri value=pkg:/a@1\nfile path=a/very/long/path/that/goes/on/and/on/for/a/while/until/it/wraps owner=root group=bin mode=0444\n";
+        let manifest: Manifest = input.parse().unwrap();
+        let formatted = manifest.to_pkgfmt();
+        assert!(formatted.lines().all(|l| l.len() <= MAX_LINE_LEN));
+        let reparsed: Manifest = formatted.parse().unwrap();
+        assert_eq!(
+            reparsed.actions().collect::<Vec<_>>(),
+            manifest.actions().collect::<Vec<_>>()
+        );
+    }
+}
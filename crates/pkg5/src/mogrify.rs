@@ -0,0 +1,1094 @@
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use fancy_regex::{Captures, Expander, Regex};
+use miette::Diagnostic;
+use thiserror::Error;
+
+use crate::{
+    action::{Action, ActionError, ActionKind},
+    manifest::{Manifest, ManifestLine},
+};
+
+#[derive(Error, Debug, Diagnostic)]
+pub enum MogrifyError {
+    #[error(transparent)]
+    #[diagnostic(code(pkg5::io))]
+    IOError(#[from] std::io::Error),
+    #[error("{source_location}: {message}")]
+    #[diagnostic(code(pkg5::mogrify::invalid_transform))]
+    InvalidTransform {
+        source_location: String,
+        message: String,
+    },
+    #[error("{source_location}: cannot find include file {include}")]
+    #[diagnostic(
+        code(pkg5::mogrify::include_not_found),
+        help("add the directory containing the file to the include path")
+    )]
+    IncludeNotFound {
+        source_location: String,
+        include: String,
+    },
+    #[error("{source_location}: {error}")]
+    #[diagnostic(code(pkg5::mogrify::invalid_action))]
+    InvalidAction {
+        source_location: String,
+        error: ActionError,
+    },
+    #[error("{source_location}: {message}")]
+    #[diagnostic(code(pkg5::mogrify::transform_failed))]
+    TransformFailed {
+        source_location: String,
+        message: String,
+    },
+    #[error("transform at {source_location} exited with code {code}: {message}")]
+    #[diagnostic(code(pkg5::mogrify::exit))]
+    Exit {
+        source_location: String,
+        code: i32,
+        message: String,
+    },
+}
+
+type MogrifyResult<T> = std::result::Result<T, MogrifyError>;
+
+/// Emitted actions are run through the transforms again. Stop when a
+/// transform keeps emitting actions that trigger itself.
+const MAX_EMIT_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+struct SourceLine {
+    text: String,
+    location: String,
+}
+
+#[derive(Debug, Clone)]
+enum Operation {
+    Drop,
+    Abort,
+    Add(String, String),
+    Default(String, String),
+    Set(String, String),
+    Delete(String, Regex),
+    Edit(String, Regex, String),
+    Emit(String),
+    Print(String),
+    Exit(i32, String),
+}
+
+#[derive(Debug, Clone)]
+struct Transform {
+    types: Vec<String>,
+    matchers: Vec<(String, Regex)>,
+    operation: Operation,
+    location: String,
+}
+
+/// The result of running all transforms over the input manifests.
+#[derive(Debug, Default, Clone)]
+pub struct MogrifyOutput {
+    /// The transformed manifest including comments in input order.
+    pub manifest: Manifest,
+    /// Messages produced by `print` operations.
+    pub printed: Vec<String>,
+    /// Set when a transform aborted processing. No manifest lines are
+    /// produced in that case, just like pkgmogrify.
+    pub aborted: bool,
+}
+
+enum Flow {
+    Continue,
+    Abort,
+}
+
+/// Applies pkgmogrify(1) style `<transform>` directives to manifests.
+#[derive(Debug, Default, Clone)]
+pub struct Mogrifier {
+    include_dirs: Vec<PathBuf>,
+    macros: HashMap<String, String>,
+    transforms: Vec<Transform>,
+    lines: Vec<SourceLine>,
+}
+
+impl Mogrifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Directories searched for files referenced by `<include>`, the same as `-I` of pkgmogrify.
+    pub fn add_include_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.include_dirs.push(dir.as_ref().to_path_buf());
+    }
+
+    /// Defines a macro used to replace `$(name)` in the input, the same as `-D` of pkgmogrify.
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.macros.insert(name.to_owned(), value.to_owned());
+    }
+
+    pub fn read_file<P: AsRef<Path>>(&mut self, path: P) -> MogrifyResult<()> {
+        let contents = read_to_string(path.as_ref())?;
+        self.read_str(&path.as_ref().display().to_string(), &contents)
+    }
+
+    /// Reads manifest lines and transform directives. `name` is only used in error messages.
+    pub fn read_str(&mut self, name: &str, contents: &str) -> MogrifyResult<()> {
+        let mut pending = String::new();
+        let mut start_line = 0;
+
+        for (idx, raw_line) in contents.lines().enumerate() {
+            if pending.is_empty() {
+                start_line = idx + 1;
+            }
+            let raw_line = raw_line.trim();
+            if let Some(continued) = raw_line.strip_suffix('\\') {
+                pending.push_str(continued);
+                continue;
+            }
+            pending.push_str(raw_line);
+            let line = self.expand_macros(&std::mem::take(&mut pending));
+            self.process_line(line, format!("{}:{}", name, start_line))?;
+        }
+
+        if !pending.is_empty() {
+            let line = self.expand_macros(&pending);
+            self.process_line(line, format!("{}:{}", name, start_line))?;
+        }
+
+        Ok(())
+    }
+
+    fn expand_macros(&self, line: &str) -> String {
+        let mut out = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(start) = rest.find("$(") {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            match after.find(')') {
+                Some(end) if self.macros.contains_key(&after[..end]) => {
+                    out.push_str(&self.macros[&after[..end]]);
+                    rest = &after[end + 1..];
+                }
+                // Undefined macros are left alone like pkgmogrify does.
+                _ => {
+                    out.push_str("$(");
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn process_line(&mut self, line: String, location: String) -> MogrifyResult<()> {
+        if !is_directive(line.trim_start()) {
+            self.lines.push(SourceLine {
+                text: line,
+                location,
+            });
+            return Ok(());
+        }
+
+        let mut rest = line.trim();
+        while !rest.is_empty() {
+            if !is_directive(rest) {
+                return Err(MogrifyError::InvalidTransform {
+                    source_location: location,
+                    message: format!("unexpected text after directive: {}", rest),
+                });
+            }
+            let end = directive_end(rest).ok_or(MogrifyError::InvalidTransform {
+                source_location: location.clone(),
+                message: format!("unterminated directive: {}", rest),
+            })?;
+            let directive = &rest[1..end];
+            if let Some(include) = directive.strip_prefix("include") {
+                self.include(include.trim(), &location)?;
+            } else {
+                let body = directive.strip_prefix("transform").unwrap_or(directive);
+                let transform = parse_transform(body, &location)?;
+                self.transforms.push(transform);
+            }
+            rest = rest[end + 1..].trim_start();
+        }
+
+        Ok(())
+    }
+
+    fn include(&mut self, file: &str, location: &str) -> MogrifyResult<()> {
+        let file = strip_quotes(file);
+        let candidates = if Path::new(file).is_absolute() {
+            vec![PathBuf::from(file)]
+        } else {
+            self.include_dirs
+                .iter()
+                .map(|dir| dir.join(file))
+                .chain(std::iter::once(PathBuf::from(file)))
+                .collect()
+        };
+
+        let path =
+            candidates
+                .into_iter()
+                .find(|p| p.is_file())
+                .ok_or(MogrifyError::IncludeNotFound {
+                    source_location: location.to_owned(),
+                    include: file.to_owned(),
+                })?;
+
+        self.read_file(path)
+    }
+
+    /// Add a single `<transform ...>` or `<include ...>` directive.
+    pub fn add_directive(&mut self, directive: &str) -> MogrifyResult<()> {
+        self.read_str("<directive>", directive)
+    }
+
+    pub fn run(&self) -> MogrifyResult<MogrifyOutput> {
+        let mut output = MogrifyOutput::default();
+        let mut pkg_attrs: HashMap<String, Vec<String>> = HashMap::new();
+        let mut actions = vec![];
+
+        // The first pass collects the package attributes so %{attr} can be
+        // used regardless of where the set action is in the input.
+        for line in &self.lines {
+            let trimmed = line.text.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                actions.push(None);
+                continue;
+            }
+            let action = Action::parse(trimmed).map_err(|error| MogrifyError::InvalidAction {
+                source_location: line.location.clone(),
+                error,
+            })?;
+            if action.kind == ActionKind::Set {
+                if let Some(name) = action.get("name") {
+                    pkg_attrs
+                        .entry(name.to_owned())
+                        .or_default()
+                        .extend(action.values("value").iter().cloned());
+                }
+            }
+            actions.push(Some(action));
+        }
+
+        for (line, action) in self.lines.iter().zip(actions) {
+            let Some(action) = action else {
+                output
+                    .manifest
+                    .lines
+                    .push(ManifestLine::Text(line.text.clone()));
+                continue;
+            };
+            let mut results = vec![];
+            let flow = self.apply(
+                action,
+                &pkg_attrs,
+                &line.location,
+                0,
+                &mut results,
+                &mut output.printed,
+            )?;
+            if let Flow::Abort = flow {
+                output.manifest = Manifest::new();
+                output.aborted = true;
+                return Ok(output);
+            }
+            for result in results {
+                output.manifest.push(result);
+            }
+        }
+
+        // pkg transforms operate on a synthetic action carrying the package
+        // attributes. The action itself never ends up in the manifest.
+        let mut pkg_action = Action::new(ActionKind::Other(String::from("pkg")));
+        for (name, values) in &pkg_attrs {
+            pkg_action.set_attr_values(name, values.clone());
+        }
+        let mut emitted = vec![];
+        let flow = self.apply_transforms(
+            pkg_action,
+            &pkg_attrs,
+            "<pkg>",
+            0,
+            &mut emitted,
+            &mut output.printed,
+        )?;
+        if let Flow::Abort = flow {
+            output.manifest = Manifest::new();
+            output.aborted = true;
+            return Ok(output);
+        }
+        for action in emitted {
+            output.manifest.push(action);
+        }
+
+        Ok(output)
+    }
+
+    /// Runs `action` through all transforms and pushes the resulting action
+    /// and everything it emitted to `results`.
+    fn apply(
+        &self,
+        action: Action,
+        pkg_attrs: &HashMap<String, Vec<String>>,
+        location: &str,
+        depth: usize,
+        results: &mut Vec<Action>,
+        printed: &mut Vec<String>,
+    ) -> MogrifyResult<Flow> {
+        let mut emitted = vec![];
+        let mut action = Some(action);
+        let flow = self.apply_transforms_to(
+            &mut action,
+            pkg_attrs,
+            location,
+            depth,
+            &mut emitted,
+            printed,
+        )?;
+        if let Some(action) = action {
+            results.push(action);
+        }
+        results.extend(emitted);
+        Ok(flow)
+    }
+
+    fn apply_transforms(
+        &self,
+        action: Action,
+        pkg_attrs: &HashMap<String, Vec<String>>,
+        location: &str,
+        depth: usize,
+        emitted: &mut Vec<Action>,
+        printed: &mut Vec<String>,
+    ) -> MogrifyResult<Flow> {
+        let mut action = Some(action);
+        self.apply_transforms_to(&mut action, pkg_attrs, location, depth, emitted, printed)
+    }
+
+    fn apply_transforms_to(
+        &self,
+        action_slot: &mut Option<Action>,
+        pkg_attrs: &HashMap<String, Vec<String>>,
+        location: &str,
+        depth: usize,
+        emitted: &mut Vec<Action>,
+        printed: &mut Vec<String>,
+    ) -> MogrifyResult<Flow> {
+        if depth > MAX_EMIT_DEPTH {
+            return Err(MogrifyError::TransformFailed {
+                source_location: location.to_owned(),
+                message: String::from("emitted actions are nested too deep"),
+            });
+        }
+
+        for transform in &self.transforms {
+            let Some(action) = action_slot.as_mut() else {
+                break;
+            };
+            let Some(groups) = transform.matches(action) else {
+                continue;
+            };
+
+            let failed = |message: String| MogrifyError::TransformFailed {
+                source_location: format!("{} (transform at {})", location, transform.location),
+                message,
+            };
+            let subst = |s: &str, action: &Action| {
+                substitute(s, action, &groups, pkg_attrs).map_err(failed)
+            };
+
+            match &transform.operation {
+                Operation::Drop => {
+                    *action_slot = None;
+                }
+                Operation::Abort => return Ok(Flow::Abort),
+                Operation::Exit(code, message) => {
+                    let message = subst(message, action)?;
+                    if *code == 0 {
+                        return Ok(Flow::Abort);
+                    }
+                    return Err(MogrifyError::Exit {
+                        source_location: transform.location.clone(),
+                        code: *code,
+                        message,
+                    });
+                }
+                Operation::Add(attr, value) => {
+                    let attr = subst(attr, action)?;
+                    let value = subst(value, action)?;
+                    if attr == "action.hash" {
+                        action.payload = Some(value);
+                    } else {
+                        action.add_attr(&attr, &value);
+                    }
+                }
+                Operation::Default(attr, value) => {
+                    let attr = subst(attr, action)?;
+                    if attr == "action.hash" {
+                        if action.payload.is_none() {
+                            action.payload = Some(subst(value, action)?);
+                        }
+                    } else if !action.has_attr(&attr) {
+                        let value = subst(value, action)?;
+                        action.set_attr(&attr, &value);
+                    }
+                }
+                Operation::Set(attr, value) => {
+                    let attr = subst(attr, action)?;
+                    let value = subst(value, action)?;
+                    if attr == "action.hash" {
+                        action.payload = Some(value);
+                    } else {
+                        action.set_attr(&attr, &value);
+                    }
+                }
+                Operation::Delete(attr, regex) => {
+                    let attr = subst(attr, action)?;
+                    if attr == "action.hash" {
+                        if let Some(payload) = &action.payload {
+                            if is_match(regex, payload).map_err(failed)? {
+                                action.payload = None;
+                            }
+                        }
+                    } else {
+                        let mut kept = vec![];
+                        for value in action.values(&attr) {
+                            if !is_match(regex, value).map_err(failed)? {
+                                kept.push(value.clone());
+                            }
+                        }
+                        action.set_attr_values(&attr, kept);
+                    }
+                }
+                Operation::Edit(attr, regex, replacement) => {
+                    let attr = subst(attr, action)?;
+                    let replacement = subst(replacement, action)?;
+                    let expander = Expander::python();
+                    let edit = |value: &str| {
+                        regex
+                            .replace_all(value, |caps: &Captures| {
+                                expander.expansion(&replacement, caps)
+                            })
+                            .to_string()
+                    };
+                    if attr == "action.hash" {
+                        action.payload = action.payload.as_deref().map(edit);
+                    } else if action.has_attr(&attr) {
+                        let values = action.values(&attr).iter().map(|v| edit(v)).collect();
+                        action.set_attr_values(&attr, values);
+                    }
+                }
+                Operation::Print(message) => {
+                    printed.push(subst(message, action)?);
+                }
+                Operation::Emit(line) => {
+                    let line = subst(line, action)?;
+                    let trimmed = line.trim();
+                    if trimmed.is_empty() || trimmed.starts_with('#') {
+                        continue;
+                    }
+                    let new_action =
+                        Action::parse(trimmed).map_err(|error| MogrifyError::InvalidAction {
+                            source_location: transform.location.clone(),
+                            error,
+                        })?;
+                    let flow =
+                        self.apply(new_action, pkg_attrs, location, depth + 1, emitted, printed)?;
+                    if let Flow::Abort = flow {
+                        return Ok(Flow::Abort);
+                    }
+                }
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+impl Transform {
+    /// Returns the match groups of all attribute matchers if the transform
+    /// applies to the action.
+    fn matches(&self, action: &Action) -> Option<Vec<Option<String>>> {
+        if !self.types.is_empty() && !self.types.iter().any(|t| t == action.kind.as_str()) {
+            return None;
+        }
+        // Transforms only apply to the synthetic pkg action if they name it.
+        if self.types.is_empty() && action.kind.as_str() == "pkg" {
+            return None;
+        }
+
+        let mut groups = vec![];
+        for (attr, regex) in &self.matchers {
+            let values = match attr.as_str() {
+                "action.hash" => action.payload.iter().cloned().collect::<Vec<_>>(),
+                "action.name" => vec![action.kind.to_string()],
+                _ => action.values(attr).to_vec(),
+            };
+            let captures = values
+                .iter()
+                .find_map(|value| regex.captures(value).ok().flatten())?;
+            groups.extend(
+                captures
+                    .iter()
+                    .skip(1)
+                    .map(|m| m.map(|m| m.as_str().to_owned())),
+            );
+        }
+        Some(groups)
+    }
+}
+
+fn is_directive(line: &str) -> bool {
+    ["<transform", "<include"].iter().any(|prefix| {
+        line.strip_prefix(prefix)
+            .map(|rest| rest.starts_with(|c: char| c.is_whitespace() || c == '>'))
+            .unwrap_or(false)
+    })
+}
+
+/// Finds the `>` closing the directive at the start of `line`. Arrows,
+/// quoted strings and substitutions like `%<1>` are skipped.
+fn directive_end(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut closer: Option<u8> = None;
+    let mut i = 1;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if let Some(q) = quote {
+            if c == b'\\' {
+                i += 1;
+            } else if c == q {
+                quote = None;
+            }
+        } else if c == b'"' || c == b'\'' {
+            quote = Some(c);
+        } else if let Some(close) = closer {
+            if c == close {
+                closer = None;
+            }
+        } else if c == b'%' && i + 1 < bytes.len() {
+            closer = match bytes[i + 1] {
+                b'<' => Some(b'>'),
+                b'(' => Some(b')'),
+                b'{' => Some(b'}'),
+                _ => None,
+            };
+            if closer.is_some() {
+                i += 1;
+            }
+        } else if c == b'-' && bytes.get(i + 1) == Some(&b'>') {
+            i += 1;
+        } else if c == b'>' {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+fn strip_quotes(s: &str) -> &str {
+    for q in ['"', '\''] {
+        if let Some(inner) = s.strip_prefix(q).and_then(|s| s.strip_suffix(q)) {
+            return inner;
+        }
+    }
+    s
+}
+
+/// pkgmogrify uses python's re.match which only anchors at the start.
+fn compile_anchored(pattern: &str, location: &str) -> MogrifyResult<Regex> {
+    Regex::new(&format!(r"\A(?:{})", pattern)).map_err(|e| MogrifyError::InvalidTransform {
+        source_location: location.to_owned(),
+        message: format!("invalid regular expression {}: {}", pattern, e),
+    })
+}
+
+fn compile(pattern: &str, location: &str) -> MogrifyResult<Regex> {
+    Regex::new(pattern).map_err(|e| MogrifyError::InvalidTransform {
+        source_location: location.to_owned(),
+        message: format!("invalid regular expression {}: {}", pattern, e),
+    })
+}
+
+fn is_match(regex: &Regex, value: &str) -> std::result::Result<bool, String> {
+    regex.is_match(value).map_err(|e| e.to_string())
+}
+
+fn parse_transform(body: &str, location: &str) -> MogrifyResult<Transform> {
+    let invalid = |message: String| MogrifyError::InvalidTransform {
+        source_location: location.to_owned(),
+        message,
+    };
+
+    let (matching, operation) = body
+        .split_once("->")
+        .ok_or(invalid(format!("missing -> in transform {}", body.trim())))?;
+
+    let mut types = vec![];
+    let mut matchers = vec![];
+    for token in split_words(matching).map_err(invalid)? {
+        if let Some((attr, value)) = token.split_once('=') {
+            let value = strip_quotes(value);
+            matchers.retain(|(a, _): &(String, Regex)| a != attr);
+            matchers.push((attr.to_owned(), compile_anchored(value, location)?));
+        } else {
+            types.push(token);
+        }
+    }
+
+    let operation = operation.trim();
+    let (op, args) = operation
+        .split_once(char::is_whitespace)
+        .map(|(op, args)| (op, args.trim()))
+        .unwrap_or((operation, ""));
+
+    let attr_value = |args: &str| -> MogrifyResult<(String, String)> {
+        let mut words = shell_split(args).map_err(invalid)?;
+        if words.len() != 2 {
+            return Err(invalid(format!(
+                "{} requires an attribute and a value: {}",
+                op, operation
+            )));
+        }
+        let value = words.pop().unwrap_or_default();
+        let attr = words.pop().unwrap_or_default();
+        Ok((attr, value))
+    };
+
+    let operation = match op {
+        "drop" => Operation::Drop,
+        "abort" => Operation::Abort,
+        "add" => {
+            let (attr, value) = attr_value(args)?;
+            Operation::Add(attr, value)
+        }
+        "default" => {
+            let (attr, value) = attr_value(args)?;
+            Operation::Default(attr, value)
+        }
+        "set" => {
+            let (attr, value) = attr_value(args)?;
+            Operation::Set(attr, value)
+        }
+        "delete" => {
+            let (attr, value) = attr_value(args)?;
+            Operation::Delete(attr, compile_anchored(&value, location)?)
+        }
+        "edit" => {
+            let mut words = shell_split(args).map_err(invalid)?.into_iter();
+            match (words.next(), words.next(), words.next(), words.next()) {
+                (Some(attr), Some(regex), replacement, None) => Operation::Edit(
+                    attr,
+                    compile(&regex, location)?,
+                    replacement.unwrap_or_default(),
+                ),
+                _ => {
+                    return Err(invalid(format!(
+                        "edit requires an attribute, a regular expression and an optional replacement: {}",
+                        operation
+                    )))
+                }
+            }
+        }
+        "emit" => Operation::Emit(args.to_owned()),
+        "print" => Operation::Print(args.to_owned()),
+        "exit" => {
+            let words = shell_split(args).map_err(invalid)?;
+            let code = match words.first() {
+                Some(code) => code
+                    .parse::<i32>()
+                    .map_err(|_| invalid(format!("exit code {} is not a number", code)))?,
+                None => 0,
+            };
+            Operation::Exit(
+                code,
+                words.into_iter().skip(1).collect::<Vec<_>>().join(" "),
+            )
+        }
+        "" => return Err(invalid(String::from("missing operation in transform"))),
+        unknown => return Err(invalid(format!("unknown operation {}", unknown))),
+    };
+
+    Ok(Transform {
+        types,
+        matchers,
+        operation,
+        location: location.to_owned(),
+    })
+}
+
+/// Splits the matching part of a transform into words. Unlike shell_split
+/// quotes are kept so that `value="a b"` stays one word.
+fn split_words(s: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut current = String::new();
+    let mut quote = None;
+    for c in s.chars() {
+        match quote {
+            Some(q) => {
+                current.push(c);
+                if c == q {
+                    quote = None;
+                }
+            }
+            None if c.is_whitespace() => {
+                if !current.is_empty() {
+                    words.push(std::mem::take(&mut current));
+                }
+            }
+            None => {
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+                current.push(c);
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated quote in {}", s.trim()));
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    Ok(words)
+}
+
+/// POSIX shell word splitting as done by python's shlex.split, which is
+/// what pkgmogrify uses for operation arguments.
+fn shell_split(s: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut current: Option<String> = None;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = current.take() {
+                    words.push(word);
+                }
+            }
+            '\\' => {
+                let word = current.get_or_insert_with(String::new);
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+            }
+            '\'' => {
+                let word = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(format!("unterminated quote in {}", s)),
+                    }
+                }
+            }
+            '"' => {
+                let word = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.peek() {
+                            Some('"') | Some('\\') => word.push(chars.next().unwrap_or('\\')),
+                            _ => word.push('\\'),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(format!("unterminated quote in {}", s)),
+                    }
+                }
+            }
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(word) = current {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Replaces `%<N>` with match groups of the transform and `%(attr)` and
+/// `%{attr}` with attributes of the action or the package.
+fn substitute(
+    s: &str,
+    action: &Action,
+    groups: &[Option<String>],
+    pkg_attrs: &HashMap<String, Vec<String>>,
+) -> std::result::Result<String, String> {
+    let mut backrefs = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("%<") {
+        backrefs.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let digits = after.strip_prefix('\\').unwrap_or(after);
+        let len = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(0);
+        if len == 0 || !digits[len..].starts_with('>') {
+            backrefs.push_str("%<");
+            rest = after;
+            continue;
+        }
+        let index: usize = digits[..len]
+            .parse()
+            .map_err(|_| format!("invalid match group reference {}", &rest[start..]))?;
+        let group = index
+            .checked_sub(1)
+            .and_then(|i| groups.get(i))
+            .ok_or(format!("no match group {} in transform", index))?;
+        backrefs.push_str(group.as_deref().unwrap_or_default());
+        rest = &digits[len + 1..];
+    }
+    backrefs.push_str(rest);
+
+    let mut out = String::with_capacity(backrefs.len());
+    let mut rest = backrefs.as_str();
+    while let Some(start) = rest.find(['%']) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (close, from_pkg) = match after.chars().next() {
+            Some('(') => (')', false),
+            Some('{') => ('}', true),
+            _ => {
+                out.push('%');
+                rest = after;
+                continue;
+            }
+        };
+        let end = find_unquoted(&after[1..], close).ok_or(format!(
+            "unterminated attribute reference {}",
+            &rest[start..]
+        ))?;
+        let reference = &after[1..end + 1];
+        out.push_str(&attribute_value(reference, action, from_pkg, pkg_attrs)?);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+fn find_unquoted(s: &str, needle: char) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == needle => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+fn attribute_value(
+    reference: &str,
+    action: &Action,
+    from_pkg: bool,
+    pkg_attrs: &HashMap<String, Vec<String>>,
+) -> std::result::Result<String, String> {
+    let mut parts = vec![];
+    let mut rest = reference;
+    while let Some(pos) = find_unquoted(rest, ';') {
+        parts.push(&rest[..pos]);
+        rest = &rest[pos + 1..];
+    }
+    parts.push(rest);
+
+    let name = parts[0];
+    let mut sep = " ";
+    let mut prefix = "";
+    let mut suffix = "";
+    let mut notfound = None;
+    for modifier in &parts[1..] {
+        let (key, value) = modifier
+            .split_once('=')
+            .ok_or(format!("invalid modifier {} in %({})", modifier, reference))?;
+        let value = strip_quotes(value);
+        match key {
+            "sep" => sep = value,
+            "prefix" => prefix = value,
+            "suffix" => suffix = value,
+            "notfound" => notfound = Some(value),
+            _ => return Err(format!("unknown modifier {} in {}", key, reference)),
+        }
+    }
+
+    let values = if from_pkg {
+        pkg_attrs.get(name).cloned().unwrap_or_default()
+    } else {
+        match name {
+            "action.hash" => action.payload.iter().cloned().collect(),
+            "action.name" => vec![action.kind.to_string()],
+            _ => action.values(name).to_vec(),
+        }
+    };
+
+    if values.is_empty() {
+        return notfound
+            .map(|v| v.to_owned())
+            .ok_or(format!("attribute {} not found", name));
+    }
+
+    Ok(values
+        .iter()
+        .map(|v| format!("{}{}{}", prefix, v, suffix))
+        .collect::<Vec<_>>()
+        .join(sep))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mogrify(input: &str) -> MogrifyOutput {
+        let mut mogrifier = Mogrifier::new();
+        mogrifier.add_include_dir(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../transform-include"
+        ));
+        mogrifier.read_str("test.mog", input).unwrap();
+        mogrifier.run().unwrap()
+    }
+
+    // Comments of included files are passed through, only compare actions.
+    fn actions(output: &MogrifyOutput) -> Vec<String> {
+        output.manifest.actions().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults_from_transform_include() {
+        let output = mogrify(
+            r#"set name=pkg.fmri value=pkg:/library/foo@1.0,5.11-2023.0.0.1
+dir path=usr
+dir path=etc
+file path=usr/bin/foo
+file path=usr/share/man/man1/foo.1
+file path=usr/lib/libfoo.a
+<include defaults>
+<include docs>
+"#,
+        );
+
+        assert_eq!(
+            actions(&output),
+            vec![
+                "set name=pkg.fmri value=pkg:/library/foo@1.0,5.11-2023.0.0.1",
+                "dir group=sys mode=0755 owner=root path=etc",
+                "file group=bin mode=0555 owner=root path=usr/bin/foo",
+                "file facet.doc.man=all group=bin mangler.man.stability=uncommitted mode=0444 owner=root path=usr/share/man/man1/foo.1",
+                "file group=bin mode=0444 owner=root path=usr/lib/libfoo.a",
+            ]
+        );
+    }
+
+    #[test]
+    fn emit_print_and_backreferences() {
+        let output = mogrify(
+            r#"set name=pkg.fmri value=pkg:/runtime/perl-536@5.36.0,5.11-2023.0.0.1
+file path=usr/perl5/5.36/bin/perl
+<transform file link hardlink path=usr/perl5/(5.[0-9]+)(/bin/[^/]+)$ -> emit \
+    link path=usr%<2> target=../perl5/%<1>%<2> mediator=perl mediator-version=%<1> >
+<transform set name=pkg.fmri value=pkg:/(.*)@(.*),.* -> \
+	print PACKAGE += "%<1>@%<2>" >
+"#,
+        );
+
+        assert_eq!(
+            actions(&output)[2],
+            "link mediator=perl mediator-version=5.36 path=usr/bin/perl target=../perl5/5.36/bin/perl"
+        );
+        assert_eq!(
+            output.printed,
+            vec![r#"PACKAGE += "runtime/perl-536@5.36.0""#]
+        );
+    }
+
+    #[test]
+    fn autopyc_emits_bytecode() {
+        let output = mogrify(
+            r#"file path=usr/lib/python3.9/vendor-packages/foo.py
+<include autopyc>
+"#,
+        );
+
+        assert_eq!(
+            actions(&output),
+            vec![
+                "file path=usr/lib/python3.9/vendor-packages/foo.py",
+                "file path=/usr/lib/python3.9/vendor-packages/__pycache__/foo.cpython-39.pyc",
+            ]
+        );
+    }
+
+    #[test]
+    fn edit_delete_and_locale_facets() {
+        let output = mogrify(
+            r#"file path=usr/lib/python3.9/vendor-packages/_foo.cpython-39.so pkg.depend.runpath=a
+file path=usr/share/locale/de/LC_MESSAGES/foo.mo
+<transform file -> \
+	edit path "^(usr/lib/python3\.\d+/vendor-packages/(.*/)?[^/]+)\.cpython-3\d+[dmu]*\.so$" "\1.so">
+<transform file -> delete pkg.depend.runpath .* >
+<include locale>
+"#,
+        );
+
+        assert_eq!(
+            actions(&output),
+            vec![
+                "file path=usr/lib/python3.9/vendor-packages/_foo.so",
+                "file facet.locale.de=true path=usr/share/locale/de/LC_MESSAGES/foo.mo",
+            ]
+        );
+    }
+
+    #[test]
+    fn pkg_action_and_attribute_substitution() {
+        let mut mogrifier = Mogrifier::new();
+        mogrifier.define("COMPONENT_NAME", "foo");
+        mogrifier
+            .read_str(
+                "test.mog",
+                r#"set name=pkg.fmri value=pkg:/library/foo@1.0,5.11-2023.0.0.1
+depend fmri=pkg:/a@1 fmri=pkg:/b type=require-any
+<transform set name=pkg.fmri -> emit \
+    set name=com.oracle.info.name value=$(COMPONENT_NAME) >
+<transform pkg pkg.fmri=.+@(.+),.+$ -> \
+    emit set name=com.oracle.info.version \
+             value=%{pkg.human-version;notfound='%<1>'} >
+<transform depend fmri=pkg:/.+ -> print %(fmri;sep="|";prefix="REQUIRED_PACKAGES += ") >
+"#,
+            )
+            .unwrap();
+        let output = mogrifier.run().unwrap();
+
+        assert_eq!(
+            actions(&output),
+            vec![
+                "set name=pkg.fmri value=pkg:/library/foo@1.0,5.11-2023.0.0.1",
+                "set name=com.oracle.info.name value=foo",
+                "depend fmri=pkg:/a@1 fmri=pkg:/b type=require-any",
+                "set name=com.oracle.info.version value=1.0",
+            ]
+        );
+        assert_eq!(
+            output.printed,
+            vec!["REQUIRED_PACKAGES += pkg:/a@1|REQUIRED_PACKAGES += pkg:/b"]
+        );
+    }
+
+    #[test]
+    fn invalid_transforms_are_reported() {
+        let mut mogrifier = Mogrifier::new();
+        assert!(mogrifier
+            .add_directive("<transform file path=foo drop>")
+            .is_err());
+        assert!(mogrifier
+            .add_directive("<transform file -> frobnicate>")
+            .is_err());
+        assert!(mogrifier.add_directive("<include does-not-exist>").is_err());
+    }
+}
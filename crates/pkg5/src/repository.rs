@@ -0,0 +1,613 @@
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read, read_to_string, write},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::{
+    action::{Action, ActionKind},
+    fmri::{Fmri, FmriError},
+    manifest::{Manifest, ManifestError},
+};
+
+#[derive(Error, Debug, Diagnostic)]
+pub enum RepositoryError {
+    #[error(transparent)]
+    #[diagnostic(code(pkg5::io))]
+    IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    #[diagnostic(code(pkg5::repository::json))]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Fmri(#[from] FmriError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Manifest(#[from] ManifestError),
+    #[error("{0} is not a pkg5 repository")]
+    #[diagnostic(code(pkg5::repository::not_a_repository))]
+    NotARepository(String),
+    #[error("publisher {0} does not exist in the repository")]
+    #[diagnostic(code(pkg5::repository::unknown_publisher))]
+    UnknownPublisher(String),
+    #[error("manifest has no pkg.fmri set action")]
+    #[diagnostic(code(pkg5::repository::missing_fmri))]
+    MissingFmri,
+    #[error("the fmri {0} has no version")]
+    #[diagnostic(code(pkg5::repository::missing_version))]
+    MissingVersion(String),
+    #[error("could not find the payload {0} for {1} in any of the payload directories")]
+    #[diagnostic(code(pkg5::repository::payload_not_found))]
+    PayloadNotFound(String, String),
+    #[error("no package {0} in the repository")]
+    #[diagnostic(code(pkg5::repository::no_such_package))]
+    NoSuchPackage(String),
+}
+
+type RepositoryResult<T> = std::result::Result<T, RepositoryError>;
+
+const REPOSITORY_CONFIG: &str = "pkg5.repository";
+const CATALOG_ATTRS: &str = "catalog.attrs";
+const CATALOG_BASE: &str = "catalog.base.C";
+const CATALOG_DEPENDENCY: &str = "catalog.dependency.C";
+const CATALOG_SUMMARY: &str = "catalog.summary.C";
+/// The timestamp format used in package versions.
+const VERSION_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+/// The timestamp format used in catalog files.
+const CATALOG_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CatalogPartInfo {
+    #[serde(rename = "last-modified")]
+    last_modified: String,
+    #[serde(rename = "signature-sha-1")]
+    signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CatalogAttrs {
+    created: String,
+    #[serde(rename = "last-modified")]
+    last_modified: String,
+    #[serde(rename = "package-count")]
+    package_count: usize,
+    #[serde(rename = "package-version-count")]
+    package_version_count: usize,
+    parts: BTreeMap<String, CatalogPartInfo>,
+    updates: BTreeMap<String, serde_json::Value>,
+    version: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CatalogEntry {
+    version: String,
+    #[serde(
+        rename = "signature-sha-1",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    signature: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    actions: Vec<String>,
+}
+
+/// publisher -> package stem -> versions
+type CatalogPart = BTreeMap<String, BTreeMap<String, Vec<CatalogEntry>>>;
+
+/// A pkg5 file repository as created by `pkgrepo create`.
+#[derive(Debug, Clone)]
+pub struct FileRepository {
+    root: PathBuf,
+}
+
+impl FileRepository {
+    /// Opens the repository at `root` creating it if it does not exist yet.
+    pub fn create<P: AsRef<Path>>(root: P) -> RepositoryResult<Self> {
+        let root = root.as_ref().to_path_buf();
+        create_dir_all(&root)?;
+        let config = root.join(REPOSITORY_CONFIG);
+        if !config.exists() {
+            write(&config, "[repository]\nversion = 4\n")?;
+        }
+        Ok(Self { root })
+    }
+
+    pub fn open<P: AsRef<Path>>(root: P) -> RepositoryResult<Self> {
+        let root = root.as_ref().to_path_buf();
+        if !root.join(REPOSITORY_CONFIG).is_file() {
+            return Err(RepositoryError::NotARepository(root.display().to_string()));
+        }
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn publisher_dir(&self, publisher: &str) -> PathBuf {
+        self.root.join("publisher").join(publisher)
+    }
+
+    fn catalog_dir(&self, publisher: &str) -> PathBuf {
+        self.publisher_dir(publisher).join("catalog")
+    }
+
+    fn file_path(&self, publisher: &str, hash: &str) -> PathBuf {
+        self.publisher_dir(publisher)
+            .join("file")
+            .join(&hash[..2.min(hash.len())])
+            .join(hash)
+    }
+
+    fn manifest_path(&self, publisher: &str, fmri: &Fmri) -> PathBuf {
+        self.publisher_dir(publisher)
+            .join("pkg")
+            .join(url_quote(&fmri.name))
+            .join(url_quote(fmri.version.as_deref().unwrap_or_default()))
+    }
+
+    pub fn has_publisher(&self, publisher: &str) -> bool {
+        self.publisher_dir(publisher).is_dir()
+    }
+
+    /// Adds a publisher. The first publisher of a repository becomes its default.
+    pub fn add_publisher(&self, publisher: &str) -> RepositoryResult<()> {
+        for dir in ["catalog", "file", "pkg"] {
+            create_dir_all(self.publisher_dir(publisher).join(dir))?;
+        }
+
+        let config_path = self.root.join(REPOSITORY_CONFIG);
+        let config = read_to_string(&config_path)?;
+        if !config.lines().any(|l| l.trim() == "[publisher]") {
+            write(
+                &config_path,
+                format!("[publisher]\nprefix = {}\n\n{}", publisher, config),
+            )?;
+        }
+
+        if !self.catalog_dir(publisher).join(CATALOG_ATTRS).exists() {
+            self.write_catalog(
+                publisher,
+                CatalogPart::new(),
+                CatalogPart::new(),
+                CatalogPart::new(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Publishes a manifest. Payloads of file and license actions are looked
+    /// up in `payload_dirs` in order. Returns the FMRI including the
+    /// publication timestamp.
+    pub fn publish<P: AsRef<Path>>(
+        &self,
+        publisher: &str,
+        manifest: &Manifest,
+        payload_dirs: &[P],
+    ) -> RepositoryResult<Fmri> {
+        if !self.has_publisher(publisher) {
+            return Err(RepositoryError::UnknownPublisher(publisher.to_owned()));
+        }
+
+        let mut fmri: Fmri = manifest
+            .fmri()
+            .ok_or(RepositoryError::MissingFmri)?
+            .parse()?;
+        let version = fmri
+            .version_without_timestamp()
+            .ok_or(RepositoryError::MissingVersion(fmri.to_string()))?
+            .to_owned();
+        let now = Utc::now();
+        fmri.publisher = Some(publisher.to_owned());
+        fmri.version = Some(format!(
+            "{}:{}",
+            version,
+            now.format(VERSION_TIMESTAMP_FORMAT)
+        ));
+
+        let mut actions = vec![];
+        for action in manifest.actions() {
+            let mut action = action.clone();
+            match action.kind {
+                ActionKind::Set if action.get("name") == Some("pkg.fmri") => {
+                    action.set_attr("value", &fmri.to_string());
+                    actions.insert(0, action);
+                    continue;
+                }
+                ActionKind::File | ActionKind::License => {
+                    self.store_payload(publisher, &mut action, payload_dirs)?;
+                }
+                _ => {}
+            }
+            actions.push(action);
+        }
+
+        let mut manifest_text = actions
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        manifest_text.push('\n');
+
+        let manifest_path = self.manifest_path(publisher, &fmri);
+        if let Some(parent) = manifest_path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(&manifest_path, &manifest_text)?;
+
+        self.add_to_catalog(publisher, &fmri, &actions, &manifest_text, now)?;
+
+        Ok(fmri)
+    }
+
+    fn store_payload<P: AsRef<Path>>(
+        &self,
+        publisher: &str,
+        action: &mut Action,
+        payload_dirs: &[P],
+    ) -> RepositoryResult<()> {
+        let payload = action
+            .payload
+            .clone()
+            .or(action.get("path").map(|p| p.to_owned()))
+            .ok_or(RepositoryError::PayloadNotFound(
+                String::new(),
+                action.to_string(),
+            ))?;
+
+        let payload_path = payload_dirs
+            .iter()
+            .map(|dir| dir.as_ref().join(&payload))
+            .find(|p| p.is_file())
+            .ok_or(RepositoryError::PayloadNotFound(
+                payload.clone(),
+                action.to_string(),
+            ))?;
+
+        let content = read(payload_path)?;
+        let hash = format!("{:x}", Sha1::digest(&content));
+
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&content)?;
+        let compressed = encoder.finish()?;
+        let chash = format!("{:x}", Sha1::digest(&compressed));
+
+        let file_path = self.file_path(publisher, &hash);
+        if !file_path.exists() {
+            if let Some(parent) = file_path.parent() {
+                create_dir_all(parent)?;
+            }
+            write(&file_path, &compressed)?;
+        }
+
+        action.payload = Some(hash);
+        action.set_attr("chash", &chash);
+        action.set_attr("pkg.size", &content.len().to_string());
+        action.set_attr("pkg.csize", &compressed.len().to_string());
+        Ok(())
+    }
+
+    fn read_catalog_part(&self, publisher: &str, name: &str) -> RepositoryResult<CatalogPart> {
+        let path = self.catalog_dir(publisher).join(name);
+        if !path.exists() {
+            return Ok(CatalogPart::new());
+        }
+        let mut value: serde_json::Value = serde_json::from_slice(&read(path)?)?;
+        if let Some(map) = value.as_object_mut() {
+            map.remove("_SIGNATURE");
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    fn add_to_catalog(
+        &self,
+        publisher: &str,
+        fmri: &Fmri,
+        actions: &[Action],
+        manifest_text: &str,
+        now: DateTime<Utc>,
+    ) -> RepositoryResult<()> {
+        let version = fmri.version.clone().unwrap_or_default();
+
+        let mut dependency_actions = vec![];
+        let mut summary_actions = vec![];
+        for action in actions {
+            match action.kind {
+                ActionKind::Depend => dependency_actions.push(action.to_string()),
+                ActionKind::Set => match action.get("name") {
+                    Some("pkg.fmri") => {}
+                    Some(name)
+                        if name.starts_with("variant.")
+                            || name.starts_with("facet.")
+                            || ["pkg.obsolete", "pkg.renamed", "pkg.legacy"].contains(&name) =>
+                    {
+                        dependency_actions.push(action.to_string())
+                    }
+                    _ => summary_actions.push(action.to_string()),
+                },
+                _ => {}
+            }
+        }
+
+        let mut parts = vec![];
+        for (name, entry) in [
+            (
+                CATALOG_BASE,
+                CatalogEntry {
+                    version: version.clone(),
+                    signature: Some(format!("{:x}", Sha1::digest(manifest_text.as_bytes()))),
+                    actions: vec![],
+                },
+            ),
+            (
+                CATALOG_DEPENDENCY,
+                CatalogEntry {
+                    version: version.clone(),
+                    signature: None,
+                    actions: dependency_actions,
+                },
+            ),
+            (
+                CATALOG_SUMMARY,
+                CatalogEntry {
+                    version: version.clone(),
+                    signature: None,
+                    actions: summary_actions,
+                },
+            ),
+        ] {
+            let mut part = self.read_catalog_part(publisher, name)?;
+            let versions = part
+                .entry(publisher.to_owned())
+                .or_default()
+                .entry(fmri.name.clone())
+                .or_default();
+            versions.retain(|e| e.version != version);
+            versions.push(entry);
+            parts.push(part);
+        }
+
+        let summary = parts.pop().unwrap_or_default();
+        let dependency = parts.pop().unwrap_or_default();
+        let base = parts.pop().unwrap_or_default();
+        self.write_catalog_at(publisher, base, dependency, summary, now)
+    }
+
+    fn write_catalog(
+        &self,
+        publisher: &str,
+        base: CatalogPart,
+        dependency: CatalogPart,
+        summary: CatalogPart,
+    ) -> RepositoryResult<()> {
+        self.write_catalog_at(publisher, base, dependency, summary, Utc::now())
+    }
+
+    fn write_catalog_at(
+        &self,
+        publisher: &str,
+        base: CatalogPart,
+        dependency: CatalogPart,
+        summary: CatalogPart,
+        now: DateTime<Utc>,
+    ) -> RepositoryResult<()> {
+        let catalog_dir = self.catalog_dir(publisher);
+        create_dir_all(&catalog_dir)?;
+        let timestamp = now.format(CATALOG_TIMESTAMP_FORMAT).to_string();
+
+        let attrs_path = catalog_dir.join(CATALOG_ATTRS);
+        let mut attrs = if attrs_path.exists() {
+            serde_json::from_slice::<CatalogAttrs>(&read(&attrs_path)?)?
+        } else {
+            CatalogAttrs {
+                created: timestamp.clone(),
+                last_modified: timestamp.clone(),
+                package_count: 0,
+                package_version_count: 0,
+                parts: BTreeMap::new(),
+                updates: BTreeMap::new(),
+                version: 1,
+            }
+        };
+
+        attrs.package_count = base.values().map(|stems| stems.len()).sum();
+        attrs.package_version_count = base
+            .values()
+            .flat_map(|stems| stems.values())
+            .map(|versions| versions.len())
+            .sum();
+        attrs.last_modified = timestamp.clone();
+
+        for (name, part) in [
+            (CATALOG_BASE, base),
+            (CATALOG_DEPENDENCY, dependency),
+            (CATALOG_SUMMARY, summary),
+        ] {
+            let contents = serde_json::to_vec(&part)?;
+            write(catalog_dir.join(name), &contents)?;
+            attrs.parts.insert(
+                name.to_owned(),
+                CatalogPartInfo {
+                    last_modified: timestamp.clone(),
+                    signature: format!("{:x}", Sha1::digest(&contents)),
+                },
+            );
+        }
+
+        write(attrs_path, serde_json::to_vec(&attrs)?)?;
+        Ok(())
+    }
+
+    /// Lists all package versions of a publisher.
+    pub fn packages(&self, publisher: &str) -> RepositoryResult<Vec<Fmri>> {
+        if !self.has_publisher(publisher) {
+            return Err(RepositoryError::UnknownPublisher(publisher.to_owned()));
+        }
+        let base = self.read_catalog_part(publisher, CATALOG_BASE)?;
+        Ok(base
+            .get(publisher)
+            .map(|stems| {
+                stems
+                    .iter()
+                    .flat_map(|(name, versions)| {
+                        versions.iter().map(|entry| Fmri {
+                            publisher: Some(publisher.to_owned()),
+                            name: name.clone(),
+                            version: Some(entry.version.clone()),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Reads a published manifest. Without a timestamp in the version of
+    /// `fmri` the latest matching publication is returned.
+    pub fn manifest(&self, publisher: &str, fmri: &Fmri) -> RepositoryResult<Manifest> {
+        let found = self
+            .packages(publisher)?
+            .into_iter()
+            .rfind(|p| {
+                p.name == fmri.name
+                    && match &fmri.version {
+                        Some(v) if v.contains(':') => p.version.as_ref() == Some(v),
+                        Some(_) => {
+                            p.version_without_timestamp() == fmri.version_without_timestamp()
+                        }
+                        None => true,
+                    }
+            })
+            .ok_or(RepositoryError::NoSuchPackage(fmri.to_string()))?;
+
+        Ok(read_to_string(self.manifest_path(publisher, &found))?.parse()?)
+    }
+
+    /// Reads the uncompressed content of a payload.
+    pub fn file(&self, publisher: &str, hash: &str) -> RepositoryResult<Vec<u8>> {
+        let compressed = read(self.file_path(publisher, hash))?;
+        let mut content = vec![];
+        GzDecoder::new(compressed.as_slice()).read_to_end(&mut content)?;
+        Ok(content)
+    }
+}
+
+/// Percent encodes everything but unreserved characters like python's
+/// `urllib.parse.quote(s, "")` which pkg(5) uses for repository paths.
+fn url_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"_.-~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_repository_paths() {
+        assert_eq!(url_quote("library/zlib"), "library%2Fzlib");
+        assert_eq!(
+            url_quote("1.2.13,5.11-2023.0.0.1:20231010T101010Z"),
+            "1.2.13%2C5.11-2023.0.0.1%3A20231010T101010Z"
+        );
+    }
+
+    #[test]
+    fn publish_and_read_back() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let proto_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(proto_dir.path().join("usr/bin")).unwrap();
+        write(proto_dir.path().join("usr/bin/foo"), "hello world\n").unwrap();
+        write(proto_dir.path().join("COPYING"), "MIT\n").unwrap();
+
+        let manifest: Manifest = r#"set name=pkg.summary value="foo tool"
+set name=pkg.fmri value=pkg:/utility/foo@1.0,5.11-2023.0.0.1
+dir path=usr/bin owner=root group=bin mode=0755
+file path=usr/bin/foo owner=root group=bin mode=0555
+license COPYING license=MIT
+depend fmri=pkg:/library/zlib type=require
+"#
+        .parse()
+        .unwrap();
+
+        let repo = FileRepository::create(repo_dir.path()).unwrap();
+        assert!(!repo.has_publisher("userland"));
+        assert!(matches!(
+            repo.publish("userland", &manifest, &[proto_dir.path()]),
+            Err(RepositoryError::UnknownPublisher(_))
+        ));
+        repo.add_publisher("userland").unwrap();
+
+        let fmri = repo
+            .publish("userland", &manifest, &[proto_dir.path()])
+            .unwrap();
+        assert_eq!(fmri.publisher.as_deref(), Some("userland"));
+        assert_eq!(
+            fmri.version_without_timestamp(),
+            Some("1.0,5.11-2023.0.0.1")
+        );
+
+        let repo = FileRepository::open(repo_dir.path()).unwrap();
+        assert_eq!(repo.packages("userland").unwrap(), vec![fmri.clone()]);
+        let config = read_to_string(repo_dir.path().join(REPOSITORY_CONFIG)).unwrap();
+        assert!(config.starts_with("[publisher]\nprefix = userland\n"));
+
+        let published = repo
+            .manifest(
+                "userland",
+                &"pkg:/utility/foo@1.0,5.11-2023.0.0.1".parse().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(published.fmri(), Some(fmri.to_string().as_str()));
+        let file = published.actions_of(ActionKind::File).next().unwrap();
+        let hash = file.payload.clone().unwrap();
+        assert_eq!(hash, format!("{:x}", Sha1::digest(b"hello world\n")));
+        assert_eq!(file.get("pkg.size"), Some("12"));
+        assert_eq!(repo.file("userland", &hash).unwrap(), b"hello world\n");
+
+        let summary = read_to_string(
+            repo_dir
+                .path()
+                .join("publisher/userland/catalog/catalog.summary.C"),
+        )
+        .unwrap();
+        assert!(summary.contains("foo tool"));
+        let dependency = read_to_string(
+            repo_dir
+                .path()
+                .join("publisher/userland/catalog/catalog.dependency.C"),
+        )
+        .unwrap();
+        assert!(dependency.contains("pkg:/library/zlib"));
+    }
+
+    #[test]
+    fn publish_fails_on_missing_payload() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo = FileRepository::create(repo_dir.path()).unwrap();
+        repo.add_publisher("userland").unwrap();
+        let manifest: Manifest = "set name=pkg.fmri value=pkg:/foo@1.0\nfile path=usr/bin/foo\n"
+            .parse()
+            .unwrap();
+        let empty: [&Path; 0] = [];
+        assert!(matches!(
+            repo.publish("userland", &manifest, &empty),
+            Err(RepositoryError::PayloadNotFound(..))
+        ));
+    }
+}
@@ -6,22 +6,25 @@ use std::{
     process::{Command, Stdio},
 };
 
-use bundle::{Bundle, ConfigureBuildSection, ScriptBuildSection};
+use bundle::{
+    Bundle, CMakeBuildSection, ConfigureBuildSection, InstallDirectiveNode, MesonBuildSection,
+    NoBuildSection, ScriptBuildSection,
+};
 use miette::{IntoDiagnostic, Result, WrapErr};
 
-use crate::{config::Settings, derive_source_name, workspace::Workspace};
+use crate::{derive_source_name, logs::run_logged, sandbox::Sandbox, workspace::Workspace};
 
-pub fn build_package_sources(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result<()> {
+pub fn build_package_sources(wks: &Workspace, pkg: &Bundle, sandbox: &Sandbox) -> Result<()> {
     match pkg.package_document.ensure_build_section() {
-        bundle::BuildSection::Configure(c) => build_using_automake(wks, pkg, &c, settings),
-        bundle::BuildSection::CMake => todo!(),
-        bundle::BuildSection::Meson => todo!(),
+        bundle::BuildSection::Configure(c) => build_using_automake(wks, pkg, &c, sandbox),
+        bundle::BuildSection::Cmake(c) => build_using_cmake(wks, pkg, &c, sandbox),
+        bundle::BuildSection::Meson(m) => build_using_meson(wks, pkg, &m, sandbox),
         bundle::BuildSection::Build(s) => {
-            build_using_scripts(wks, pkg, &s, settings)?;
+            build_using_scripts(wks, pkg, &s, sandbox)?;
 
             Ok(())
         }
-        bundle::BuildSection::NoBuild => todo!(),
+        bundle::BuildSection::NoBuild(n) => stage_without_build(wks, pkg, &n),
     }?;
 
     Ok(())
@@ -31,7 +34,7 @@ fn build_using_scripts(
     wks: &Workspace,
     pkg: &Bundle,
     build_section: &ScriptBuildSection,
-    settings: &Settings,
+    sandbox: &Sandbox,
 ) -> Result<()> {
     let build_dir = wks.get_or_create_build_dir()?;
     let unpack_name = derive_source_name(
@@ -42,7 +45,8 @@ fn build_using_scripts(
     std::env::set_current_dir(&unpack_path).into_diagnostic()?;
 
     for script in &build_section.scripts {
-        let status = Command::new(pkg.get_path().join(&script.name))
+        let mut script_cmd = sandbox.command(pkg.get_path().join(&script.name));
+        script_cmd
             .stdout(Stdio::inherit())
             .env(
                 "PROTO_DIR",
@@ -50,10 +54,8 @@ fn build_using_scripts(
                     .into_diagnostic()?
                     .into_os_string(),
             )
-            .env("UNPACK_DIR", &unpack_path.clone().into_os_string())
-            .env("PATH", settings.get_search_path().join(":"))
-            .status()
-            .into_diagnostic()?;
+            .env("UNPACK_DIR", &unpack_path.clone().into_os_string());
+        let status = run_logged(wks, &mut script_cmd)?;
 
         if status.success() {
             println!(
@@ -114,7 +116,83 @@ fn build_using_scripts(
         }
     }
 
-    for install_directive in &build_section.install_directives {
+    run_install_directives(wks, pkg, &unpack_path, &build_section.install_directives)?;
+
+    println!("Build for package {} finished", pkg.get_name());
+
+    Ok(())
+}
+
+fn stage_without_build(
+    wks: &Workspace,
+    pkg: &Bundle,
+    build_section: &NoBuildSection,
+) -> Result<()> {
+    // Packages made only of install directives take their files from the bundle
+    let unpack_path = match pkg.package_document.sources.first() {
+        Some(section) => Some(wks.get_or_create_build_dir()?.join(derive_source_name(
+            pkg.package_document.name.clone(),
+            section,
+        ))),
+        None => None,
+    };
+
+    if !build_section.install_directives.is_empty() {
+        run_install_directives(
+            wks,
+            pkg,
+            unpack_path.as_deref().unwrap_or(pkg.get_path()),
+            &build_section.install_directives,
+        )?;
+    } else if let Some(unpack_path) = unpack_path.filter(|p| p.exists()) {
+        let target_path = if let Some(prefix) = &pkg.package_document.prefix {
+            let prefix = if prefix.starts_with("/") {
+                &prefix[1..]
+            } else {
+                prefix.as_str()
+            };
+
+            wks.get_or_create_prototype_dir()?.join(prefix)
+        } else {
+            wks.get_or_create_prototype_dir()?
+        };
+
+        if !target_path.exists() {
+            DirBuilder::new()
+                .recursive(true)
+                .create(&target_path)
+                .into_diagnostic()?;
+        }
+
+        println!(
+            "Staging sources {} into prototype directory {}",
+            unpack_path.display(),
+            target_path.display()
+        );
+
+        let mut copy_options = fs_extra::dir::CopyOptions::default();
+        copy_options.overwrite = true;
+        copy_options.content_only = true;
+        fs_extra::dir::copy(&unpack_path, &target_path, &copy_options).into_diagnostic()?;
+    } else {
+        println!(
+            "Package {} has no sources to stage into the prototype directory",
+            pkg.get_name()
+        );
+    }
+
+    println!("Staging for package {} finished", pkg.get_name());
+
+    Ok(())
+}
+
+fn run_install_directives(
+    wks: &Workspace,
+    pkg: &Bundle,
+    unpack_path: &Path,
+    install_directives: &[InstallDirectiveNode],
+) -> Result<()> {
+    for install_directive in install_directives {
         let target_path = if let Some(prefix) = &pkg.package_document.prefix {
             let prefix = if prefix.starts_with("/") {
                 &prefix[1..]
@@ -198,8 +276,6 @@ fn build_using_scripts(
         println!("Copy suceeded");
     }
 
-    println!("Build for package {} finished", pkg.get_name());
-
     Ok(())
 }
 
@@ -237,14 +313,14 @@ fn copy_with_rsync<P: AsRef<Path>>(
     );
 
     // point rsync command to it to copy over selected files
-    let rsync_status = Command::new("rsync")
+    let mut rsync_cmd = Command::new("rsync");
+    rsync_cmd
         .arg("-avp")
         .arg(&contents_file_arg)
         .arg(path_2_string(from))
         .arg(path_2_string(to))
-        .stdout(Stdio::inherit())
-        .status()
-        .into_diagnostic()?;
+        .stdout(Stdio::inherit());
+    let rsync_status = run_logged(wks, &mut rsync_cmd)?;
 
     if rsync_status.success() {
         Ok(())
@@ -253,6 +329,22 @@ fn copy_with_rsync<P: AsRef<Path>>(
     }
 }
 
+/// The triple of the machine running the build as the host compiler reports it.
+fn build_triple(sandbox: &Sandbox) -> Result<String> {
+    let output = sandbox
+        .command("cc")
+        .arg("-dumpmachine")
+        .output()
+        .into_diagnostic()
+        .wrap_err("could not ask the host compiler for the build triple")?;
+    if !output.status.success() {
+        return Err(miette::miette!(
+            "cc -dumpmachine failed, the build triple of a cross compile can not be determined"
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
 fn path_2_string<P: AsRef<Path>>(path: P) -> String {
     path.as_ref().to_string_lossy().to_string()
 }
@@ -261,7 +353,7 @@ fn build_using_automake(
     wks: &Workspace,
     pkg: &Bundle,
     build_section: &ConfigureBuildSection,
-    settings: &Settings,
+    sandbox: &Sandbox,
 ) -> Result<()> {
     let build_dir = wks.get_or_create_build_dir()?;
     let unpack_name = derive_source_name(
@@ -280,6 +372,18 @@ fn build_using_automake(
     let mut option_vec: Vec<_> = vec![];
     let mut env_flags: HashMap<String, String> = HashMap::new();
 
+    // Flags of the package are added to the ones of the sandbox like the target defaults
+    for flag_name in ["CFLAGS", "CXXFLAGS", "CPPFLAGS", "FFLAGS", "LDFLAGS"] {
+        if let Some(value) = sandbox.get_env().get(flag_name) {
+            env_flags.insert(String::from(flag_name), value.clone());
+        }
+    }
+
+    if let Some(target) = sandbox.get_target() {
+        option_vec.push(format!("--host={}", target.triple));
+        option_vec.push(format!("--build={}", build_triple(sandbox)?));
+    }
+
     for option in build_section.options.iter() {
         let opt_arg = format!("--{}", option.option);
         option_vec.push(opt_arg);
@@ -319,7 +423,6 @@ fn build_using_automake(
         option_vec.push(format!("--prefix={}", prefix));
     }
 
-    env_flags.insert("PATH".into(), settings.get_search_path().join(":"));
     let proto_dir_path = wks.get_or_create_prototype_dir()?;
     let proto_dir_str = proto_dir_path.to_string_lossy().to_string();
 
@@ -332,8 +435,7 @@ fn build_using_automake(
         String::from("./configure")
     };
 
-    let mut configure_cmd = Command::new(&bin_path);
-    configure_cmd.env_clear();
+    let mut configure_cmd = sandbox.command(&bin_path);
     configure_cmd.envs(&env_flags);
     configure_cmd.args(&option_vec);
     configure_cmd.arg(&destdir_arg);
@@ -352,7 +454,7 @@ fn build_using_automake(
             .join(",")
     );
 
-    let status = configure_cmd.status().into_diagnostic()?;
+    let status = run_logged(wks, &mut configure_cmd)?;
     if status.success() {
         println!("Successfully configured {}", pkg.get_name());
     } else {
@@ -362,9 +464,228 @@ fn build_using_automake(
         )));
     }
 
-    crate::compile::run_compile(wks, pkg, settings).wrap_err("compilation step failed")?;
+    crate::compile::run_compile(wks, pkg, sandbox).wrap_err("compilation step failed")?;
 
-    crate::install::run_install(wks, pkg, settings).wrap_err("installation step failed")
+    crate::install::run_install(wks, pkg, sandbox).wrap_err("installation step failed")
+}
+
+fn build_using_cmake(
+    wks: &Workspace,
+    pkg: &Bundle,
+    build_section: &CMakeBuildSection,
+    sandbox: &Sandbox,
+) -> Result<()> {
+    let build_dir = wks.get_or_create_build_dir()?;
+    let unpack_name = derive_source_name(
+        pkg.package_document.name.clone(),
+        &pkg.package_document.sources[0],
+    );
+    let unpack_path = build_dir.join(&unpack_name);
+    let out_dir = build_dir.join("out");
+    if !out_dir.exists() {
+        DirBuilder::new().create(&out_dir).into_diagnostic()?;
+    }
+    std::env::set_current_dir(&out_dir).into_diagnostic()?;
+
+    let mut option_vec: Vec<String> = vec![
+        String::from("-S"),
+        path_2_string(&unpack_path),
+        String::from("-B"),
+        path_2_string(&out_dir),
+    ];
+
+    if let Some(generator) = &build_section.generator {
+        option_vec.push(String::from("-G"));
+        option_vec.push(generator.clone());
+    }
+
+    if let Some(prefix) = &pkg.package_document.prefix {
+        option_vec.push(format!("-DCMAKE_INSTALL_PREFIX={}", prefix));
+    }
+
+    if let Some(build_type) = &build_section.build_type {
+        option_vec.push(format!("-DCMAKE_BUILD_TYPE={}", build_type));
+    }
+
+    if let Some(toolchain_file) = &build_section.toolchain_file {
+        // Toolchain files are shipped in the bundle next to the package.kdl
+        let toolchain_path = pkg.get_path().join(toolchain_file);
+        option_vec.push(format!(
+            "-DCMAKE_TOOLCHAIN_FILE={}",
+            path_2_string(toolchain_path)
+        ));
+    }
+
+    for define in build_section.defines.iter() {
+        let mut define = define.clone();
+        define.value = expand_env(&define.value)?;
+        option_vec.push(define.to_arg());
+    }
+
+    let env_flags: HashMap<String, String> = HashMap::new();
+
+    let mut cmake_cmd = sandbox.command("cmake");
+    cmake_cmd.envs(&env_flags);
+    cmake_cmd.args(&option_vec);
+
+    cmake_cmd.stdin(Stdio::null());
+    cmake_cmd.stdout(Stdio::inherit());
+
+    println!(
+        "Running cmake with options {}; env=[{}]",
+        option_vec.join(" "),
+        env_flags
+            .into_iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join(",")
+    );
+
+    let status = run_logged(wks, &mut cmake_cmd)?;
+    if status.success() {
+        println!("Successfully configured {}", pkg.get_name());
+    } else {
+        return Err(miette::miette!(format!(
+            "Could not configure {}",
+            pkg.get_name()
+        )));
+    }
+
+    crate::compile::run_compile(wks, pkg, sandbox).wrap_err("compilation step failed")?;
+
+    crate::install::run_install(wks, pkg, sandbox).wrap_err("installation step failed")
+}
+
+fn build_using_meson(
+    wks: &Workspace,
+    pkg: &Bundle,
+    build_section: &MesonBuildSection,
+    sandbox: &Sandbox,
+) -> Result<()> {
+    let build_dir = wks.get_or_create_build_dir()?;
+    let unpack_name = derive_source_name(
+        pkg.package_document.name.clone(),
+        &pkg.package_document.sources[0],
+    );
+    let unpack_path = build_dir.join(&unpack_name);
+    let out_dir = build_dir.join("out");
+    std::env::set_current_dir(&build_dir).into_diagnostic()?;
+
+    let mut option_vec: Vec<String> = vec![String::from("setup")];
+
+    // Rerunning with --no-clean needs to reconfigure the existing build directory
+    if out_dir.join("meson-private").exists() {
+        option_vec.push(String::from("--reconfigure"));
+    }
+
+    if let Some(prefix) = build_section
+        .prefix
+        .as_ref()
+        .or(pkg.package_document.prefix.as_ref())
+    {
+        option_vec.push(format!("--prefix={}", prefix));
+    }
+
+    if let Some(libdir) = &build_section.libdir {
+        option_vec.push(format!("--libdir={}", libdir));
+    }
+
+    if let Some(buildtype) = &build_section.buildtype {
+        option_vec.push(format!("--buildtype={}", buildtype));
+    }
+
+    // Cross and native files are shipped in the bundle next to the package.kdl
+    if let Some(cross_file) = &build_section.cross_file {
+        option_vec.push(format!(
+            "--cross-file={}",
+            path_2_string(pkg.get_path().join(cross_file))
+        ));
+    }
+
+    if let Some(native_file) = &build_section.native_file {
+        option_vec.push(format!(
+            "--native-file={}",
+            path_2_string(pkg.get_path().join(native_file))
+        ));
+    }
+
+    for option in build_section.options.iter() {
+        let mut option = option.clone();
+        option.value = expand_env(&option.value)?;
+        option_vec.push(option.to_arg());
+    }
+
+    option_vec.push(path_2_string(&out_dir));
+    option_vec.push(path_2_string(&unpack_path));
+
+    let proto_dir_str = path_2_string(wks.get_or_create_prototype_dir()?);
+
+    let mut env_flags: HashMap<String, String> = HashMap::new();
+
+    run_meson(wks, sandbox, &option_vec, &env_flags)?;
+    println!("Successfully configured {}", pkg.get_name());
+
+    run_meson(
+        wks,
+        sandbox,
+        &[
+            String::from("compile"),
+            String::from("-C"),
+            path_2_string(&out_dir),
+        ],
+        &env_flags,
+    )?;
+    println!("Successfully built {}", pkg.get_name());
+
+    env_flags.insert(String::from("DESTDIR"), proto_dir_str.clone());
+    run_meson(
+        wks,
+        sandbox,
+        &[
+            String::from("install"),
+            String::from("-C"),
+            path_2_string(&out_dir),
+            format!("--destdir={}", &proto_dir_str),
+        ],
+        &env_flags,
+    )?;
+    println!("Successfully installed {}", pkg.get_name());
+
+    Ok(())
+}
+
+fn run_meson(
+    wks: &Workspace,
+    sandbox: &Sandbox,
+    args: &[String],
+    env_flags: &HashMap<String, String>,
+) -> Result<()> {
+    let mut meson_cmd = sandbox.command("meson");
+    meson_cmd.envs(env_flags);
+    meson_cmd.args(args);
+
+    meson_cmd.stdin(Stdio::null());
+    meson_cmd.stdout(Stdio::inherit());
+
+    println!(
+        "Running meson {}; env=[{}]",
+        args.join(" "),
+        env_flags
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join(",")
+    );
+
+    let status = run_logged(wks, &mut meson_cmd)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(miette::miette!(format!(
+            "meson {} returned a non zero exit code",
+            args.first().map(|a| a.as_str()).unwrap_or("")
+        )))
+    }
 }
 
 #[inline(never)]
@@ -382,3 +703,47 @@ fn expand_env(value: &str) -> Result<String> {
         Ok(value.clone().to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    #[test]
+    fn no_build_without_sources() -> miette::Result<()> {
+        let dir = test_dir("no-build-without-sources");
+        let bundle_dir = dir.join("site-config");
+        std::fs::create_dir_all(bundle_dir.join("files")).into_diagnostic()?;
+        std::fs::write(bundle_dir.join("files/motd"), "welcome\n").into_diagnostic()?;
+        std::fs::write(
+            bundle_dir.join("package.kdl"),
+            r#"
+            name "system/site-config"
+            project-name "site-config"
+            no-build {
+                install src="files/motd" target="etc/motd" name="motd"
+            }
+        "#,
+        )
+        .into_diagnostic()?;
+
+        let pkg = Bundle::open_local(&bundle_dir)?;
+        let wks = Workspace::new(dir.join("wks"))?;
+        let bundle::BuildSection::NoBuild(section) = pkg.package_document.ensure_build_section()
+        else {
+            panic!("expected a no-build section");
+        };
+        stage_without_build(&wks, &pkg, &section)?;
+        assert_eq!(
+            std::fs::read_to_string(wks.get_or_create_prototype_dir()?.join("etc/motd"))
+                .into_diagnostic()?,
+            "welcome\n"
+        );
+
+        // Without sources and install directives there is nothing to stage
+        stage_without_build(&wks, &pkg, &NoBuildSection::default())?;
+
+        std::fs::remove_dir_all(&dir).into_diagnostic()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{read_dir, read_to_string, DirBuilder, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::config::Settings;
+use bundle::ArchiveSource;
+use clap::Subcommand;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+/// Directory below the archives directory holding the archives named by
+/// their sha256.
+const STORE_DIR: &str = "sha256";
+const INDEX_FILE: &str = "index.json";
+
+/// Downloads run in parallel so updates of the index must not overlap.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum CacheCommand {
+    /// List the cached archives and the urls they were downloaded from
+    List,
+    /// Check that every cached archive still has the checksum it is stored under
+    Verify {
+        /// Remove the archives that do not match
+        #[arg(long, default_value = "false")]
+        remove: bool,
+    },
+    /// Remove archives that are no longer in the index or used by a gate
+    Prune {
+        /// Only keep the archives the packages of this gate use
+        #[arg(long, short)]
+        gate: Option<PathBuf>,
+
+        /// Only show what would be removed
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+    },
+    /// Add an archive that was downloaded by other means
+    Import {
+        path: PathBuf,
+
+        /// The url the archive is downloaded from so packages using it find it
+        #[arg(long)]
+        url: Option<String>,
+    },
+}
+
+/// Maps the url an archive was downloaded from to its digests. The archive
+/// itself is stored under its sha256 so archives with the same file name
+/// do not collide and a changed upstream file is never mistaken for the
+/// cached one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct CacheIndex {
+    pub archives: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CacheEntry {
+    pub sha256: String,
+    pub sha512: String,
+    pub file_name: String,
+}
+
+impl CacheIndex {
+    pub fn load() -> Result<Self> {
+        let path = Settings::get_or_create_archives_dir()?.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = read_to_string(&path).into_diagnostic()?;
+        serde_json::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err(format!(
+                "could not read archive cache index {}",
+                path.display()
+            ))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let archives_dir = Settings::get_or_create_archives_dir()?;
+        let tmp_path = archives_dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut f = File::create(&tmp_path).into_diagnostic()?;
+        f.write_all(
+            serde_json::to_string_pretty(self)
+                .into_diagnostic()?
+                .as_bytes(),
+        )
+        .into_diagnostic()?;
+        std::fs::rename(tmp_path, archives_dir.join(INDEX_FILE)).into_diagnostic()?;
+        Ok(())
+    }
+
+    /// The entry of the url of the archive if the sha512 the archive
+    /// declares matches it.
+    pub fn find(&self, archive: &ArchiveSource) -> Option<&CacheEntry> {
+        let sha512 = archive.sha512.as_ref()?;
+        self.archives
+            .get(&archive.src)
+            .filter(|entry| sha512.eq_ignore_ascii_case(&entry.sha512))
+    }
+}
+
+pub(crate) fn get_or_create_store_dir() -> Result<PathBuf> {
+    let store_dir = Settings::get_or_create_archives_dir()?.join(STORE_DIR);
+    if !store_dir.exists() {
+        DirBuilder::new()
+            .recursive(true)
+            .create(&store_dir)
+            .into_diagnostic()?;
+    }
+    Ok(store_dir)
+}
+
+/// Returns the cached copy of the archive. Archives with a sha256 are found
+/// by it, all others by the url they were downloaded from as long as the
+/// sha512 they declare matches. Archives without a checksum are never
+/// taken from the cache so they are verified again on every download.
+pub(crate) fn lookup(archive: &ArchiveSource) -> Result<Option<PathBuf>> {
+    let store_dir = get_or_create_store_dir()?;
+    if let Some(sha256) = &archive.sha256 {
+        let path = store_dir.join(sha256.to_lowercase());
+        return Ok(path.exists().then_some(path));
+    }
+
+    let index = CacheIndex::load()?;
+    let Some(entry) = index.find(archive) else {
+        return Ok(None);
+    };
+
+    let path = store_dir.join(&entry.sha256);
+    Ok(path.exists().then_some(path))
+}
+
+/// Moves the file into the cache and records the url it came from.
+pub(crate) fn insert(url: &str, file: &Path, sha256: &str, sha512: &str) -> Result<PathBuf> {
+    let cached_path = get_or_create_store_dir()?.join(sha256);
+    if std::fs::rename(file, &cached_path).is_err() {
+        // The workspace may be on another filesystem than the cache
+        std::fs::copy(file, &cached_path).into_diagnostic()?;
+        std::fs::remove_file(file).into_diagnostic()?;
+    }
+
+    let file_name = url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or(sha256)
+        .to_owned();
+
+    let _lock = INDEX_LOCK.lock().unwrap();
+    let mut index = CacheIndex::load()?;
+    index.archives.insert(
+        url.to_owned(),
+        CacheEntry {
+            sha256: sha256.to_owned(),
+            sha512: sha512.to_owned(),
+            file_name,
+        },
+    );
+    index.save()?;
+
+    Ok(cached_path)
+}
+
+/// Copies an archive into the cache. Without a url it can only be found by
+/// packages that declare its sha256.
+pub(crate) fn import(path: &Path, url: Option<&str>) -> Result<String> {
+    let (sha256, sha512) = file_digests(path)?;
+    let cached_path = get_or_create_store_dir()?.join(&sha256);
+    std::fs::copy(path, &cached_path).into_diagnostic()?;
+
+    if let Some(url) = url {
+        let file_name = path
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or(sha256.clone());
+        let _lock = INDEX_LOCK.lock().unwrap();
+        let mut index = CacheIndex::load()?;
+        index.archives.insert(
+            url.to_owned(),
+            CacheEntry {
+                sha256: sha256.clone(),
+                sha512,
+                file_name,
+            },
+        );
+        index.save()?;
+    }
+
+    Ok(sha256)
+}
+
+/// Returns the sha256 of all archives in the cache.
+pub(crate) fn cached_digests() -> Result<Vec<String>> {
+    let mut digests = read_dir(get_or_create_store_dir()?)
+        .into_diagnostic()?
+        .map(|e| e.map(|e| e.file_name().to_string_lossy().to_string()))
+        .collect::<std::io::Result<Vec<String>>>()
+        .into_diagnostic()?;
+    digests.sort();
+    Ok(digests)
+}
+
+/// Returns the archives whose content no longer matches the sha256 they are
+/// stored under.
+pub(crate) fn verify() -> Result<Vec<String>> {
+    let store_dir = get_or_create_store_dir()?;
+    let mut broken = vec![];
+    for digest in cached_digests()? {
+        let (sha256, _) = file_digests(store_dir.join(&digest))?;
+        if sha256 != digest {
+            broken.push(digest);
+        }
+    }
+    Ok(broken)
+}
+
+/// Removes the archives not in `keep` as well as index entries pointing to
+/// archives that are not there. Without `keep` only archives no url of the
+/// index points to are removed. Returns the removed digests.
+pub(crate) fn prune(keep: Option<&HashSet<String>>, dry_run: bool) -> Result<Vec<String>> {
+    let store_dir = get_or_create_store_dir()?;
+    let _lock = INDEX_LOCK.lock().unwrap();
+    let mut index = CacheIndex::load()?;
+
+    let indexed = index
+        .archives
+        .values()
+        .map(|e| e.sha256.clone())
+        .collect::<HashSet<String>>();
+    let removed = cached_digests()?
+        .into_iter()
+        .filter(|digest| match keep {
+            Some(keep) => !keep.contains(digest),
+            None => !indexed.contains(digest),
+        })
+        .collect::<Vec<String>>();
+
+    if !dry_run {
+        for digest in &removed {
+            std::fs::remove_file(store_dir.join(digest)).into_diagnostic()?;
+        }
+        index
+            .archives
+            .retain(|_, entry| store_dir.join(&entry.sha256).exists());
+        index.save()?;
+    }
+
+    Ok(removed)
+}
+
+/// Computes the sha256 and sha512 of a file.
+pub(crate) fn file_digests<P: AsRef<Path>>(path: P) -> Result<(String, String)> {
+    let mut file = File::open(path.as_ref())
+        .into_diagnostic()
+        .wrap_err(format!(
+            "could not open archive {}",
+            path.as_ref().display()
+        ))?;
+    let mut hasher256 = Sha256::new();
+    let mut hasher512 = Sha512::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let len = file.read(&mut buf).into_diagnostic()?;
+        if len == 0 {
+            break;
+        }
+        hasher256.update(&buf[..len]);
+        hasher512.update(&buf[..len]);
+    }
+    Ok((
+        format!("{:x}", hasher256.finalize()),
+        format!("{:x}", hasher512.finalize()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_only_archives_with_matching_sha512() {
+        let src = "https://example.com/foo-1.0.tar.gz";
+        let mut index = CacheIndex::default();
+        index.archives.insert(
+            src.to_owned(),
+            CacheEntry {
+                sha256: "abc".to_owned(),
+                sha512: "def".to_owned(),
+                file_name: "foo-1.0.tar.gz".to_owned(),
+            },
+        );
+
+        let mut archive = ArchiveSource {
+            src: src.to_owned(),
+            ..Default::default()
+        };
+        assert!(index.find(&archive).is_none());
+
+        archive.sha512 = Some("123".to_owned());
+        assert!(index.find(&archive).is_none());
+
+        archive.sha512 = Some("DEF".to_owned());
+        assert_eq!(index.find(&archive).unwrap().sha256, "abc");
+    }
+}
@@ -64,7 +64,7 @@ pub fn handle_add(wks: &Workspace, section: &Sections, doc: &mut Bundle) -> Resu
             Ok(())
         }
         Sections::Build { section } => {
-            let section = build::handle_section(section);
+            let section = build::handle_section(section)?;
             doc.package_document.add_build_section(section);
             Ok(())
         }
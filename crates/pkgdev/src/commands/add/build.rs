@@ -1,4 +1,5 @@
 use clap::Subcommand;
+use miette::Result;
 
 #[derive(Debug, Subcommand, Clone)]
 pub enum BuildSection {
@@ -6,14 +7,69 @@ pub enum BuildSection {
         #[arg(long, short)]
         arg: Vec<String>,
     },
-    CMake,
-    Meson,
+    Cmake {
+        /// Cache entries in the form NAME[:TYPE]=VALUE
+        #[arg(long, short = 'D')]
+        define: Vec<String>,
+        #[arg(long, short = 'G')]
+        generator: Option<String>,
+        #[arg(long, short)]
+        build_type: Option<String>,
+        #[arg(long, short)]
+        toolchain_file: Option<String>,
+    },
+    Meson {
+        /// Project options in the form NAME=VALUE
+        #[arg(long, short = 'D')]
+        option: Vec<String>,
+        #[arg(long, short)]
+        buildtype: Option<String>,
+        #[arg(long, short)]
+        prefix: Option<String>,
+        #[arg(long, short)]
+        libdir: Option<String>,
+        #[arg(long)]
+        cross_file: Option<String>,
+        #[arg(long)]
+        native_file: Option<String>,
+    },
     Script {
         arg: Vec<String>,
     },
 }
 
-pub(crate) fn handle_section(section: &BuildSection) -> bundle::BuildSection {
+fn parse_cmake_define(define: &str) -> Result<bundle::CMakeDefineNode> {
+    let (key, value) = define.split_once('=').ok_or(miette::miette!(
+        "cmake define {} must be in the form NAME[:TYPE]=VALUE",
+        define
+    ))?;
+
+    let (name, define_type) = if let Some((name, define_type)) = key.split_once(':') {
+        (name.to_owned(), Some(define_type.to_owned()))
+    } else {
+        (key.to_owned(), None)
+    };
+
+    Ok(bundle::CMakeDefineNode {
+        name,
+        value: value.to_owned(),
+        define_type,
+    })
+}
+
+fn parse_meson_option(option: &str) -> Result<bundle::MesonOptionNode> {
+    let (name, value) = option.split_once('=').ok_or(miette::miette!(
+        "meson option {} must be in the form NAME=VALUE",
+        option
+    ))?;
+
+    Ok(bundle::MesonOptionNode {
+        name: name.to_owned(),
+        value: value.to_owned(),
+    })
+}
+
+pub(crate) fn handle_section(section: &BuildSection) -> Result<bundle::BuildSection> {
     match section {
         BuildSection::Configure { arg } => {
             let options = arg
@@ -28,15 +84,53 @@ pub(crate) fn handle_section(section: &BuildSection) -> bundle::BuildSection {
                     }
                 })
                 .collect();
-            bundle::BuildSection::Configure(bundle::ConfigureBuildSection {
+            Ok(bundle::BuildSection::Configure(
+                bundle::ConfigureBuildSection {
+                    options,
+                    flags: vec![],
+                    compiler: None,
+                    linker: None,
+                },
+            ))
+        }
+        BuildSection::Cmake {
+            define,
+            generator,
+            build_type,
+            toolchain_file,
+        } => {
+            let defines = define
+                .iter()
+                .map(|d| parse_cmake_define(d))
+                .collect::<Result<Vec<bundle::CMakeDefineNode>>>()?;
+            Ok(bundle::BuildSection::Cmake(bundle::CMakeBuildSection {
+                defines,
+                generator: generator.clone(),
+                build_type: build_type.clone(),
+                toolchain_file: toolchain_file.clone(),
+            }))
+        }
+        BuildSection::Meson {
+            option,
+            buildtype,
+            prefix,
+            libdir,
+            cross_file,
+            native_file,
+        } => {
+            let options = option
+                .iter()
+                .map(|o| parse_meson_option(o))
+                .collect::<Result<Vec<bundle::MesonOptionNode>>>()?;
+            Ok(bundle::BuildSection::Meson(bundle::MesonBuildSection {
                 options,
-                flags: vec![],
-                compiler: None,
-                linker: None,
-            })
+                buildtype: buildtype.clone(),
+                prefix: prefix.clone(),
+                libdir: libdir.clone(),
+                cross_file: cross_file.clone(),
+                native_file: native_file.clone(),
+            }))
         }
-        BuildSection::CMake => todo!(),
-        BuildSection::Meson => todo!(),
         BuildSection::Script { .. } => todo!(),
     }
 }
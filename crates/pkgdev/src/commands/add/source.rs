@@ -1,4 +1,7 @@
-use crate::workspace::{DownloadFile, HasherKind, Workspace};
+use crate::{
+    patch,
+    workspace::{DownloadFile, HasherKind, Workspace},
+};
 use bundle::{ArchiveSource, Bundle, SourceNode};
 use clap::Subcommand;
 use curl::easy::Easy2;
@@ -20,6 +23,9 @@ pub enum Sources {
         branch: Option<String>,
         #[arg(short, long)]
         tag: Option<String>,
+        /// Pin the source to this commit
+        #[arg(short, long)]
+        commit: Option<String>,
     },
     File {
         local_path: PathBuf,
@@ -40,7 +46,9 @@ impl std::fmt::Display for Sources {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Archive { url, .. } => write!(f, "archive {}", url),
-            Self::Git { url, branch, tag } => write!(
+            Self::Git {
+                url, branch, tag, ..
+            } => write!(
                 f,
                 "git {} branch={} tag={}",
                 url,
@@ -105,10 +113,16 @@ pub fn handle_add_source(wks: &Workspace, src: &Sources, pkg: &mut Bundle) -> Re
 
             OneOrMany::One(SourceNode::Archive(archive_node))
         }
-        Sources::Git { url, branch, tag } => OneOrMany::One(SourceNode::Git(bundle::GitSource {
+        Sources::Git {
+            url,
+            branch,
+            tag,
+            commit,
+        } => OneOrMany::One(SourceNode::Git(bundle::GitSource {
             repository: url.to_string(),
             branch: branch.as_deref().map(|s| String::from(s)),
             tag: tag.as_deref().map(|s| String::from(s)),
+            commit: commit.clone(),
             archive: None,
             must_stay_as_repo: None,
             directory: None,
@@ -145,8 +159,22 @@ pub fn handle_add_source(wks: &Workspace, src: &Sources, pkg: &mut Bundle) -> Re
                     Path::new(&file_name).to_path_buf(),
                     drop_directories.clone(),
                 )?))
+            } else if local_path.join(patch::SERIES_FILE).is_file() {
+                // The series file decides the order so the directory is one source
+                let dir_name = local_path
+                    .canonicalize()
+                    .into_diagnostic()?
+                    .file_name()
+                    .ok_or(miette::miette!("no filename for {}", local_path.display()))?
+                    .to_owned();
+
+                OneOrMany::One(SourceNode::Patch(bundle::PatchSource::new(
+                    dir_name,
+                    drop_directories.clone(),
+                )?))
             } else {
                 let mut patch_vec = vec![];
+                let mut file_names = vec![];
                 let read_dir_res = fs::read_dir(local_path).into_diagnostic()?;
                 for entry in read_dir_res {
                     let entry = entry.into_diagnostic()?;
@@ -156,12 +184,17 @@ pub fn handle_add_source(wks: &Workspace, src: &Sources, pkg: &mut Bundle) -> Re
                             .file_name()
                             .ok_or(miette::miette!("no filename for {}", local_path.display()))?
                             .to_owned();
-                        patch_vec.push(SourceNode::Patch(bundle::PatchSource::new(
-                            file_name,
-                            drop_directories.clone(),
-                        )?));
+                        file_names.push(file_name);
                     }
                 }
+                // Without a series file patches apply in the order of their names
+                file_names.sort();
+                for file_name in file_names {
+                    patch_vec.push(SourceNode::Patch(bundle::PatchSource::new(
+                        file_name,
+                        drop_directories.clone(),
+                    )?));
+                }
                 OneOrMany::Many(patch_vec)
             }
         }
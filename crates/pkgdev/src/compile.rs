@@ -3,8 +3,7 @@ use std::{collections::HashMap, process::Stdio};
 use bundle::Bundle;
 use miette::{IntoDiagnostic, Result};
 
-use crate::{config::Settings, derive_source_name, workspace::Workspace};
-use std::process::Command;
+use crate::{derive_source_name, logs::run_logged, sandbox::Sandbox, workspace::Workspace};
 
 enum BuildTool {
     Make,
@@ -20,7 +19,7 @@ impl ToString for BuildTool {
     }
 }
 
-pub fn run_compile(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result<()> {
+pub fn run_compile(wks: &Workspace, pkg: &Bundle, sandbox: &Sandbox) -> Result<()> {
     let build_dir = wks.get_or_create_build_dir()?;
     let unpack_name = derive_source_name(
         pkg.package_document.name.clone(),
@@ -28,14 +27,14 @@ pub fn run_compile(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result
     );
 
     let unpack_path = build_dir.join(&unpack_name);
-    if pkg.package_document.seperate_build_dir {
+    if pkg.package_document.uses_seperate_build_dir() {
         let out_dir = build_dir.join("out");
         std::env::set_current_dir(&out_dir).into_diagnostic()?;
     } else {
         std::env::set_current_dir(&unpack_path).into_diagnostic()?;
     }
 
-    let build_tool_check_dir = if pkg.package_document.seperate_build_dir {
+    let build_tool_check_dir = if pkg.package_document.uses_seperate_build_dir() {
         build_dir.join("out")
     } else {
         unpack_path.clone()
@@ -49,10 +48,8 @@ pub fn run_compile(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result
         return Err(miette::miette!("no supported build tool could be detected make sure a Makefile or build.ninja file exists in the build directory"));
     };
 
-    let mut env_flags: HashMap<String, String> = HashMap::new();
-    env_flags.insert("PATH".into(), settings.get_search_path().join(":"));
-    let mut build_cmd = Command::new(build_tool.to_string());
-    build_cmd.env_clear();
+    let env_flags: HashMap<String, String> = HashMap::new();
+    let mut build_cmd = sandbox.command(build_tool.to_string());
     build_cmd.envs(&env_flags);
 
     build_cmd.stdin(Stdio::null());
@@ -69,7 +66,7 @@ pub fn run_compile(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result
             .join(",")
     );
 
-    let status = build_cmd.status().into_diagnostic()?;
+    let status = run_logged(wks, &mut build_cmd)?;
     if status.success() {
         println!("Successfully built {}", pkg.get_name());
     } else {
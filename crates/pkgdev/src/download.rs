@@ -1,124 +1,687 @@
-use std::{fs, path::Path, process::Command};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
 use crate::{
+    cache,
     config::Settings,
+    logs::{log_errors, run_logged},
     path::add_extension,
     workspace::{DownloadFile, HasherKind, Workspace},
 };
-use bundle::SourceSection;
+use bundle::{ArchiveSource, Bundle, GitSource, PackageLock};
 use curl::easy::Easy2;
-use miette::{IntoDiagnostic, Result, WrapErr};
+use gate::Gate;
+use miette::{Diagnostic, IntoDiagnostic, Result, WrapErr};
+use thiserror::Error;
+
+#[derive(Debug, Error, Diagnostic)]
+pub(crate) enum DownloadError {
+    #[error("archive {0} has no checksum or signature and can not be verified")]
+    #[diagnostic(
+        code(pkgdev::download::unverified),
+        help("add sha256=\"{1}\" to the archive node after checking it against the upstream release or build with --allow-unverified")
+    )]
+    Unverified(String, String),
+    #[error("checksum missmatch for archive {url}, expected {kind}: {expected}, actual {actual}")]
+    #[diagnostic(code(pkgdev::download::checksum_mismatch))]
+    ChecksumMismatch {
+        url: String,
+        kind: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("archive {url} is declared with different {kind} checksums {first} and {second}")]
+    #[diagnostic(
+        code(pkgdev::download::conflicting_checksums),
+        help("the packages using this archive must declare the same checksums for it")
+    )]
+    ConflictingChecksums {
+        url: String,
+        kind: String,
+        first: String,
+        second: String,
+    },
+}
+
+/// Name of the keyring next to the package.kdl used to verify archive signatures.
+const BUNDLE_KEYRING: &str = "keyring.gpg";
+
+/// How many archives are downloaded at the same time.
+const PARALLEL_DOWNLOADS: usize = 4;
+/// How often a url is tried before moving on to the next mirror.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
 
 pub(crate) fn download_and_verify(
     wks: &Workspace,
-    src_sections: &[SourceSection],
+    pkg: &Bundle,
+    gate_data: Option<&Gate>,
     archive_clean: bool,
+    allow_unverified: bool,
 ) -> Result<()> {
-    for section in src_sections {
-        for src in &section.sources {
-            match src {
-                bundle::SourceNode::Archive(archive) => {
-                    let url: url::Url = archive
-                        .src
-                        .parse()
-                        .into_diagnostic()
-                        .wrap_err("could not parse archive src argument as url")?;
-
-                    let local_file = wks.get_file_path(url.clone())?;
-
-                    let file_name = local_file.file_name().ok_or(miette::miette!("Archive must have a file_name. A Folder with / at the end can not be an archive"))?;
-                    let archive_path =
-                        Settings::get_or_create_archives_dir()?.join(Path::new(file_name));
-
-                    if archive_clean {
-                        std::fs::remove_file(&archive_path).ok();
-                    }
+    let archives = archives_of(pkg)
+        .into_iter()
+        .map(|archive| (pkg, archive))
+        .collect::<Vec<_>>();
+    fetch_archives(wks, &archives, gate_data, archive_clean, allow_unverified)?;
 
-                    if !archive_path.exists() {
-                        println!("Downloading {}", url.to_string());
-                        let mut easy =
-                            Easy2::new(wks.open_local_file(url.clone(), HasherKind::Sha512)?);
-                        easy.get(true).into_diagnostic()?;
-                        easy.url(&url.to_string()).into_diagnostic()?;
-                        easy.progress(true).into_diagnostic()?;
-                        easy.perform().into_diagnostic()?;
-                        let local_file = { easy.get_mut() as &mut DownloadFile };
-                        let downloaded_file_hash = local_file.get_hash();
-                        if let Some(sha512) = &archive.sha512 {
-                            if downloaded_file_hash == *sha512 {
-                                println!("Success, checksums match");
-                                let local_path = local_file.get_path();
-                                fs::copy(&local_path, archive_path).into_diagnostic()?;
-                                fs::remove_file(&local_path).into_diagnostic()?;
-                            } else {
-                                return Err(miette::miette!(format!(
-                                    "checksum missmatch for archive {}, expected: {}, actual {}",
-                                    url.to_string(),
-                                    sha512,
-                                    downloaded_file_hash
-                                )));
-                            }
-                        }
-                    } else {
-                        println!("File {} exists skipping", local_file.display());
-                    }
+    let mut lock = PackageLock::open(pkg.get_lock_path())?;
+    let mut lock_changed = false;
+    for git in git_sources_of(pkg) {
+        let commit = match locked_commit(&lock, git) {
+            Some(commit) => commit,
+            None => {
+                let commit = resolve_git_commit(wks, git)?;
+                println!("Resolved {} to commit {}", git.repository, commit);
+                lock.set_commit(git, &commit);
+                lock_changed = true;
+                commit
+            }
+        };
+
+        let git_repo_path = &wks
+            .get_or_create_download_dir()?
+            .join(git.get_repo_prefix());
+        let archive_path = git_archive_path(git, &commit)?;
+
+        if archive_clean {
+            std::fs::remove_file(&archive_path).ok();
+        }
+
+        if !archive_path.exists() {
+            if !git_repo_path.exists() {
+                if git.archive.is_some() {
+                    git_archive_get(wks, &git, &commit, &archive_path)?;
+                } else {
+                    git_clone_get(wks, &git, &commit, &archive_path)?;
                 }
-                bundle::SourceNode::Git(git) => {
-                    let git_prefix = &git.get_repo_prefix();
-                    let git_repo_path = &wks.get_or_create_download_dir()?.join(&git_prefix);
-                    let archive_path = add_extension(
-                        Settings::get_or_create_archives_dir()?.join(&git_prefix),
-                        "tar.gz",
-                    );
-
-                    if archive_clean {
-                        std::fs::remove_file(&archive_path).ok();
-                    }
+            } else {
+                git_checkout(wks, git, &commit)?;
+                if git.must_stay_as_repo.is_some() {
+                    println!("Creating Archive of full repo");
+                    make_git_archive_with_tar(wks, git, &archive_path)?;
+                } else {
+                    println!("Creating git-archive based archive from git");
+                    make_git_archive(wks, git, &commit, &archive_path)?;
+                }
+            }
+        }
+    }
+
+    if lock_changed {
+        lock.save(pkg.get_lock_path())?;
+        println!(
+            "Recorded the resolved commits in {}",
+            pkg.get_lock_path().display()
+        );
+    }
+
+    Ok(())
+}
+
+/// All archive sources of a package.
+pub(crate) fn archives_of(pkg: &Bundle) -> Vec<&ArchiveSource> {
+    pkg.package_document
+        .sources
+        .iter()
+        .flat_map(|section| section.sources.iter())
+        .filter_map(|src| match src {
+            bundle::SourceNode::Archive(archive) => Some(archive),
+            _ => None,
+        })
+        .collect()
+}
+
+/// All git sources of a package.
+pub(crate) fn git_sources_of(pkg: &Bundle) -> Vec<&GitSource> {
+    pkg.package_document
+        .sources
+        .iter()
+        .flat_map(|section| section.sources.iter())
+        .filter_map(|src| match src {
+            bundle::SourceNode::Git(git) => Some(git),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The commit the git source is pinned to either in the package.kdl or in
+/// the package.lock written when it was fetched.
+pub(crate) fn locked_commit(lock: &PackageLock, git: &GitSource) -> Option<String> {
+    git.commit
+        .clone()
+        .or(lock.get_commit(git).map(|c| c.to_owned()))
+}
+
+/// Archives made from git sources are named after the commit so a moving
+/// branch never reuses an archive of an older state.
+pub(crate) fn git_archive_path(git: &GitSource, commit: &str) -> Result<PathBuf> {
+    Ok(add_extension(
+        Settings::get_or_create_archives_dir()?.join(git.get_commit_prefix(commit)),
+        "tar.gz",
+    ))
+}
 
-                    if !archive_path.exists() {
-                        if !git_repo_path.exists() {
-                            if git.archive.is_some() {
-                                git_archive_get(wks, &git)?;
-                            } else {
-                                git_clone_get(wks, &git)?;
-                            }
-                        } else {
-                            if git.must_stay_as_repo.is_some() {
-                                println!("Creating Archive of full repo");
-                                make_git_archive_with_tar(wks, git)?;
-                            } else {
-                                println!("Creating git-archive based archive from git");
-                                make_git_archive(wks, git)?;
-                            }
-                        }
+/// Asks the remote which commit the branch or tag of the source points to.
+pub(crate) fn resolve_git_commit(wks: &Workspace, git: &GitSource) -> Result<String> {
+    let wanted = if let Some(tag) = &git.tag {
+        vec![
+            format!("refs/tags/{}", tag),
+            format!("refs/tags/{}^{{}}", tag),
+        ]
+    } else if let Some(branch) = &git.branch {
+        vec![format!("refs/heads/{}", branch)]
+    } else {
+        vec![String::from("HEAD")]
+    };
+
+    let mut git_cmd = Command::new("git");
+    git_cmd.arg("ls-remote");
+    git_cmd.arg(&git.repository);
+    git_cmd.args(&wanted);
+    log_errors(wks, &mut git_cmd)?;
+    let output = git_cmd.output().into_diagnostic()?;
+    if !output.status.success() {
+        return Err(miette::miette!(
+            "could not list the refs of {}",
+            git.repository
+        ));
+    }
+
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let refs = listing
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect::<Vec<(&str, &str)>>();
+    // Annotated tags point to the tag object, the peeled ref to the commit
+    refs.iter()
+        .find(|(_, name)| name.ends_with("^{}"))
+        .or(refs.first())
+        .map(|(commit, _)| commit.to_string())
+        .ok_or(miette::miette!("{} has no {}", git.repository, wanted[0]))
+}
+
+/// Merges the archives with the same url into one carrying the checksums of
+/// all of their declarations. Declarations with different values for the
+/// same checksum can not both be satisfied and are an error.
+fn merge_archives<'a>(
+    archives: &[(&'a Bundle, &ArchiveSource)],
+) -> Result<Vec<(&'a Bundle, ArchiveSource)>> {
+    let mut merged: Vec<(&'a Bundle, ArchiveSource)> = vec![];
+    for (pkg, archive) in archives {
+        let Some((_, existing)) = merged.iter_mut().find(|(_, a)| a.src == archive.src) else {
+            merged.push((*pkg, (*archive).clone()));
+            continue;
+        };
+
+        for (kind, merged_digest, digest) in [
+            ("sha256", &mut existing.sha256, &archive.sha256),
+            ("sha512", &mut existing.sha512, &archive.sha512),
+        ] {
+            match (merged_digest.as_ref(), digest) {
+                (Some(first), Some(second)) if !first.eq_ignore_ascii_case(second) => {
+                    return Err(DownloadError::ConflictingChecksums {
+                        url: archive.src.clone(),
+                        kind: kind.to_owned(),
+                        first: first.clone(),
+                        second: second.clone(),
                     }
+                    .into());
                 }
+                (None, Some(digest)) => *merged_digest = Some(digest.clone()),
                 _ => {}
             }
         }
     }
+    Ok(merged)
+}
+
+/// Downloads and verifies the given archives with up to
+/// [`PARALLEL_DOWNLOADS`] at the same time. Archives with the same url are
+/// only fetched once verified against the checksums of all their
+/// declarations. All archives are tried even if some of them fail, the
+/// errors are reported together.
+pub(crate) fn fetch_archives(
+    wks: &Workspace,
+    archives: &[(&Bundle, &ArchiveSource)],
+    gate_data: Option<&Gate>,
+    archive_clean: bool,
+    allow_unverified: bool,
+) -> Result<()> {
+    let jobs = merge_archives(archives)?;
+
+    let next = AtomicUsize::new(0);
+    let errors = Mutex::new(vec![]);
+    thread::scope(|scope| {
+        for _ in 0..PARALLEL_DOWNLOADS.min(jobs.len()) {
+            scope.spawn(|| loop {
+                let Some((pkg, archive)) = jobs.get(next.fetch_add(1, Ordering::SeqCst)) else {
+                    break;
+                };
+                if let Err(err) = download_archive(
+                    wks,
+                    pkg,
+                    archive,
+                    gate_data,
+                    archive_clean,
+                    allow_unverified,
+                ) {
+                    errors
+                        .lock()
+                        .unwrap()
+                        .push(format!("{}: {}", archive.src, err));
+                }
+            });
+        }
+    });
+
+    let errors = errors.into_inner().unwrap();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(miette::miette!(
+            "{} of {} archives could not be downloaded\n{}",
+            errors.len(),
+            jobs.len(),
+            errors.join("\n")
+        ))
+    }
+}
+
+fn download_archive(
+    wks: &Workspace,
+    pkg: &Bundle,
+    archive: &ArchiveSource,
+    gate_data: Option<&Gate>,
+    archive_clean: bool,
+    allow_unverified: bool,
+) -> Result<()> {
+    if !archive_clean {
+        if let Some(cached_path) = cache::lookup(archive)? {
+            println!(
+                "Archive {} is cached as {} skipping",
+                archive.src,
+                cached_path.display()
+            );
+            return Ok(());
+        }
+    }
+
+    let src_url: url::Url = archive
+        .src
+        .parse()
+        .into_diagnostic()
+        .wrap_err("could not parse archive src argument as url")?;
+    let file_name = wks
+        .get_file_path(src_url)?
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .ok_or(miette::miette!(
+            "Archive must have a file_name. A Folder with / at the end can not be an archive"
+        ))?;
+    let urls = archive_urls(archive, &file_name, gate_data);
+    let mut failures: Vec<String> = vec![];
+    let mut fetched = None;
+    for url in &urls {
+        match fetch_archive(wks, archive, url) {
+            Ok(archive_file) => {
+                fetched = Some(archive_file);
+                break;
+            }
+            Err(err) => {
+                println!("Could not use {}: {}", url, err);
+                failures.push(format!("{}: {}", url, err));
+            }
+        }
+    }
+
+    let Some(fetched) = fetched else {
+        return Err(miette::miette!(
+            "none of the urls of archive {} served a valid copy\n{}",
+            archive.src,
+            failures.join("\n")
+        ));
+    };
+
+    if fetched.url != archive.src {
+        println!(
+            "Archive {} was served by mirror {}",
+            archive.src, fetched.url
+        );
+    }
+
+    let mut verified = fetched.checksum_verified;
+
+    if let Some(signature_url) = archive.get_signature_url_for(&fetched.url) {
+        verify_signature(
+            wks,
+            &fetched.path,
+            &signature_url,
+            find_keyring(pkg, gate_data).as_deref(),
+            &pkg.package_document.trusted_keys,
+        )?;
+        verified = true;
+    }
+
+    if !verified {
+        if allow_unverified {
+            println!(
+                "Warning: archive {} has no checksum or signature, using it unverified",
+                archive.src
+            );
+        } else {
+            fs::remove_file(&fetched.path).into_diagnostic()?;
+            return Err(DownloadError::Unverified(archive.src.clone(), fetched.sha256).into());
+        }
+    }
+
+    cache::insert(
+        &archive.src,
+        &fetched.path,
+        &fetched.sha256,
+        &fetched.sha512,
+    )?;
     Ok(())
 }
 
-fn git_clone_get(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
+/// An archive downloaded into the workspace whose declared checksums matched.
+struct FetchedArchive {
+    url: String,
+    path: PathBuf,
+    sha256: String,
+    sha512: String,
+    checksum_verified: bool,
+}
+
+/// The urls to try for an archive in order: its src, its own mirrors and
+/// then the mirrors of the gate.
+fn archive_urls(archive: &ArchiveSource, file_name: &str, gate_data: Option<&Gate>) -> Vec<String> {
+    let mut urls = vec![archive.src.clone()];
+    urls.extend(archive.mirrors.iter().cloned());
+    if let Some(gate_data) = gate_data {
+        urls.extend(gate_data.mirrors.iter().filter_map(|m| {
+            m.archive_url(
+                file_name,
+                archive.sha256.as_deref(),
+                archive.sha512.as_deref(),
+            )
+        }));
+    }
+    urls
+}
+
+/// Downloads the archive from one url and checks it against the checksums
+/// the archive declares. Failed transfers are retried with a growing delay
+/// and continue where the previous attempt stopped if the server supports
+/// range requests.
+fn fetch_archive(
+    wks: &Workspace,
+    archive: &ArchiveSource,
+    source_url: &str,
+) -> Result<FetchedArchive> {
+    let url: url::Url = source_url
+        .parse()
+        .into_diagnostic()
+        .wrap_err("could not parse archive url")?;
+    // Mirrors use the file name of the src url so the archive lands in the same place
+    let src_url: url::Url = archive.src.parse().into_diagnostic()?;
+
+    let mut attempt = 1;
+    let local_file = loop {
+        let local_file = wks.open_local_file(src_url.clone(), HasherKind::Sha512)?;
+        let resumed_from = local_file.resumed_from();
+        if resumed_from > 0 {
+            println!("Resuming download of {} at byte {}", url, resumed_from);
+        } else {
+            println!("Downloading {}", url);
+        }
+
+        let mut easy = Easy2::new(local_file);
+        let result = perform_download(&mut easy, &url, resumed_from);
+
+        match result {
+            Ok(()) => break easy,
+            Err(err) if attempt < DOWNLOAD_ATTEMPTS => {
+                let delay = Duration::from_secs(2u64.pow(attempt - 1));
+                println!(
+                    "Download of {} failed ({}), retrying in {}s",
+                    url,
+                    err,
+                    delay.as_secs()
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(err) => {
+                return Err(err.wrap_err(format!(
+                    "could not download {} after {} attempts",
+                    url, attempt
+                )))
+            }
+        }
+    };
+
+    let local_file = local_file.get_ref();
+    let checksum_verified = match check_digests(&url, archive, local_file) {
+        Ok(checksum_verified) => checksum_verified,
+        Err(err) => {
+            // Neither resuming nor the next mirror may build on a broken file
+            fs::remove_file(local_file.get_path()).ok();
+            return Err(err);
+        }
+    };
+
+    if checksum_verified {
+        println!("Success, checksums match");
+    }
+
+    Ok(FetchedArchive {
+        url: source_url.to_owned(),
+        path: local_file.get_path(),
+        sha256: local_file.get_sha256(),
+        sha512: local_file.get_sha512(),
+        checksum_verified,
+    })
+}
+
+fn perform_download(
+    easy: &mut Easy2<DownloadFile>,
+    url: &url::Url,
+    resumed_from: u64,
+) -> Result<()> {
+    easy.get(true).into_diagnostic()?;
+    easy.url(url.as_str()).into_diagnostic()?;
+    easy.follow_location(true).into_diagnostic()?;
+    easy.fail_on_error(true).into_diagnostic()?;
+    easy.progress(true).into_diagnostic()?;
+    if resumed_from > 0 {
+        easy.resume_from(resumed_from).into_diagnostic()?;
+    }
+
+    match easy.perform() {
+        Ok(()) => Ok(()),
+        // The server has nothing after the end of a file that is already complete
+        Err(_) if resumed_from > 0 && easy.response_code().ok() == Some(416) => Ok(()),
+        Err(err) => match easy.get_ref().get_error() {
+            Some(handler_err) => Err(miette::miette!("{}: {}", err, handler_err)),
+            None => Err(err).into_diagnostic(),
+        },
+    }
+}
+
+/// Checks the file against all checksums the archive declares and returns
+/// whether there was any.
+fn check_digests(url: &url::Url, archive: &ArchiveSource, file: &DownloadFile) -> Result<bool> {
+    let mut checksum_verified = false;
+
+    if let Some(sha512) = &archive.sha512 {
+        check_digest(url, "sha512", sha512, &file.get_sha512())?;
+        checksum_verified = true;
+    }
+
+    if let Some(sha256) = &archive.sha256 {
+        check_digest(url, "sha256", sha256, &file.get_sha256())?;
+        checksum_verified = true;
+    }
+
+    Ok(checksum_verified)
+}
+
+fn check_digest(url: &url::Url, kind: &str, expected: &str, actual: &str) -> Result<()> {
+    if expected.eq_ignore_ascii_case(actual) {
+        Ok(())
+    } else {
+        Err(DownloadError::ChecksumMismatch {
+            url: url.to_string(),
+            kind: kind.to_owned(),
+            expected: expected.to_owned(),
+            actual: actual.to_owned(),
+        }
+        .into())
+    }
+}
+
+/// Archive signatures are checked against the keyring.gpg of the package or
+/// if it has none against the keyring of the gate.
+pub(crate) fn find_keyring(pkg: &Bundle, gate_data: Option<&Gate>) -> Option<PathBuf> {
+    let bundle_keyring = pkg.get_path().join(BUNDLE_KEYRING);
+    if bundle_keyring.exists() {
+        Some(bundle_keyring)
+    } else {
+        gate_data.and_then(|g| g.get_keyring_path())
+    }
+}
+
+/// Downloads the detached OpenPGP signature of an archive and checks it with
+/// gpgv. If the package pins trusted keys the signature must be made by one
+/// of them, otherwise any key of the keyring is accepted.
+fn verify_signature(
+    wks: &Workspace,
+    archive_path: &Path,
+    signature_url: &str,
+    keyring: Option<&Path>,
+    trusted_keys: &[String],
+) -> Result<()> {
+    let keyring = keyring.ok_or(miette::miette!(
+        "archive {} is signed but neither the package has a {} nor the gate a keyring to verify it with",
+        archive_path.display(),
+        BUNDLE_KEYRING
+    ))?;
+
+    if !keyring.exists() {
+        return Err(miette::miette!(
+            "keyring {} to verify archive {} does not exist",
+            keyring.display(),
+            archive_path.display()
+        ));
+    }
+
+    let url: url::Url = signature_url
+        .parse()
+        .into_diagnostic()
+        .wrap_err("could not parse signature url")?;
+
+    println!("Downloading signature {}", url.to_string());
+    let mut easy = Easy2::new(wks.open_or_truncate_local_file(url.clone(), HasherKind::Sha512)?);
+    easy.get(true).into_diagnostic()?;
+    easy.url(&url.to_string()).into_diagnostic()?;
+    easy.perform()
+        .into_diagnostic()
+        .wrap_err(format!("could not download signature {}", url))?;
+    let response_code = easy.response_code().into_diagnostic()?;
+    if url.scheme().starts_with("http") && response_code != 200 {
+        return Err(miette::miette!(
+            "could not download signature {}, the server answered with {}",
+            url,
+            response_code
+        ));
+    }
+    let signature_path = easy.get_ref().get_path();
+
+    let mut gpgv_cmd = Command::new("gpgv");
+    gpgv_cmd
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--keyring")
+        .arg(keyring)
+        .arg(&signature_path)
+        .arg(archive_path);
+    log_errors(wks, &mut gpgv_cmd)?;
+    let output = gpgv_cmd.output().into_diagnostic()?;
+    if !output.status.success() {
+        return Err(miette::miette!(
+            "bad signature for archive {}, it could not be verified with the keyring {}",
+            archive_path.display(),
+            keyring.display()
+        ));
+    }
+
+    let signers = signing_fingerprints(&String::from_utf8_lossy(&output.stdout));
+    if !trusted_keys.is_empty()
+        && !trusted_keys
+            .iter()
+            .map(|k| k.replace(' ', "").to_uppercase())
+            .any(|k| signers.contains(&k))
+    {
+        return Err(miette::miette!(
+            "archive {} was signed by {} which is not one of the trusted keys of the package",
+            archive_path.display(),
+            signers.join(", ")
+        ));
+    }
+
+    println!("Success, signature is valid");
+    Ok(())
+}
+
+/// Returns the fingerprints of the signing keys and their primary keys from
+/// the VALIDSIG lines of the gpgv status output.
+fn signing_fingerprints(status: &str) -> Vec<String> {
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .flat_map(|rest| {
+            let fields = rest.split_whitespace().collect::<Vec<&str>>();
+            [fields.first().copied(), fields.get(9).copied()]
+        })
+        .flatten()
+        .map(|fingerprint| fingerprint.to_uppercase())
+        .collect()
+}
+
+fn git_clone_get(
+    wks: &Workspace,
+    git: &GitSource,
+    commit: &str,
+    archive_path: &Path,
+) -> Result<()> {
     let mut git_cmd = Command::new("git");
 
     let repo_prefix = git.get_repo_prefix();
 
     git_cmd.current_dir(&wks.get_or_create_download_dir()?);
     git_cmd.arg("clone");
-    git_cmd.arg("--single-branch");
     if let Some(tag) = &git.tag {
+        git_cmd.arg("--single-branch");
         git_cmd.arg("--branch");
         git_cmd.arg(tag);
     } else if let Some(branch) = &git.branch {
+        git_cmd.arg("--single-branch");
         git_cmd.arg("--branch");
         git_cmd.arg(branch);
     }
     git_cmd.arg(&git.repository);
     git_cmd.arg(&repo_prefix);
 
-    let status = git_cmd.status().into_diagnostic()?;
+    let status = run_logged(wks, &mut git_cmd)?;
     if status.success() {
         println!("Git successfully cloned from remote");
     } else {
@@ -128,35 +691,53 @@ fn git_clone_get(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
         )));
     }
 
+    git_checkout(wks, git, commit)?;
+
     if git.must_stay_as_repo.is_some() {
         println!("Creating Archive of full repo");
-        make_git_archive_with_tar(wks, git)
+        make_git_archive_with_tar(wks, git, archive_path)
     } else {
         println!("Creating git-archive based archive from git");
-        make_git_archive(wks, git)
+        make_git_archive(wks, git, commit, archive_path)
     }
 }
 
-fn make_git_archive_with_tar(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
+fn git_checkout(wks: &Workspace, git: &GitSource, commit: &str) -> Result<()> {
+    let mut git_cmd = Command::new("git");
+    git_cmd.current_dir(
+        &wks.get_or_create_download_dir()?
+            .join(git.get_repo_prefix()),
+    );
+    git_cmd.arg("checkout");
+    git_cmd.arg("--detach");
+    git_cmd.arg(commit);
+
+    let status = run_logged(wks, &mut git_cmd)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(miette::miette!(
+            "Could not check out commit {} of {}",
+            commit,
+            git.repository
+        ))
+    }
+}
+
+fn make_git_archive_with_tar(wks: &Workspace, git: &GitSource, archive_path: &Path) -> Result<()> {
     let repo_prefix = git.get_repo_prefix();
 
     let mut archive_cmd = Command::new("gtar");
     archive_cmd.current_dir(&wks.get_or_create_download_dir()?);
     archive_cmd.arg("-czf");
-    let archive_name_arg = add_extension(
-        Settings::get_or_create_archives_dir()?.join(&repo_prefix),
-        "tar.gz",
-    )
-    .to_string_lossy()
-    .to_string();
-    archive_cmd.arg(&archive_name_arg);
+    archive_cmd.arg(archive_path);
     archive_cmd.arg(&repo_prefix);
 
-    let status = archive_cmd.status().into_diagnostic()?;
+    let status = run_logged(wks, &mut archive_cmd)?;
     if status.success() {
         println!(
-            "Git Archive {}.tar.gz successfully created by way of tar",
-            &repo_prefix
+            "Git Archive {} successfully created by way of tar",
+            archive_path.display()
         );
         Ok(())
     } else {
@@ -167,7 +748,12 @@ fn make_git_archive_with_tar(wks: &Workspace, git: &bundle::GitSource) -> Result
     }
 }
 
-fn make_git_archive(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
+fn make_git_archive(
+    wks: &Workspace,
+    git: &GitSource,
+    commit: &str,
+    archive_path: &Path,
+) -> Result<()> {
     let repo_prefix = git.get_repo_prefix();
 
     let mut archive_cmd = Command::new("git");
@@ -175,22 +761,17 @@ fn make_git_archive(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
     archive_cmd.arg("archive");
     archive_cmd.arg("--format=tar.gz");
     let prefix_arg = format!("--prefix={}/", &repo_prefix);
-    let output_arg = format!(
-        "--output={}",
-        add_extension(
-            Settings::get_or_create_archives_dir()?.join(&repo_prefix),
-            "tar.gz"
-        )
-        .to_string_lossy()
-        .to_string()
-    );
+    let output_arg = format!("--output={}", archive_path.display());
     archive_cmd.arg(&prefix_arg);
     archive_cmd.arg(&output_arg);
-    archive_cmd.arg("HEAD");
+    archive_cmd.arg(commit);
 
-    let status = archive_cmd.status().into_diagnostic()?;
+    let status = run_logged(wks, &mut archive_cmd)?;
     if status.success() {
-        println!("Git Archive {}.tar.gz successfully created", &repo_prefix);
+        println!(
+            "Git Archive {} successfully created",
+            archive_path.display()
+        );
         Ok(())
     } else {
         Err(miette::miette!(format!(
@@ -200,20 +781,17 @@ fn make_git_archive(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
     }
 }
 
-fn git_archive_get(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
+fn git_archive_get(
+    wks: &Workspace,
+    git: &GitSource,
+    commit: &str,
+    archive_path: &Path,
+) -> Result<()> {
     let mut git_cmd = Command::new("git");
     let repo_prefix = git.get_repo_prefix();
 
     let prefix_arg = format!("--prefix={}", &repo_prefix);
-    let output_arg = format!(
-        "--output={}",
-        add_extension(
-            Settings::get_or_create_archives_dir()?.join(&repo_prefix),
-            "tar.gz"
-        )
-        .to_string_lossy()
-        .to_string()
-    );
+    let output_arg = format!("--output={}", archive_path.display());
     let remote_arg = format!("--remote={}", &git.repository);
 
     git_cmd.current_dir(&wks.get_or_create_download_dir()?);
@@ -223,15 +801,9 @@ fn git_archive_get(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
     git_cmd.arg(output_arg);
     git_cmd.arg(remote_arg);
     git_cmd.arg("-v");
-    if let Some(tag) = &git.tag {
-        git_cmd.arg(tag);
-    } else if let Some(branch) = &git.branch {
-        git_cmd.arg(branch);
-    } else {
-        git_cmd.arg("HEAD");
-    }
+    git_cmd.arg(commit);
 
-    let status = git_cmd.status().into_diagnostic()?;
+    let status = run_logged(wks, &mut git_cmd)?;
     if status.success() {
         println!("Archive sucesscully copied from git remote");
         Ok(())
@@ -242,3 +814,58 @@ fn git_archive_get(wks: &Workspace, git: &bundle::GitSource) -> Result<()> {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    fn bundle(dir: &Path, name: &str, archive: &str) -> Bundle {
+        let package_dir = dir.join(name);
+        std::fs::create_dir_all(&package_dir).unwrap();
+        std::fs::write(
+            package_dir.join("package.kdl"),
+            format!(
+                "name \"library/{}\"\nproject-name \"{}\"\nsource {{\n    {}\n}}\n",
+                name, name, archive
+            ),
+        )
+        .unwrap();
+        Bundle::open_local(&package_dir).unwrap()
+    }
+
+    #[test]
+    fn merges_checksums_of_the_same_url() {
+        let dir = test_dir("download-merge");
+        let first = bundle(
+            &dir,
+            "first",
+            r#"archive "https://example.org/foo-1.0.tar.gz" sha256="abc""#,
+        );
+        let second = bundle(
+            &dir,
+            "second",
+            r#"archive "https://example.org/foo-1.0.tar.gz" sha256="ABC" sha512="def""#,
+        );
+        let conflicting = bundle(
+            &dir,
+            "conflicting",
+            r#"archive "https://example.org/foo-1.0.tar.gz" sha512="123""#,
+        );
+
+        let archives = [&first, &second]
+            .into_iter()
+            .flat_map(|pkg| archives_of(pkg).into_iter().map(move |a| (pkg, a)))
+            .collect::<Vec<_>>();
+        let merged = merge_archives(&archives).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].1.sha256.as_deref(), Some("abc"));
+        assert_eq!(merged[0].1.sha512.as_deref(), Some("def"));
+
+        let archives = [&first, &second, &conflicting]
+            .into_iter()
+            .flat_map(|pkg| archives_of(pkg).into_iter().map(move |a| (pkg, a)))
+            .collect::<Vec<_>>();
+        assert!(merge_archives(&archives).is_err());
+    }
+}
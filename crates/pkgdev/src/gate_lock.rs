@@ -0,0 +1,402 @@
+use std::{
+    collections::BTreeMap,
+    fs::{read_dir, read_to_string, File},
+    io::Write,
+    path::Path,
+};
+
+use crate::{cache, download, workspace::Workspace};
+use bundle::{Bundle, Package, PackageLock};
+use gate::Gate;
+use miette::{Diagnostic, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error, Diagnostic)]
+pub(crate) enum GateLockError {
+    #[error("package {0} is not in the gate.lock")]
+    #[diagnostic(
+        code(pkgdev::gate_lock::not_locked),
+        help("run pkgdev lock to record the current sources or build without --locked")
+    )]
+    NotLocked(String),
+    #[error("{0} changed since the gate.lock was written:\n{1}")]
+    #[diagnostic(
+        code(pkgdev::gate_lock::outdated),
+        help("run pkgdev lock to record the current sources or build without --locked")
+    )]
+    Outdated(String, String),
+}
+
+/// Records every input that went into the packages of a gate so a build
+/// can be repeated exactly and changes to the sources can be found.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct GateLock {
+    pub name: String,
+    pub version: String,
+    pub branch: String,
+    pub publisher: String,
+    /// sha256 of every file in the transform include directory
+    pub transforms: BTreeMap<String, String>,
+    pub packages: BTreeMap<String, LockedPackage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LockedPackage {
+    pub archives: Vec<LockedArchive>,
+    pub git: Vec<LockedGit>,
+    /// sha256 of every file of the bundle like patches and overlays
+    pub files: BTreeMap<String, String>,
+    /// The package document with the gate data merged into it
+    pub document: Package,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LockedArchive {
+    pub url: String,
+    pub sha256: String,
+    pub sha512: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LockedGit {
+    pub repository: String,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub commit: String,
+}
+
+impl GateLock {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = read_to_string(path)
+            .into_diagnostic()
+            .wrap_err(format!("could not read gate lock {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err(format!("could not parse gate lock {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut f = File::create(path).into_diagnostic()?;
+        f.write_all(
+            serde_json::to_string_pretty(self)
+                .into_diagnostic()?
+                .as_bytes(),
+        )
+        .into_diagnostic()?;
+        Ok(())
+    }
+}
+
+/// Resolves all sources of the packages and records them. Archives are
+/// downloaded to learn their digests, branches and tags of git sources are
+/// resolved to the commit they point to now unless the package.lock already
+/// pins them.
+pub(crate) fn generate(
+    wks: &Workspace,
+    gate_data: &Gate,
+    packages: &[Bundle],
+    transform_include_dir: Option<&Path>,
+    allow_unverified: bool,
+) -> Result<GateLock> {
+    let archives = packages
+        .iter()
+        .flat_map(|pkg| {
+            download::archives_of(pkg)
+                .into_iter()
+                .map(move |archive| (pkg, archive))
+        })
+        .collect::<Vec<_>>();
+    download::fetch_archives(wks, &archives, Some(gate_data), false, allow_unverified)?;
+
+    let mut lock = GateLock {
+        name: gate_data.name.clone(),
+        version: gate_data.version.clone(),
+        branch: gate_data.branch.clone(),
+        publisher: gate_data.publisher.clone(),
+        transforms: match transform_include_dir {
+            Some(dir) => file_hashes(dir)?,
+            None => BTreeMap::new(),
+        },
+        packages: BTreeMap::new(),
+    };
+
+    for pkg in packages {
+        let mut locked_archives = vec![];
+        for archive in download::archives_of(pkg) {
+            let cached_path = cache::lookup(archive)?.ok_or(miette::miette!(
+                "archive {} is missing from the cache after downloading it",
+                archive.src
+            ))?;
+            let (sha256, sha512) = cache::file_digests(cached_path)?;
+            locked_archives.push(LockedArchive {
+                url: archive.src.clone(),
+                sha256,
+                sha512,
+            });
+        }
+
+        let package_lock = PackageLock::open(pkg.get_lock_path())?;
+        let mut locked_git = vec![];
+        for git in download::git_sources_of(pkg) {
+            let commit = match download::locked_commit(&package_lock, git) {
+                Some(commit) => commit,
+                None => download::resolve_git_commit(wks, git)?,
+            };
+            locked_git.push(LockedGit {
+                repository: git.repository.clone(),
+                branch: git.branch.clone(),
+                tag: git.tag.clone(),
+                commit,
+            });
+        }
+
+        lock.packages.insert(
+            pkg.get_name(),
+            LockedPackage {
+                archives: locked_archives,
+                git: locked_git,
+                files: bundle_file_hashes(pkg)?,
+                document: pkg.package_document.clone(),
+            },
+        );
+    }
+
+    Ok(lock)
+}
+
+/// Describes every difference between two locks, one line each.
+pub(crate) fn diff(old: &GateLock, new: &GateLock) -> Result<Vec<String>> {
+    let mut changes = vec![];
+    for (field, old_value, new_value) in [
+        ("version", &old.version, &new.version),
+        ("branch", &old.branch, &new.branch),
+        ("publisher", &old.publisher, &new.publisher),
+    ] {
+        if old_value != new_value {
+            changes.push(format!(
+                "gate {} changed from {} to {}",
+                field, old_value, new_value
+            ));
+        }
+    }
+    diff_hashes(
+        "transform include",
+        &old.transforms,
+        &new.transforms,
+        &mut changes,
+    );
+
+    for name in old.packages.keys() {
+        if !new.packages.contains_key(name) {
+            changes.push(format!("package {} was removed", name));
+        }
+    }
+    for (name, new_package) in &new.packages {
+        let Some(old_package) = old.packages.get(name) else {
+            changes.push(format!("package {} was added", name));
+            continue;
+        };
+
+        let mut package_changes = vec![];
+        diff_package(old_package, new_package, &mut package_changes)?;
+        for old_archive in &old_package.archives {
+            match new_package
+                .archives
+                .iter()
+                .find(|a| a.url == old_archive.url)
+            {
+                None => package_changes.push(format!("archive {} was removed", old_archive.url)),
+                Some(new_archive) if new_archive != old_archive => package_changes.push(format!(
+                    "archive {} changed from sha256 {} to {}",
+                    old_archive.url, old_archive.sha256, new_archive.sha256
+                )),
+                Some(_) => {}
+            }
+        }
+        for new_archive in &new_package.archives {
+            if !old_package
+                .archives
+                .iter()
+                .any(|a| a.url == new_archive.url)
+            {
+                package_changes.push(format!("archive {} was added", new_archive.url));
+            }
+        }
+        for new_git in &new_package.git {
+            let old_git = old_package.git.iter().find(|g| {
+                g.repository == new_git.repository
+                    && g.branch == new_git.branch
+                    && g.tag == new_git.tag
+            });
+            match old_git {
+                None => {
+                    package_changes.push(format!("git source {} was added", new_git.repository))
+                }
+                Some(old_git) if old_git.commit != new_git.commit => package_changes.push(format!(
+                    "git source {} moved from {} to {}",
+                    new_git.repository, old_git.commit, new_git.commit
+                )),
+                Some(_) => {}
+            }
+        }
+
+        changes.extend(
+            package_changes
+                .into_iter()
+                .map(|change| format!("{}: {}", name, change)),
+        );
+    }
+
+    Ok(changes)
+}
+
+/// Checks that the inputs of the package that can be checked without
+/// network access still match the lock and pins the archives and git
+/// sources to the locked digests and commits.
+pub(crate) fn check_and_pin(lock: &GateLock, pkg: &mut Bundle) -> Result<()> {
+    let name = pkg.get_name();
+    let locked = lock
+        .packages
+        .get(&name)
+        .ok_or(GateLockError::NotLocked(name.clone()))?;
+
+    let current = LockedPackage {
+        archives: vec![],
+        git: vec![],
+        files: bundle_file_hashes(pkg)?,
+        document: pkg.package_document.clone(),
+    };
+    let mut changes = vec![];
+    diff_package(locked, &current, &mut changes)?;
+    if !changes.is_empty() {
+        return Err(GateLockError::Outdated(name, changes.join("\n")).into());
+    }
+
+    for section in pkg.package_document.sources.iter_mut() {
+        for src in section.sources.iter_mut() {
+            match src {
+                bundle::SourceNode::Archive(archive) => {
+                    let locked_archive = locked
+                        .archives
+                        .iter()
+                        .find(|a| a.url == archive.src)
+                        .ok_or(GateLockError::NotLocked(format!(
+                            "archive {} of {}",
+                            archive.src, name
+                        )))?;
+                    archive.sha256 = Some(locked_archive.sha256.clone());
+                    archive.sha512 = Some(locked_archive.sha512.clone());
+                }
+                bundle::SourceNode::Git(git) => {
+                    let locked_git = locked
+                        .git
+                        .iter()
+                        .find(|g| {
+                            g.repository == git.repository
+                                && g.branch == git.branch
+                                && g.tag == git.tag
+                        })
+                        .ok_or(GateLockError::NotLocked(format!(
+                            "git source {} of {}",
+                            git.repository, name
+                        )))?;
+                    git.commit = Some(locked_git.commit.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the transform includes are the ones the lock was made with.
+pub(crate) fn check_transforms(
+    lock: &GateLock,
+    transform_include_dir: Option<&Path>,
+) -> Result<()> {
+    let current = match transform_include_dir {
+        Some(dir) => file_hashes(dir)?,
+        None => BTreeMap::new(),
+    };
+    let mut changes = vec![];
+    diff_hashes(
+        "transform include",
+        &lock.transforms,
+        &current,
+        &mut changes,
+    );
+    if changes.is_empty() {
+        Ok(())
+    } else {
+        Err(GateLockError::Outdated(String::from("the gate"), changes.join("\n")).into())
+    }
+}
+
+/// Compares the bundle files and package documents of two locked packages.
+fn diff_package(old: &LockedPackage, new: &LockedPackage, changes: &mut Vec<String>) -> Result<()> {
+    diff_hashes("file", &old.files, &new.files, changes);
+    if serde_json::to_value(&old.document).into_diagnostic()?
+        != serde_json::to_value(&new.document).into_diagnostic()?
+    {
+        changes.push(String::from("the package document changed"));
+    }
+    Ok(())
+}
+
+fn diff_hashes(
+    kind: &str,
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+    changes: &mut Vec<String>,
+) {
+    for (path, old_hash) in old {
+        match new.get(path) {
+            None => changes.push(format!("{} {} was removed", kind, path)),
+            Some(new_hash) if new_hash != old_hash => {
+                changes.push(format!("{} {} changed", kind, path))
+            }
+            Some(_) => {}
+        }
+    }
+    for path in new.keys() {
+        if !old.contains_key(path) {
+            changes.push(format!("{} {} was added", kind, path));
+        }
+    }
+}
+
+/// The package.kdl is recorded as the merged document and the package.lock
+/// as the git commits so neither is part of the file hashes.
+fn bundle_file_hashes(pkg: &Bundle) -> Result<BTreeMap<String, String>> {
+    let mut hashes = file_hashes(pkg.get_path())?;
+    hashes.remove("package.kdl");
+    hashes.remove("package.lock");
+    Ok(hashes)
+}
+
+/// Returns the sha256 of every file below the directory by relative path.
+fn file_hashes(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    collect_file_hashes(dir, dir, &mut hashes)?;
+    Ok(hashes)
+}
+
+fn collect_file_hashes(
+    root: &Path,
+    dir: &Path,
+    hashes: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    for entry in read_dir(dir).into_diagnostic()? {
+        let path = entry.into_diagnostic()?.path();
+        if path.is_dir() {
+            collect_file_hashes(root, &path, hashes)?;
+        } else if path.is_file() {
+            let (sha256, _) = cache::file_digests(&path)?;
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            hashes.insert(relative.to_string_lossy().to_string(), sha256);
+        }
+    }
+    Ok(())
+}
@@ -3,8 +3,7 @@ use std::{collections::HashMap, process::Stdio};
 use bundle::Bundle;
 use miette::{IntoDiagnostic, Result};
 
-use crate::{config::Settings, derive_source_name, workspace::Workspace};
-use std::process::Command;
+use crate::{derive_source_name, logs::run_logged, sandbox::Sandbox, workspace::Workspace};
 
 enum BuildTool {
     Make,
@@ -21,21 +20,21 @@ impl ToString for BuildTool {
 }
 
 //TODO: custom install section
-pub fn run_install(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result<()> {
+pub fn run_install(wks: &Workspace, pkg: &Bundle, sandbox: &Sandbox) -> Result<()> {
     let build_dir = wks.get_or_create_build_dir()?;
     let unpack_name = derive_source_name(
         pkg.package_document.name.clone(),
         &pkg.package_document.sources[0],
     );
     let unpack_path = build_dir.join(&unpack_name);
-    if pkg.package_document.seperate_build_dir {
+    if pkg.package_document.uses_seperate_build_dir() {
         let out_dir = build_dir.join("out");
         std::env::set_current_dir(&out_dir).into_diagnostic()?;
     } else {
         std::env::set_current_dir(&unpack_path).into_diagnostic()?;
     }
 
-    let build_tool_check_dir = if pkg.package_document.seperate_build_dir {
+    let build_tool_check_dir = if pkg.package_document.uses_seperate_build_dir() {
         build_dir.join("out")
     } else {
         unpack_path.clone()
@@ -50,7 +49,6 @@ pub fn run_install(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result
     };
 
     let mut env_flags: HashMap<String, String> = HashMap::new();
-    env_flags.insert("PATH".into(), settings.get_search_path().join(":"));
 
     let proto_dir_path = wks.get_or_create_prototype_dir()?;
     let proto_dir_str = proto_dir_path.to_string_lossy().to_string();
@@ -58,11 +56,13 @@ pub fn run_install(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result
     env_flags.insert(String::from("DESTDIR"), proto_dir_str.clone());
     let destdir_arg = format!("DESTDIR={}", &proto_dir_str);
 
-    let mut build_cmd = Command::new(build_tool.to_string());
-    build_cmd.env_clear();
+    let mut build_cmd = sandbox.command(build_tool.to_string());
     build_cmd.arg("install");
     build_cmd.envs(&env_flags);
-    build_cmd.arg(&destdir_arg);
+    // ninja would treat the assignment as a target name and only reads DESTDIR from the environment
+    if let BuildTool::Make = build_tool {
+        build_cmd.arg(&destdir_arg);
+    }
 
     build_cmd.stdin(Stdio::null());
     build_cmd.stdout(Stdio::inherit());
@@ -79,7 +79,7 @@ pub fn run_install(wks: &Workspace, pkg: &Bundle, settings: &Settings) -> Result
             .join(",")
     );
 
-    let status = build_cmd.status().into_diagnostic()?;
+    let status = run_logged(wks, &mut build_cmd)?;
     if status.success() {
         println!("Successfully installed {}", pkg.get_name());
     } else {
@@ -1,15 +1,21 @@
 use std::{
     fs::File,
-    path::PathBuf,
+    path::Path,
     process::{Command, Stdio},
 };
 
-use crate::{config::Settings, derive_source_name, workspace::Workspace};
-use bundle::{Bundle, SourceNode};
+use crate::{
+    config::Settings,
+    derive_source_name,
+    logs::{log_errors, run_logged},
+    workspace::Workspace,
+};
+use bundle::{Bundle, DependencyKind, Package, SourceNode};
 use fs_extra::file::write_all;
 use gate::Gate;
 use microtemplate::{render, Substitutions};
 use miette::{IntoDiagnostic, Result};
+use pkg5::{generate::generate_manifest, mogrify::Mogrifier, repository::FileRepository, Manifest};
 
 const DEFAULT_IPS_TEMPLATE: &str = r#"
 #
@@ -27,11 +33,10 @@ const DEFAULT_IPS_TEMPLATE: &str = r#"
 # Copyright 2023 OpenFlowLabs
 #
 
-set name=pkg.fmri value=pkg:/{name}@{version},{build_version}-{branch_version}.{revision}
+set name=pkg.fmri value=pkg:/{name}@{fmri_version}
 set name=pkg.summary value="{summary}"
 set name=info.classification value="org.opensolaris.category.2008:{classification}"
 set name=info.upstream-url value="{project_url}"
-set name=info.source-url value="{source_url}"
 
 license {license_file_name} license='{license_name}'
 
@@ -42,44 +47,49 @@ license {license_file_name} license='{license_name}'
 #[derive(Substitutions)]
 struct StringInterpolationVars<'a> {
     pub name: &'a str,
-    pub version: &'a str,
-    pub build_version: &'a str,
-    pub branch_version: &'a str,
-    pub revision: &'a str,
+    pub fmri_version: &'a str,
     pub summary: &'a str,
     pub classification: &'a str,
     pub project_url: &'a str,
-    pub source_url: &'a str,
     pub license_file_name: &'a str,
     pub license_name: &'a str,
 }
 
-fn get_source_url<'a>(src: &'a SourceNode) -> &'a str {
+fn get_source_url(src: &SourceNode) -> Option<&str> {
     match src {
-        SourceNode::Archive(a) => &a.src,
-        SourceNode::Git(g) => &g.repository,
-        _ => "",
+        SourceNode::Archive(a) => Some(&a.src),
+        SourceNode::Git(g) => Some(&g.repository),
+        _ => None,
     }
 }
 
-pub fn run_generate_filelist(wks: &Workspace, pkg: &Bundle) -> Result<()> {
+pub fn run_generate_filelist(wks: &Workspace, pkg: &Bundle, native: bool) -> Result<()> {
     let proto_path = wks.get_or_create_prototype_dir()?;
     let manifest_path = wks.get_or_create_manifest_dir()?;
 
+    if native {
+        let manifest = generate_manifest(&proto_path)?;
+        write_all(manifest_path.join("filelist.fmt"), &manifest.to_pkgfmt()).into_diagnostic()?;
+        println!("Generated filelist for {}", pkg.get_name());
+        return Ok(());
+    }
+
     let formatted_manifest = File::create(manifest_path.join("filelist.fmt")).into_diagnostic()?;
 
-    let pkg_send_cmd = Command::new("pkgsend")
+    let mut pkg_send_cmd = Command::new("pkgsend");
+    pkg_send_cmd
         .arg("generate")
         .arg(proto_path.to_string_lossy().to_string())
-        .stdout(Stdio::piped())
-        .spawn()
-        .into_diagnostic()?;
+        .stdout(Stdio::piped());
+    log_errors(wks, &mut pkg_send_cmd)?;
+    let pkg_send_child = pkg_send_cmd.spawn().into_diagnostic()?;
 
-    let pkg_fmt_cmd_status = Command::new("pkgfmt")
-        .stdin(pkg_send_cmd.stdout.unwrap())
-        .stdout(formatted_manifest)
-        .status()
-        .into_diagnostic()?;
+    let mut pkg_fmt_cmd = Command::new("pkgfmt");
+    pkg_fmt_cmd
+        .stdin(pkg_send_child.stdout.unwrap())
+        .stdout(formatted_manifest);
+    log_errors(wks, &mut pkg_fmt_cmd)?;
+    let pkg_fmt_cmd_status = pkg_fmt_cmd.status().into_diagnostic()?;
 
     if pkg_fmt_cmd_status.success() {
         println!("Generated filelist for {}", pkg.get_name());
@@ -88,60 +98,19 @@ pub fn run_generate_filelist(wks: &Workspace, pkg: &Bundle) -> Result<()> {
         Err(miette::miette!("non zero code returned from pkgfmt"))
     }
 }
+/// Mogrifies the generated manifest with the transforms of the gate and the
+/// package. Transform includes are read from the include directory for the
+/// native mogrifier and pkgmogrify alike.
 pub fn run_mogrify(
     wks: &Workspace,
     pkg: &Bundle,
     gate: Option<Gate>,
-    transform_includes: Option<PathBuf>,
+    include_dir: &Path,
+    native: bool,
 ) -> Result<()> {
-    let vars = StringInterpolationVars {
-        name: &pkg.get_name(),
-        version: &pkg
-            .package_document
-            .version
-            .clone()
-            .unwrap_or(String::from("0.5.11")), //TODO take this default version from the gate
-        build_version: &gate.clone().unwrap_or(Gate::default()).version,
-        branch_version: &gate.clone().unwrap_or(Gate::default()).branch,
-        revision: &pkg
-            .package_document
-            .revision
-            .clone()
-            .unwrap_or(String::from("1")),
-        summary: &pkg
-            .package_document
-            .summary
-            .clone()
-            .ok_or(miette::miette!("no summary specified"))?,
-        classification: &pkg
-            .package_document
-            .classification
-            .clone()
-            .ok_or(miette::miette!("no classification specified"))?,
-        project_url: &pkg
-            .package_document
-            .project_url
-            .clone()
-            .ok_or(miette::miette!("no project_url specified"))?,
-        source_url: get_source_url(&pkg.package_document.sources[0].sources[0]),
-        license_file_name: &pkg
-            .package_document
-            .license_file
-            .clone()
-            .ok_or(miette::miette!("no license_file specified"))?,
-        license_name: &pkg
-            .package_document
-            .license
-            .clone()
-            .ok_or(miette::miette!("no license specified"))?,
-    };
-
     let manifest_path = wks.get_or_create_manifest_dir()?;
 
-    let manifest = render(DEFAULT_IPS_TEMPLATE, vars);
-
-    let mogrified_manifest = File::create(manifest_path.join("mogrified.mog")).into_diagnostic()?;
-
+    let manifest = render_package_manifest(pkg, gate.as_ref())?;
     write_all(manifest_path.join("generated.p5m"), &manifest).into_diagnostic()?;
 
     let include_path = if let Some(gate) = gate {
@@ -166,13 +135,40 @@ pub fn run_mogrify(
         None
     };
 
-    let mut pkg_mogrify_cmd = Command::new("pkgmogrify");
+    if native {
+        let mut mogrifier = Mogrifier::new();
+        mogrifier.add_include_dir(include_dir);
+        mogrifier.read_file(manifest_path.join("generated.p5m"))?;
+        mogrifier.read_file(manifest_path.join("filelist.fmt"))?;
+        if let Some(includes) = &include_path {
+            mogrifier.read_file(includes)?;
+        }
+        if let Some(mog_file_path) = pkg.get_mogrify_manifest() {
+            mogrifier.read_file(mog_file_path)?;
+        }
 
-    if let Some(includes_path) = transform_includes {
-        pkg_mogrify_cmd.arg("-I").arg(&includes_path);
+        let output = mogrifier.run()?;
+        for line in &output.printed {
+            println!("{}", line);
+        }
+        write_all(
+            manifest_path.join("mogrified.mog"),
+            &output.manifest.to_pkgfmt(),
+        )
+        .into_diagnostic()?;
+
+        println!("Mogrified manifests for {}", pkg.get_name());
+        return Ok(());
     }
+
+    let mogrified_manifest = File::create(manifest_path.join("mogrified.mog")).into_diagnostic()?;
+
+    let mut pkg_mogrify_cmd = Command::new("pkgmogrify");
+
     pkg_mogrify_cmd
-        .current_dir("..")
+        .current_dir(include_dir)
+        .arg("-I")
+        .arg(include_dir)
         .arg(
             manifest_path
                 .join("generated.p5m")
@@ -195,13 +191,15 @@ pub fn run_mogrify(
     }
 
     pkg_mogrify_cmd.stdout(Stdio::piped());
+    log_errors(wks, &mut pkg_mogrify_cmd)?;
     let pkg_mogrify_status = pkg_mogrify_cmd.spawn().into_diagnostic()?;
 
-    let pkg_fmt_cmd_status = Command::new("pkgfmt")
+    let mut pkg_fmt_cmd = Command::new("pkgfmt");
+    pkg_fmt_cmd
         .stdin(pkg_mogrify_status.stdout.unwrap())
-        .stdout(mogrified_manifest)
-        .status()
-        .into_diagnostic()?;
+        .stdout(mogrified_manifest);
+    log_errors(wks, &mut pkg_fmt_cmd)?;
+    let pkg_fmt_cmd_status = pkg_fmt_cmd.status().into_diagnostic()?;
 
     if pkg_fmt_cmd_status.success() {
         println!("Mogrified manifests for {}", pkg.get_name());
@@ -211,13 +209,135 @@ pub fn run_mogrify(
     }
 }
 
+/// Renders the generated manifest of the package with its name, version,
+/// metadata and dependencies.
+fn render_package_manifest(pkg: &Bundle, gate: Option<&Gate>) -> Result<String> {
+    let vars = StringInterpolationVars {
+        name: &pkg.get_name(),
+        fmri_version: &package_fmri_version(
+            &pkg.package_document,
+            gate.unwrap_or(&Gate::default()),
+        ),
+        summary: &pkg
+            .package_document
+            .summary
+            .clone()
+            .ok_or(miette::miette!("no summary specified"))?,
+        classification: &pkg
+            .package_document
+            .classification
+            .clone()
+            .ok_or(miette::miette!("no classification specified"))?,
+        project_url: &pkg
+            .package_document
+            .project_url
+            .clone()
+            .ok_or(miette::miette!("no project_url specified"))?,
+        license_file_name: &pkg
+            .package_document
+            .license_file
+            .clone()
+            .ok_or(miette::miette!("no license_file specified"))?,
+        license_name: &pkg
+            .package_document
+            .license
+            .clone()
+            .ok_or(miette::miette!("no license specified"))?,
+    };
+
+    let mut manifest = render(DEFAULT_IPS_TEMPLATE, vars);
+    // Packages made only from files of the bundle have no sources to point to
+    if let Some(source_url) = pkg
+        .package_document
+        .sources
+        .first()
+        .and_then(|section| section.sources.first())
+        .and_then(get_source_url)
+    {
+        manifest.push_str(&format!(
+            "set name=info.source-url value=\"{}\"\n",
+            source_url
+        ));
+    }
+    manifest.push_str(&render_dependencies(pkg, gate));
+    Ok(manifest)
+}
+
+/// The version a package is published at in the gate like
+/// `2.39,0.5.11-2023.0.0.1`.
+pub fn package_fmri_version(package: &Package, gate: &Gate) -> String {
+    gate_fmri_version(
+        package.version.as_deref().unwrap_or("0.5.11"),
+        package.revision.as_deref().unwrap_or("1"),
+        gate,
+    )
+}
+
+/// Combines the component version and revision with the build and branch
+/// version of the gate.
+fn gate_fmri_version(component: &str, revision: &str, gate: &Gate) -> String {
+    format!(
+        "{},{}-{}.{}",
+        component, gate.version, gate.branch, revision
+    )
+}
+
+/// Renders the declared dependencies of the package as depend actions.
+/// Dependencies on packages of the gate are constrained to the version the
+/// gate publishes them at. Dev dependencies are only needed to build the
+/// package and are left out.
+fn render_dependencies(pkg: &Bundle, gate: Option<&Gate>) -> String {
+    let mut actions = String::new();
+    for dep in pkg.package_document.dependencies.iter().filter(|d| !d.dev) {
+        let kind = match dep.kind.as_ref().unwrap_or(&DependencyKind::Require) {
+            DependencyKind::Require => "require",
+            DependencyKind::Incorporate => "incorporate",
+            DependencyKind::Optional => "optional",
+        };
+        let fmri = match gate.and_then(|g| g.get_package(&dep.name).map(|p| (g, p))) {
+            Some((gate, gate_package)) => format!(
+                "pkg:/{}@{}",
+                dep.name,
+                package_fmri_version(&sibling_document(pkg, &gate_package), gate)
+            ),
+            None => format!("pkg:/{}", dep.name),
+        };
+        actions.push_str(&format!("depend type={} fmri={}\n", kind, fmri));
+    }
+    actions
+}
+
+/// Opens the package.kdl of another gate package next to the bundle with
+/// the gate data merged into it. Without a bundle only the gate data is
+/// known.
+fn sibling_document(pkg: &Bundle, gate_package: &Package) -> Package {
+    let name = gate_package
+        .name
+        .rsplit_once('/')
+        .map(|(_, name)| name)
+        .unwrap_or(&gate_package.name);
+    let sibling = pkg
+        .get_path()
+        .parent()
+        .map(|packages_dir| packages_dir.join(name))
+        .and_then(|path| Bundle::open_local(path).ok());
+    match sibling {
+        Some(mut sibling) => match sibling.package_document.merge_into_mut(gate_package) {
+            Ok(_) => sibling.package_document,
+            Err(_) => gate_package.clone(),
+        },
+        None => gate_package.clone(),
+    }
+}
+
 pub fn run_generate_pkgdepend(wks: &Workspace, pkg: &Bundle) -> Result<()> {
     let manifest_path = wks.get_or_create_manifest_dir()?;
     let prototype_path = wks.get_or_create_prototype_dir()?;
 
     let depend_manifest = File::create(manifest_path.join("generated.dep")).into_diagnostic()?;
 
-    let pkg_depend_cmd = Command::new("pkgdepend")
+    let mut pkg_depend_cmd = Command::new("pkgdepend");
+    pkg_depend_cmd
         .arg("generate")
         .arg("-m")
         .arg("-d")
@@ -228,15 +348,16 @@ pub fn run_generate_pkgdepend(wks: &Workspace, pkg: &Bundle) -> Result<()> {
                 .to_string_lossy()
                 .to_string(),
         )
-        .stdout(Stdio::piped())
-        .spawn()
-        .into_diagnostic()?;
+        .stdout(Stdio::piped());
+    log_errors(wks, &mut pkg_depend_cmd)?;
+    let pkg_depend_child = pkg_depend_cmd.spawn().into_diagnostic()?;
 
-    let pkg_fmt_cmd_status = Command::new("pkgfmt")
-        .stdin(pkg_depend_cmd.stdout.unwrap())
-        .stdout(depend_manifest)
-        .status()
-        .into_diagnostic()?;
+    let mut pkg_fmt_cmd = Command::new("pkgfmt");
+    pkg_fmt_cmd
+        .stdin(pkg_depend_child.stdout.unwrap())
+        .stdout(depend_manifest);
+    log_errors(wks, &mut pkg_fmt_cmd)?;
+    let pkg_fmt_cmd_status = pkg_fmt_cmd.status().into_diagnostic()?;
 
     if pkg_fmt_cmd_status.success() {
         println!("Generated dependency entries for {}", pkg.get_name());
@@ -249,7 +370,8 @@ pub fn run_generate_pkgdepend(wks: &Workspace, pkg: &Bundle) -> Result<()> {
 pub fn run_resolve_dependencies(wks: &Workspace, pkg: &Bundle) -> Result<()> {
     let manifest_path = wks.get_or_create_manifest_dir()?;
 
-    let pkg_depend_cmd = Command::new("pkgdepend")
+    let mut pkg_depend_cmd = Command::new("pkgdepend");
+    pkg_depend_cmd
         .arg("resolve")
         .arg("-m")
         .arg(
@@ -258,11 +380,10 @@ pub fn run_resolve_dependencies(wks: &Workspace, pkg: &Bundle) -> Result<()> {
                 .to_string_lossy()
                 .to_string(),
         )
-        .stdout(Stdio::inherit())
-        .status()
-        .into_diagnostic()?;
+        .stdout(Stdio::inherit());
+    let pkg_depend_status = run_logged(wks, &mut pkg_depend_cmd)?;
 
-    if pkg_depend_cmd.success() {
+    if pkg_depend_status.success() {
         println!("Resolved dependencies for {}", pkg.get_name());
         Ok(())
     } else {
@@ -273,18 +394,18 @@ pub fn run_resolve_dependencies(wks: &Workspace, pkg: &Bundle) -> Result<()> {
 pub fn run_lint(wks: &Workspace, pkg: &Bundle) -> Result<()> {
     let manifest_path = wks.get_or_create_manifest_dir()?;
 
-    let pkg_lint_cmd = Command::new("pkglint")
+    let mut pkg_lint_cmd = Command::new("pkglint");
+    pkg_lint_cmd
         .arg(
             manifest_path
                 .join("generated.dep.res")
                 .to_string_lossy()
                 .to_string(),
         )
-        .stdout(Stdio::inherit())
-        .status()
-        .into_diagnostic()?;
+        .stdout(Stdio::inherit());
+    let pkg_lint_status = run_logged(wks, &mut pkg_lint_cmd)?;
 
-    if pkg_lint_cmd.success() {
+    if pkg_lint_status.success() {
         println!("Lint success for {}", pkg.get_name());
         Ok(())
     } else {
@@ -292,16 +413,28 @@ pub fn run_lint(wks: &Workspace, pkg: &Bundle) -> Result<()> {
     }
 }
 
-pub fn ensure_repo_with_publisher_exists(publisher: &str) -> Result<()> {
+pub fn ensure_repo_with_publisher_exists(
+    wks: &Workspace,
+    publisher: &str,
+    native: bool,
+) -> Result<()> {
     let repo_base = Settings::get_or_create_repo_dir()?;
 
+    if native {
+        let repo = FileRepository::create(&repo_base)?;
+        if !repo.has_publisher(publisher) {
+            repo.add_publisher(publisher)?;
+        }
+        return Ok(());
+    }
+
     if !repo_base.join("pkg5.repository").exists() {
-        let pkg_repo_status = Command::new("pkgrepo")
+        let mut pkg_repo_cmd = Command::new("pkgrepo");
+        pkg_repo_cmd
             .arg("create")
             .arg(&repo_base.to_string_lossy().to_string())
-            .stdout(Stdio::inherit())
-            .status()
-            .into_diagnostic()?;
+            .stdout(Stdio::inherit());
+        let pkg_repo_status = run_logged(wks, &mut pkg_repo_cmd)?;
         if !pkg_repo_status.success() {
             return Err(miette::miette!(
                 "pkgrepo create failed with non zero exit code"
@@ -310,14 +443,14 @@ pub fn ensure_repo_with_publisher_exists(publisher: &str) -> Result<()> {
     }
 
     if !repo_base.join("publisher").join(publisher).exists() {
-        let pkg_repo_status = Command::new("pkgrepo")
+        let mut pkg_repo_cmd = Command::new("pkgrepo");
+        pkg_repo_cmd
             .arg("add-publisher")
             .arg("-s")
             .arg(&repo_base.to_string_lossy().to_string())
             .arg(publisher)
-            .stdout(Stdio::inherit())
-            .status()
-            .into_diagnostic()?;
+            .stdout(Stdio::inherit());
+        let pkg_repo_status = run_logged(wks, &mut pkg_repo_cmd)?;
         if !pkg_repo_status.success() {
             return Err(miette::miette!(
                 "pkgrepo create failed with non zero exit code"
@@ -328,17 +461,58 @@ pub fn ensure_repo_with_publisher_exists(publisher: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn publish_package(wks: &Workspace, pkg: &Bundle, publisher: &str) -> Result<()> {
-    let proto_dir = wks.get_or_create_prototype_dir()?;
-    let build_dir = wks.get_or_create_build_dir()?;
-    let unpack_name = derive_source_name(
-        pkg.package_document.name.clone(),
-        &pkg.package_document.sources[0],
-    );
-    let unpack_path = build_dir.join(&unpack_name);
+pub fn publish_package(wks: &Workspace, pkg: &Bundle, publisher: &str, native: bool) -> Result<()> {
+    let unpack_path = match pkg.package_document.sources.first() {
+        Some(section) => Some(wks.get_or_create_build_dir()?.join(derive_source_name(
+            pkg.package_document.name.clone(),
+            section,
+        ))),
+        None => None,
+    };
     let repo_path = Settings::get_or_create_repo_dir()?;
+    publish_to_repo(
+        wks,
+        pkg,
+        publisher,
+        &repo_path,
+        unpack_path.as_deref(),
+        native,
+    )
+}
+
+/// Publishes the packed package with the files of the prototype and the
+/// unpacked sources. Packages without sources take their files from the
+/// bundle instead.
+fn publish_to_repo(
+    wks: &Workspace,
+    pkg: &Bundle,
+    publisher: &str,
+    repo_path: &Path,
+    unpack_path: Option<&Path>,
+    native: bool,
+) -> Result<()> {
+    let proto_dir = wks.get_or_create_prototype_dir()?;
+    let unpack_path = unpack_path.unwrap_or(pkg.get_path());
+
+    if native {
+        // Without pkgdepend there is no resolved manifest, publish the mogrified one.
+        let manifest =
+            Manifest::parse_file(wks.get_or_create_manifest_dir()?.join("mogrified.mog"))?;
+        let repo = FileRepository::open(repo_path)?;
+        let fmri = repo.publish(publisher, &manifest, &[proto_dir.as_path(), unpack_path])?;
+        println!("Package {} published sucessfully", fmri);
+        println!(
+            "Install with pkg set-publisher {}; pkg install -g {} {}",
+            publisher,
+            repo_path.display(),
+            pkg.get_name()
+        );
+        return Ok(());
+    }
+
     let manifest = wks.get_or_create_manifest_dir()?.join("generated.dep.res");
-    let pkgsend_status = Command::new("pkgsend")
+    let mut pkgsend_cmd = Command::new("pkgsend");
+    pkgsend_cmd
         .arg("publish")
         .arg("-d")
         .arg(&proto_dir.to_string_lossy().to_string())
@@ -347,9 +521,8 @@ pub fn publish_package(wks: &Workspace, pkg: &Bundle, publisher: &str) -> Result
         .arg("-s")
         .arg(&repo_path.to_string_lossy().to_string())
         .arg(&manifest.to_string_lossy().to_string())
-        .stdout(Stdio::inherit())
-        .status()
-        .into_diagnostic()?;
+        .stdout(Stdio::inherit());
+    let pkgsend_status = run_logged(wks, &mut pkgsend_cmd)?;
 
     if pkgsend_status.success() {
         println!("Package {} built and published sucessfully", pkg.get_name());
@@ -366,3 +539,270 @@ pub fn publish_package(wks: &Workspace, pkg: &Bundle, publisher: &str) -> Result
         ))
     }
 }
+
+/// The package locking the packages of a gate to the versions of one
+/// release like `consolidation/solarm/solarm-incorporation`.
+pub fn incorporation_name(gate: &Gate) -> String {
+    format!("consolidation/{0}/{0}-incorporation", gate.name)
+}
+
+/// Renders the manifest of the incorporation of the gate with an
+/// incorporate dependency on every package at the version the gate
+/// publishes it at.
+pub fn generate_incorporation(gate: &Gate, packages: &[Bundle]) -> String {
+    let mut manifest = format!(
+        r#"set name=pkg.fmri value=pkg:/{}@{}
+set name=pkg.summary value="Incorporation of the {} gate"
+set name=pkg.description value="Constrains the packages of the {} gate to the versions of release {}"
+set name=info.classification value="org.opensolaris.category.2008:Meta Packages/Incorporations"
+"#,
+        incorporation_name(gate),
+        gate_fmri_version(&gate.version, "1", gate),
+        gate.name,
+        gate.name,
+        gate.version
+    );
+    let mut packages = packages.iter().collect::<Vec<&Bundle>>();
+    packages.sort_by_key(|pkg| pkg.get_name());
+    for pkg in packages {
+        manifest.push_str(&format!(
+            "depend type=incorporate fmri=pkg:/{}@{}\n",
+            pkg.get_name(),
+            package_fmri_version(&pkg.package_document, gate)
+        ));
+    }
+    manifest
+}
+
+/// Writes the incorporation of the gate into the manifest directory and
+/// publishes it to the repository.
+pub fn publish_incorporation(
+    wks: &Workspace,
+    gate: &Gate,
+    packages: &[Bundle],
+    native: bool,
+) -> Result<()> {
+    ensure_repo_with_publisher_exists(wks, &gate.publisher, native)?;
+    let manifest = generate_incorporation(gate, packages);
+    let manifest_path = wks.get_or_create_manifest_dir()?.join("incorporation.p5m");
+    write_all(&manifest_path, &manifest).into_diagnostic()?;
+    let repo_path = Settings::get_or_create_repo_dir()?;
+
+    if native {
+        let repo = FileRepository::open(&repo_path)?;
+        let no_payloads: [&Path; 0] = [];
+        let fmri = repo.publish(
+            &gate.publisher,
+            &manifest.parse::<Manifest>()?,
+            &no_payloads,
+        )?;
+        println!("Incorporation {} published sucessfully", fmri);
+        return Ok(());
+    }
+
+    let mut pkgsend_cmd = Command::new("pkgsend");
+    pkgsend_cmd
+        .arg("publish")
+        .arg("-s")
+        .arg(&repo_path.to_string_lossy().to_string())
+        .arg(&manifest_path.to_string_lossy().to_string())
+        .stdout(Stdio::inherit());
+    let pkgsend_status = run_logged(wks, &mut pkgsend_cmd)?;
+
+    if pkgsend_status.success() {
+        println!(
+            "Incorporation {} published sucessfully",
+            incorporation_name(gate)
+        );
+        Ok(())
+    } else {
+        Err(miette::miette!(
+            "non zero code returned from pkgsend publish"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    const GATE: &str = r#"
+name "test"
+version "0.5.11"
+branch "2024.1.0"
+publisher "test.org"
+
+package {
+    name "library/zlib"
+    project-name "zlib"
+}
+"#;
+
+    const APPLICATION: &str = r#"
+name "application/example"
+project-name "example"
+version "1.2"
+revision "3"
+summary "An example application"
+classification "Applications/Accessories"
+project-url "https://example.org"
+license-file "COPYING"
+license "MIT"
+source {
+    archive "https://example.org/example-1.2.tar.gz"
+}
+dependency "library/zlib"
+dependency "library/pcre" kind="optional"
+dependency "consolidation/extra/extra-incorporation" kind="incorporate"
+dependency "developer/meson" dev=true
+"#;
+
+    const ZLIB: &str = r#"
+name "library/zlib"
+project-name "zlib"
+version "1.3.1"
+revision "2"
+"#;
+
+    /// Writes the packages below a packages directory next to a gate file.
+    fn fixture(name: &str) -> (Gate, Bundle) {
+        let dir = test_dir(name);
+        for (package, document) in [("example", APPLICATION), ("zlib", ZLIB)] {
+            let package_dir = dir.join("packages").join(package);
+            std::fs::create_dir_all(&package_dir).unwrap();
+            std::fs::write(package_dir.join("package.kdl"), document).unwrap();
+        }
+        std::fs::write(dir.join("test.kdl"), GATE).unwrap();
+        (
+            Gate::new(dir.join("test.kdl")).unwrap(),
+            Bundle::open_local(dir.join("packages/example")).unwrap(),
+        )
+    }
+
+    #[test]
+    fn fmri_version_defaults() {
+        let (gate, pkg) = fixture("ips-fmri-version");
+        assert_eq!(
+            package_fmri_version(&pkg.package_document, &gate),
+            "1.2,0.5.11-2024.1.0.3"
+        );
+
+        let mut unversioned = pkg.package_document.clone();
+        unversioned.version = None;
+        unversioned.revision = None;
+        assert_eq!(
+            package_fmri_version(&unversioned, &Gate::default()),
+            "0.5.11,0.5.11-2023.0.0.1"
+        );
+    }
+
+    #[test]
+    fn renders_dependencies() {
+        let (gate, pkg) = fixture("ips-dependencies");
+        assert_eq!(
+            render_dependencies(&pkg, Some(&gate)),
+            "depend type=require fmri=pkg:/library/zlib@1.3.1,0.5.11-2024.1.0.2
+depend type=optional fmri=pkg:/library/pcre
+depend type=incorporate fmri=pkg:/consolidation/extra/extra-incorporation
+"
+        );
+        assert_eq!(
+            render_dependencies(&pkg, None),
+            "depend type=require fmri=pkg:/library/zlib
+depend type=optional fmri=pkg:/library/pcre
+depend type=incorporate fmri=pkg:/consolidation/extra/extra-incorporation
+"
+        );
+    }
+
+    #[test]
+    fn incorporation_depends_on_every_package() {
+        let (gate, pkg) = fixture("ips-incorporation");
+        let zlib = Bundle::open_local(pkg.get_path().parent().unwrap().join("zlib")).unwrap();
+        let manifest = generate_incorporation(&gate, &[pkg, zlib])
+            .parse::<Manifest>()
+            .unwrap();
+
+        assert_eq!(
+            manifest.fmri(),
+            Some("pkg:/consolidation/test/test-incorporation@0.5.11,0.5.11-2024.1.0.1")
+        );
+        assert_eq!(
+            manifest.get_attr("pkg.description"),
+            Some("Constrains the packages of the test gate to the versions of release 0.5.11")
+        );
+        let incorporated = manifest
+            .actions_of(pkg5::ActionKind::Depend)
+            .filter(|action| action.get("type") == Some("incorporate"))
+            .filter_map(|action| action.get("fmri"))
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            incorporated,
+            vec![
+                "pkg:/application/example@1.2,0.5.11-2024.1.0.3",
+                "pkg:/library/zlib@1.3.1,0.5.11-2024.1.0.2",
+            ]
+        );
+    }
+
+    const SITE_CONFIG: &str = r#"
+name "system/site-config"
+project-name "site-config"
+summary "Site configuration"
+classification "System/Core"
+project-url "https://example.org"
+license-file "COPYING"
+license "MIT"
+no-build {
+    install src="files/motd" target="etc/motd" name="motd"
+}
+"#;
+
+    #[test]
+    fn packs_and_publishes_without_sources() -> Result<()> {
+        let dir = test_dir("ips-without-sources");
+        let bundle_dir = dir.join("site-config");
+        std::fs::create_dir_all(&bundle_dir).into_diagnostic()?;
+        std::fs::write(bundle_dir.join("package.kdl"), SITE_CONFIG).into_diagnostic()?;
+        std::fs::write(bundle_dir.join("COPYING"), "MIT License\n").into_diagnostic()?;
+        let pkg = Bundle::open_local(&bundle_dir)?;
+
+        let wks = Workspace::new(dir.join("wks"))?;
+        let proto_dir = wks.get_or_create_prototype_dir()?;
+        std::fs::create_dir_all(proto_dir.join("etc")).into_diagnostic()?;
+        std::fs::write(proto_dir.join("etc/motd"), "welcome\n").into_diagnostic()?;
+
+        run_generate_filelist(&wks, &pkg, true)?;
+        run_mogrify(&wks, &pkg, None, &dir, true)?;
+        let manifest =
+            Manifest::parse_file(wks.get_or_create_manifest_dir()?.join("mogrified.mog"))?;
+        assert_eq!(manifest.get_attr("info.source-url"), None);
+
+        // The license of a package without sources is taken from the bundle
+        let repo_dir = dir.join("repo");
+        let repo = FileRepository::create(&repo_dir)?;
+        repo.add_publisher("test.org")?;
+        publish_to_repo(&wks, &pkg, "test.org", &repo_dir, None, true)?;
+        let packages = repo.packages("test.org")?;
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "system/site-config");
+        Ok(())
+    }
+
+    #[test]
+    fn package_manifest_uses_fmri_version() {
+        let (gate, pkg) = fixture("ips-package-manifest");
+        let manifest = render_package_manifest(&pkg, Some(&gate)).unwrap();
+        assert!(manifest
+            .contains("set name=pkg.fmri value=pkg:/application/example@1.2,0.5.11-2024.1.0.3\n"));
+        assert!(manifest.contains(
+            "set name=info.source-url value=\"https://example.org/example-1.2.tar.gz\"\n"
+        ));
+        assert!(!manifest.contains("developer/meson"));
+
+        let manifest = render_package_manifest(&pkg, None).unwrap();
+        assert!(manifest
+            .contains("set name=pkg.fmri value=pkg:/application/example@1.2,0.5.11-2023.0.0.3\n"));
+    }
+}
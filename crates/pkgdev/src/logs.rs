@@ -0,0 +1,91 @@
+use std::{
+    fs::{read_to_string, OpenOptions},
+    io::Write,
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+use crate::workspace::Workspace;
+use miette::{Diagnostic, IntoDiagnostic, Result};
+use thiserror::Error;
+
+/// How many lines of the log are shown when a step fails.
+const TAIL_LINES: usize = 20;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("the {step} step of {package} failed")]
+#[diagnostic(
+    code(pkgdev::step_failed),
+    help("the full output is in {log}, it ended with:\n{tail}")
+)]
+pub(crate) struct StepFailedError {
+    step: String,
+    package: String,
+    log: String,
+    tail: String,
+    #[source]
+    cause: Box<dyn std::error::Error + Send + Sync>,
+}
+
+/// Wraps the error of a failed step so the end of its log gets shown with it.
+pub(crate) fn step_failed(
+    wks: &Workspace,
+    package: &str,
+    step: &str,
+    err: miette::Report,
+) -> miette::Report {
+    let Some(log) = wks.get_step_log() else {
+        return err;
+    };
+
+    miette::Report::new(StepFailedError {
+        step: step.to_owned(),
+        package: package.to_owned(),
+        log: log.display().to_string(),
+        tail: tail(log, TAIL_LINES).unwrap_or_default(),
+        cause: err.into(),
+    })
+}
+
+/// Sends stdout and stderr of the command into the log of the current step.
+/// Without a step log the command keeps writing to the terminal.
+pub(crate) fn log_output(wks: &Workspace, cmd: &mut Command) -> Result<()> {
+    if let Some(log_path) = wks.get_step_log() {
+        let mut log = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(log_path)
+            .into_diagnostic()?;
+        writeln!(log, "$ {:?}", cmd).into_diagnostic()?;
+        cmd.stdout(log.try_clone().into_diagnostic()?);
+        cmd.stderr(log);
+    }
+    Ok(())
+}
+
+/// Like [`log_output`] but only for stderr, for commands whose stdout is
+/// piped into another one.
+pub(crate) fn log_errors(wks: &Workspace, cmd: &mut Command) -> Result<()> {
+    if let Some(log_path) = wks.get_step_log() {
+        let mut log = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(log_path)
+            .into_diagnostic()?;
+        writeln!(log, "$ {:?}", cmd).into_diagnostic()?;
+        cmd.stderr(log);
+    }
+    Ok(())
+}
+
+pub(crate) fn run_logged(wks: &Workspace, cmd: &mut Command) -> Result<ExitStatus> {
+    log_output(wks, cmd)?;
+    cmd.status().into_diagnostic()
+}
+
+pub(crate) fn tail<P: AsRef<Path>>(path: P, lines: usize) -> Result<String> {
+    let contents = read_to_string(path.as_ref()).into_diagnostic()?;
+    let all_lines = contents.lines().collect::<Vec<&str>>();
+    let start = all_lines.len().saturating_sub(lines);
+    Ok(all_lines[start..].join("\n"))
+}
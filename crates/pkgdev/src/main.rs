@@ -1,24 +1,36 @@
 mod build;
+mod cache;
 mod commands;
 mod compile;
 mod config;
 mod download;
 mod forge;
+mod gate_lock;
 mod install;
 mod ips;
+mod logs;
+mod patch;
 mod path;
+mod sandbox;
+mod snapshot;
+mod staging;
+mod state;
 mod tarball;
 mod unpack;
+mod vendor;
 mod workspace;
 
 use crate::config::Settings;
-use bundle::{Bundle, SourceSection};
+use bundle::{Bundle, PackageLock, SourceSection};
 use clap::{Parser, Subcommand, ValueEnum};
 use commands::{handle_command, workspace::handle_workspace, ShellCommands};
 use gate::Gate;
 use miette::{IntoDiagnostic, Result, WrapErr};
 use rustyline::error::ReadlineError;
+use state::BuildState;
 use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
     fs::create_dir_all,
     io::Write,
     path::{Path, PathBuf},
@@ -84,13 +96,153 @@ enum Command {
         #[arg(long, default_value = "false")]
         archive_clean: bool,
 
+        /// Use archives that have neither a checksum nor a signature to verify them with
+        #[arg(long, default_value = "false")]
+        allow_unverified: bool,
+
         #[arg(short = 'I', long = "include")]
         transform_include_dir: Option<PathBuf>,
+
+        /// Use the builtin implementations of the pkg(5) tools instead of the illumos binaries
+        #[arg(long, default_value = "false")]
+        native: bool,
+
+        /// Build and publish every package of the gate in the order of their dependencies
+        #[arg(
+            long,
+            default_value = "false",
+            requires = "gate",
+            conflicts_with = "package"
+        )]
+        all: bool,
+
+        /// Continue a previous build in the workspace skipping all steps that completed for the
+        /// unchanged bundle
+        #[arg(long, default_value = "false", conflicts_with = "all")]
+        resume: bool,
+
+        /// Start the build at this step. The steps before it must have completed for the unchanged
+        /// bundle
+        #[arg(long, conflicts_with_all = ["all", "resume"])]
+        from_step: Option<BuildSteps>,
+
+        /// Build without network access taking all sources from this directory created by
+        /// pkgdev vendor
+        #[arg(long, value_name = "VENDOR_DIR")]
+        offline: Option<PathBuf>,
+
+        /// Build exactly the sources recorded in the lock file of the gate and fail if the
+        /// packages changed since
+        #[arg(long, default_value = "false", requires = "gate")]
+        locked: bool,
+
+        /// Cross compile for this target of the gate
+        #[arg(long, requires = "gate")]
+        target: Option<String>,
+
+        /// Where the tools of the build step run. Defaults to namespaces on Linux, host has to be
+        /// chosen explicitly there to build without isolation
+        #[arg(long, value_enum, default_value_t = sandbox::SandboxBackend::default())]
+        sandbox: sandbox::SandboxBackend,
+
+        /// Block network access during the build step, needs a sandbox that can isolate the network.
+        /// Implied by --offline
+        #[arg(long, default_value = "false")]
+        no_network: bool,
+
+        /// Stop after unpacking and snapshot the sources to edit them. With the name of a patch of
+        /// the package it is taken out of the sources first so pkgdev refresh can update it
+        #[arg(
+            long,
+            value_name = "PATCH",
+            num_args = 0..=1,
+            default_missing_value = "",
+            conflicts_with_all = ["all", "stop_on_step"]
+        )]
+        edit: Option<String>,
+    },
+    /// Write the changes made to the sources since pkgdev build --edit as a patch into the bundle
+    Refresh {
+        /// The file to write the patch to relative to the bundle. Defaults to the patch given to
+        /// --edit
+        patch: Option<String>,
+    },
+    /// Record the sources of every package of a gate in its lock file or show how they changed
+    Lock {
+        #[arg(long, short)]
+        gate: PathBuf,
+
+        #[arg(short = 'I', long = "include")]
+        transform_include_dir: Option<PathBuf>,
+
+        /// Only compare the current sources against the lock file
+        #[arg(long, default_value = "false")]
+        diff: bool,
+
+        /// Use archives that have neither a checksum nor a signature to verify them with
+        #[arg(long, default_value = "false")]
+        allow_unverified: bool,
+    },
+    /// Publish the incorporation package locking every package of a gate to the version the gate
+    /// builds it at
+    Incorporation {
+        #[arg(long, short)]
+        gate: PathBuf,
+
+        /// Use the builtin implementations of the pkg(5) tools instead of the illumos binaries
+        #[arg(long, default_value = "false")]
+        native: bool,
+
+        /// Only print the manifest of the incorporation
+        #[arg(long, default_value = "false")]
+        print: bool,
+    },
+    /// Download all sources of a package or of every package of a gate into a directory so they
+    /// can be built without network access
+    Vendor {
+        #[arg(long, short)]
+        gate: Option<PathBuf>,
+
+        package: Option<String>,
+
+        /// Vendor the sources of every package of the gate
+        #[arg(
+            long,
+            default_value = "false",
+            requires = "gate",
+            conflicts_with = "package"
+        )]
+        all: bool,
+
+        /// The directory to put the sources in
+        #[arg(long, short)]
+        output: PathBuf,
+
+        /// Use archives that have neither a checksum nor a signature to verify them with
+        #[arg(long, default_value = "false")]
+        allow_unverified: bool,
     },
     Forge {
         #[command(subcommand)]
         cmd: forge::ForgeCLI,
     },
+    /// List the logs of past build steps or show the log of a step
+    Log {
+        /// The package to show the logs of. Lists all packages with logs if not given
+        package: Option<String>,
+
+        /// The step to show the log of. Lists the logged steps of the package if not given
+        step: Option<BuildSteps>,
+
+        /// Only show the last lines of the log
+        #[arg(long, short = 'n')]
+        tail: Option<usize>,
+    },
+    /// Inspect and maintain the archive cache shared by all workspaces
+    Cache {
+        #[command(subcommand)]
+        cmd: cache::CacheCommand,
+    },
     /// Show the repository information
     Info {
         /// If set will save the information in the directory of the package.kdl file as json
@@ -105,23 +257,6 @@ enum Command {
     },
 }
 
-#[derive(Debug, ValueEnum, Clone)]
-pub enum CrossTriple {
-    Arm,
-    Riscv,
-    Sparc,
-}
-
-impl ToString for CrossTriple {
-    fn to_string(&self) -> String {
-        match self {
-            CrossTriple::Arm => String::from("arm"),
-            CrossTriple::Riscv => String::from("riscv"),
-            CrossTriple::Sparc => String::from("sparc"),
-        }
-    }
-}
-
 #[derive(Debug, Clone, ValueEnum, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum BuildSteps {
     Download,
@@ -131,6 +266,18 @@ pub(crate) enum BuildSteps {
     Publish,
 }
 
+impl Display for BuildSteps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildSteps::Download => write!(f, "download"),
+            BuildSteps::Unpack => write!(f, "unpack"),
+            BuildSteps::Build => write!(f, "build"),
+            BuildSteps::Pack => write!(f, "pack"),
+            BuildSteps::Publish => write!(f, "publish"),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 enum PortsError {
     #[error("can not get basename of the package: does it exist?")]
@@ -145,6 +292,17 @@ pub fn derive_source_name(package_name: String, src: &SourceSection) -> String {
     }
 }
 
+/// A fresh directory below the temporary directory for a test.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pkgdev-{}-{}", name, std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
 fn main() -> Result<()> {
     let cli: Cli = Cli::parse();
     let settings = Settings::open()?;
@@ -300,12 +458,13 @@ fn main() -> Result<()> {
             Ok(())
         }
         Command::Build {
-            stop_on_step,
+            ref gate,
+            ref package,
             no_clean,
-            archive_clean,
-            gate,
-            package,
-            transform_include_dir,
+            all,
+            resume,
+            ref edit,
+            ..
         } => {
             let wks = if let Some(wks_path) = cli.workspace {
                 settings.get_workspace_from(&wks_path)?
@@ -313,166 +472,292 @@ fn main() -> Result<()> {
                 settings.get_current_wks()?
             };
 
-            let transform_include_dir = transform_include_dir.map(|p| match p.canonicalize() {
-                Ok(p) => p,
-                Err(e) => {
-                    println!(
-                        "could not cannonicalize {} due to {} continuing ignoring and continuing",
-                        p.display(),
-                        e
-                    );
-                    p
-                }
-            });
-
-            if !no_clean {
-                std::fs::remove_dir_all(wks.get_or_create_download_dir()?)
-                    .into_diagnostic()
-                    .wrap_err("could not clean the download directory")?;
-                std::fs::remove_dir_all(wks.get_or_create_build_dir()?)
-                    .into_diagnostic()
-                    .wrap_err("could not clean the build directory")?;
-                std::fs::remove_dir_all(wks.get_or_create_prototype_dir()?)
-                    .into_diagnostic()
-                    .wrap_err("could not clean the prototype directory")?;
-                std::fs::remove_dir_all(wks.get_or_create_manifest_dir()?)
-                    .into_diagnostic()
-                    .wrap_err("could not clean the manifest directory")?;
-            }
+            let options = build_options(&cli.command)?;
 
-            let (package_bundle, gate_data) = if let Some(gate_path) = gate {
-                let gate_data = gate::Gate::new(&gate_path).wrap_err("could not open gate data")?;
+            if all {
+                let gate_path = gate
+                    .clone()
+                    .ok_or(miette::miette!("building all packages needs a gate"))?;
+                return build_gate(&wks, &settings, &gate_path, no_clean, &options);
+            }
 
-                let path = if let Some(package) = &package {
-                    let name = if package.contains("/") {
-                        package.rsplit_once('/').unwrap().1
-                    } else {
-                        package.as_str()
-                    };
-                    gate_path
-                        .parent()
-                        .unwrap_or(Path::new("./"))
-                        .join("packages")
-                        .join(name)
-                } else {
-                    Path::new("./").to_path_buf()
-                };
+            if !no_clean && !resume && options.from_step.is_none() {
+                clean_workspace(&wks)?;
+            }
 
-                let path = path.canonicalize().into_diagnostic().wrap_err(format!(
-                    "Can not canonicalize path to package {}",
-                    path.display()
-                ))?;
+            let (mut package_bundle, gate_data) = open_package(gate.clone(), package.clone())?;
 
-                let mut package_bundle =
-                    Bundle::open_local(path).wrap_err("could not open package.kdl of package")?;
+            if let Some(gate_data) = gate_data.as_ref().filter(|_| options.locked) {
+                let lock = gate_lock::GateLock::load(&gate_data.get_lock_path())?;
+                gate_lock::check_transforms(&lock, options.transform_include_dir.as_deref())?;
+                gate_lock::check_and_pin(&lock, &mut package_bundle)?;
+            }
 
-                if let Some(package) = &package {
-                    if let Some(gate_package) = gate_data.get_package(package.as_str()) {
-                        package_bundle
-                            .package_document
-                            .merge_into_mut(&gate_package)?;
-                    }
-                }
+            build_package(&wks, &settings, &package_bundle, gate_data, &options)?;
 
-                (package_bundle, Some(gate_data))
+            if let Some(edit) = edit {
+                let refresh_patch = Some(edit.as_str()).filter(|p| !p.is_empty());
+                snapshot::take(&wks, &package_bundle, refresh_patch)?;
+            }
+            Ok(())
+        }
+        Command::Refresh { patch } => {
+            let wks = if let Some(wks_path) = cli.workspace {
+                settings.get_workspace_from(&wks_path)?
             } else {
-                let path = if let Some(package) = package {
-                    let name = if package.contains("/") {
-                        package.split_once('/').unwrap().1
-                    } else {
-                        package.as_str()
-                    };
-                    Path::new("./packages").join(name)
-                } else {
-                    Path::new("./").to_path_buf()
-                };
-
-                let path = path.canonicalize().into_diagnostic().wrap_err(format!(
-                    "Can not canonicalize path to package {}",
-                    path.display()
-                ))?;
+                settings.get_current_wks()?
+            };
 
-                (
-                    Bundle::open_local(path).wrap_err("could not open package.kdl of package")?,
-                    None,
-                )
+            let patch_path = snapshot::refresh(&wks, patch)?;
+            println!("Wrote patch {}", patch_path.display());
+            Ok(())
+        }
+        Command::Forge { cmd } => forge::handle_forge(&cmd),
+        Command::Log {
+            package,
+            step,
+            tail,
+        } => {
+            let wks = if let Some(wks_path) = cli.workspace {
+                settings.get_workspace_from(&wks_path)?
+            } else {
+                settings.get_current_wks()?
             };
 
-            let sources: Vec<SourceSection> = package_bundle.package_document.sources.clone();
+            let Some(package) = package else {
+                let mut packages = std::fs::read_dir(wks.get_or_create_log_dir()?)
+                    .into_diagnostic()?
+                    .map(|e| e.map(|e| e.file_name().to_string_lossy().to_string()))
+                    .collect::<std::io::Result<Vec<String>>>()
+                    .into_diagnostic()?;
+                packages.sort();
+                for package in packages {
+                    println!("{}", package);
+                }
+                return Ok(());
+            };
 
-            download::download_and_verify(&wks, sources.as_slice(), archive_clean)
-                .wrap_err("download and verify failed")?;
+            let log_dir = wks.get_package_log_dir(&package)?;
+            if !log_dir.exists() {
+                return Err(miette::miette!(
+                    "no logs for package {} in workspace {}",
+                    package,
+                    wks.get_name()
+                ));
+            }
 
-            if let Some(stop_on_step) = &stop_on_step {
-                if stop_on_step == &BuildSteps::Download {
-                    return Ok(());
+            let Some(step) = step else {
+                for step in state::BUILD_STEPS.iter() {
+                    let log_path = log_dir.join(format!("{}.log", step));
+                    if log_path.exists() {
+                        println!("{}\t{}", step, log_path.display());
+                    }
                 }
+                return Ok(());
+            };
+
+            let log_path = log_dir.join(format!("{}.log", step));
+            if !log_path.exists() {
+                return Err(miette::miette!(
+                    "no log of the {} step of {}",
+                    step,
+                    package
+                ));
             }
 
-            unpack::unpack_sources(
+            let contents = if let Some(lines) = tail {
+                logs::tail(&log_path, lines)?
+            } else {
+                std::fs::read_to_string(&log_path).into_diagnostic()?
+            };
+            println!("{}", contents);
+            Ok(())
+        }
+        Command::Lock {
+            gate,
+            transform_include_dir,
+            diff,
+            allow_unverified,
+        } => {
+            let wks = if let Some(wks_path) = cli.workspace {
+                settings.get_workspace_from(&wks_path)?
+            } else {
+                settings.get_current_wks()?
+            };
+
+            let gate_data = Gate::new(&gate).wrap_err("could not open gate data")?;
+            let packages = open_gate_bundles(&gate, &gate_data)?;
+            let transform_include_dir = transform_include_dir
+                .map(|p| p.canonicalize().into_diagnostic())
+                .transpose()?;
+            let current = gate_lock::generate(
                 &wks,
-                package_bundle.package_document.name.clone(),
-                package_bundle.get_path(),
-                sources.as_slice(),
-            )
-            .wrap_err("unpack step failed")?;
+                &gate_data,
+                &packages,
+                transform_include_dir.as_deref(),
+                allow_unverified,
+            )?;
+
+            let lock_path = gate_data.get_lock_path();
+            if !diff {
+                current.save(&lock_path)?;
+                println!(
+                    "Locked {} packages in {}",
+                    current.packages.len(),
+                    lock_path.display()
+                );
+                return Ok(());
+            }
 
-            if let Some(stop_on_step) = &stop_on_step {
-                if stop_on_step == &BuildSteps::Unpack {
-                    return Ok(());
-                }
+            let changes = gate_lock::diff(&gate_lock::GateLock::load(&lock_path)?, &current)?;
+            if changes.is_empty() {
+                println!("{} is up to date", lock_path.display());
+                return Ok(());
+            }
+            for change in &changes {
+                println!("{}", change);
+            }
+            Err(miette::miette!(
+                "{} changes since {} was written",
+                changes.len(),
+                lock_path.display()
+            ))
+        }
+        Command::Incorporation {
+            gate,
+            native,
+            print,
+        } => {
+            let gate_data = Gate::new(&gate).wrap_err("could not open gate data")?;
+            let packages = open_gate_bundles(&gate, &gate_data)?;
+            if print {
+                print!("{}", ips::generate_incorporation(&gate_data, &packages));
+                return Ok(());
             }
 
-            build::build_package_sources(&wks, &package_bundle, &settings)
-                .wrap_err("configure step failed")?;
+            let wks = if let Some(wks_path) = cli.workspace {
+                settings.get_workspace_from(&wks_path)?
+            } else {
+                settings.get_current_wks()?
+            };
+            ips::publish_incorporation(&wks, &gate_data, &packages, native)
+                .wrap_err("publishing the incorporation failed")
+        }
+        Command::Vendor {
+            gate,
+            package,
+            all,
+            output,
+            allow_unverified,
+        } => {
+            let wks = if let Some(wks_path) = cli.workspace {
+                settings.get_workspace_from(&wks_path)?
+            } else {
+                settings.get_current_wks()?
+            };
+
+            let (packages, gate_data) = if all {
+                let gate_path =
+                    gate.ok_or(miette::miette!("vendoring all packages needs a gate"))?;
+                let gate_data = Gate::new(&gate_path).wrap_err("could not open gate data")?;
+                (open_gate_bundles(&gate_path, &gate_data)?, Some(gate_data))
+            } else {
+                let (package_bundle, gate_data) = open_package(gate, package)?;
+                (vec![package_bundle], gate_data)
+            };
 
-            if let Some(stop_on_step) = &stop_on_step {
-                if stop_on_step == &BuildSteps::Build {
-                    return Ok(());
+            vendor::vendor_packages(
+                &wks,
+                &packages,
+                gate_data.as_ref(),
+                &output,
+                allow_unverified,
+            )?;
+            println!(
+                "Vendored the sources of {} packages into {}",
+                packages.len(),
+                output.display()
+            );
+            Ok(())
+        }
+        Command::Cache { cmd } => match cmd {
+            cache::CacheCommand::List => {
+                let index = cache::CacheIndex::load()?;
+                let store_dir = cache::get_or_create_store_dir()?;
+                for digest in cache::cached_digests()? {
+                    let size = std::fs::metadata(store_dir.join(&digest))
+                        .into_diagnostic()?
+                        .len();
+                    let urls = index
+                        .archives
+                        .iter()
+                        .filter(|(_, entry)| entry.sha256 == digest)
+                        .map(|(url, _)| url.as_str())
+                        .collect::<Vec<&str>>();
+                    println!("{}\t{}\t{}", digest, size, urls.join(" "));
                 }
+                Ok(())
             }
+            cache::CacheCommand::Verify { remove } => {
+                let broken = cache::verify()?;
+                if broken.is_empty() {
+                    println!("All cached archives match their checksums");
+                    return Ok(());
+                }
 
-            if let Some(gate_data) = gate_data {
-                if let Some(distribution) = &gate_data.distribution {
-                    match distribution.distribution_type {
-                        gate::DistributionType::Tarbball => {
-                            tarball::make_release_tarball(&wks, &package_bundle)?;
-                        }
-                        gate::DistributionType::IPS => {
-                            run_ips_actions(
-                                &wks,
-                                &package_bundle,
-                                Some(gate_data),
-                                transform_include_dir,
-                            )?;
-                        }
-                    }
+                for digest in &broken {
+                    println!("{} does not match its checksum", digest);
+                }
+                if remove {
+                    let keep = cache::cached_digests()?
+                        .into_iter()
+                        .filter(|d| !broken.contains(d))
+                        .collect::<HashSet<String>>();
+                    cache::prune(Some(&keep), false)?;
+                    println!("Removed {} broken archives", broken.len());
+                    Ok(())
                 } else {
-                    run_ips_actions(
-                        &wks,
-                        &package_bundle,
-                        Some(gate_data),
-                        transform_include_dir,
-                    )?;
+                    Err(miette::miette!(
+                        "{} cached archives are broken, remove them with --remove",
+                        broken.len()
+                    ))
                 }
-            } else {
-                run_ips_actions(
-                    &wks,
-                    &package_bundle,
-                    gate_data.clone(),
-                    transform_include_dir,
-                )?;
             }
+            cache::CacheCommand::Prune { gate, dry_run } => {
+                let keep = if let Some(gate_path) = gate {
+                    let gate_data = Gate::new(&gate_path).wrap_err("could not open gate data")?;
+                    let mut keep = HashSet::new();
+                    for package_bundle in open_gate_bundles(&gate_path, &gate_data)? {
+                        for archive in download::archives_of(&package_bundle) {
+                            if let Some(cached_path) = cache::lookup(archive)? {
+                                if let Some(digest) = cached_path.file_name() {
+                                    keep.insert(digest.to_string_lossy().to_string());
+                                }
+                            }
+                        }
+                    }
+                    Some(keep)
+                } else {
+                    None
+                };
 
-            if let Some(stop_on_step) = &stop_on_step {
-                if stop_on_step == &BuildSteps::Pack {
-                    return Ok(());
+                let removed = cache::prune(keep.as_ref(), dry_run)?;
+                for digest in &removed {
+                    println!("{}", digest);
                 }
+                if dry_run {
+                    println!("Would remove {} archives", removed.len());
+                } else {
+                    println!("Removed {} archives", removed.len());
+                }
+                Ok(())
             }
-
-            Ok(())
-        }
-        Command::Forge { cmd } => forge::handle_forge(&cmd),
+            cache::CacheCommand::Import { path, url } => {
+                let digest = cache::import(&path, url.as_deref())?;
+                println!("Imported {} as {}", path.display(), digest);
+                Ok(())
+            }
+        },
         Command::Config { command } => {
             let mut cfg = Settings::open()?;
             match command {
@@ -614,22 +899,588 @@ fn main() -> Result<()> {
     }
 }
 
-fn run_ips_actions(
+#[derive(Clone)]
+struct BuildOptions {
+    stop_on_step: Option<BuildSteps>,
+    from_step: Option<BuildSteps>,
+    resume: bool,
+    archive_clean: bool,
+    allow_unverified: bool,
+    transform_include_dir: Option<PathBuf>,
+    /// The directory transform includes are read from when packing
+    include_dir: PathBuf,
+    native: bool,
+    vendor_dir: Option<PathBuf>,
+    locked: bool,
+    target: Option<String>,
+    sandbox: sandbox::SandboxBackend,
+    network: bool,
+}
+
+/// Collects the options of a build command. Building offline from a vendor
+/// directory always disables network access in the build sandbox.
+fn build_options(command: &Command) -> miette::Result<BuildOptions> {
+    let Command::Build {
+        stop_on_step,
+        archive_clean,
+        allow_unverified,
+        gate,
+        transform_include_dir,
+        native,
+        resume,
+        from_step,
+        offline,
+        locked,
+        target,
+        sandbox,
+        no_network,
+        edit,
+        ..
+    } = command
+    else {
+        return Err(miette::miette!("not a build command"));
+    };
+
+    let transform_include_dir = transform_include_dir
+        .as_ref()
+        .map(|p| match p.canonicalize() {
+            Ok(p) => p,
+            Err(e) => {
+                println!(
+                    "could not cannonicalize {} due to {} continuing ignoring and continuing",
+                    p.display(),
+                    e
+                );
+                p.clone()
+            }
+        });
+
+    let include_dir = resolve_include_dir(transform_include_dir.as_deref(), gate.as_deref())?;
+
+    Ok(BuildOptions {
+        stop_on_step: if edit.is_some() {
+            Some(BuildSteps::Unpack)
+        } else {
+            stop_on_step.clone()
+        },
+        from_step: from_step.clone(),
+        resume: *resume,
+        archive_clean: *archive_clean,
+        allow_unverified: *allow_unverified,
+        transform_include_dir,
+        include_dir,
+        native: *native,
+        vendor_dir: offline.clone(),
+        locked: *locked,
+        target: target.clone(),
+        sandbox: *sandbox,
+        network: !no_network && offline.is_none(),
+    })
+}
+
+/// Resolves the directory transform includes are read from before a build
+/// step changes the current directory. Without one given on the command
+/// line the includes are found next to the gate file.
+fn resolve_include_dir(
+    transform_include_dir: Option<&Path>,
+    gate: Option<&Path>,
+) -> miette::Result<PathBuf> {
+    let dir = match (transform_include_dir, gate) {
+        (Some(dir), _) => dir.to_path_buf(),
+        (None, Some(gate)) => gate
+            .canonicalize()
+            .into_diagnostic()
+            .wrap_err(format!("could not open gate file {}", gate.display()))?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        (None, None) => std::env::current_dir().into_diagnostic()?,
+    };
+    dir.canonicalize().into_diagnostic().wrap_err(format!(
+        "could not find the transform include directory {}",
+        dir.display()
+    ))
+}
+
+fn clean_workspace(wks: &Workspace) -> miette::Result<()> {
+    std::fs::remove_dir_all(wks.get_or_create_download_dir()?)
+        .into_diagnostic()
+        .wrap_err("could not clean the download directory")?;
+    std::fs::remove_dir_all(wks.get_or_create_build_dir()?)
+        .into_diagnostic()
+        .wrap_err("could not clean the build directory")?;
+    std::fs::remove_dir_all(wks.get_or_create_prototype_dir()?)
+        .into_diagnostic()
+        .wrap_err("could not clean the prototype directory")?;
+    std::fs::remove_dir_all(wks.get_or_create_manifest_dir()?)
+        .into_diagnostic()
+        .wrap_err("could not clean the manifest directory")?;
+    let state_path = wks.get_build_state_path();
+    if state_path.exists() {
+        std::fs::remove_file(state_path)
+            .into_diagnostic()
+            .wrap_err("could not remove the build state")?;
+    }
+    Ok(())
+}
+
+/// Packages of a gate live in the packages directory next to the gate file
+/// under the last segment of their name.
+fn gate_package_path(gate_path: &Path, package: &str) -> PathBuf {
+    let name = if package.contains("/") {
+        package.rsplit_once('/').unwrap().1
+    } else {
+        package
+    };
+    gate_path
+        .parent()
+        .unwrap_or(Path::new("./"))
+        .join("packages")
+        .join(name)
+}
+
+fn build_package(
+    wks: &Workspace,
+    settings: &Settings,
+    package_bundle: &Bundle,
+    gate_data: Option<Gate>,
+    options: &BuildOptions,
+) -> miette::Result<()> {
+    let name = package_bundle.package_document.name.as_str();
+    let fingerprints = state::step_fingerprints(package_bundle, gate_data.as_ref(), options)?;
+    let mut build_state = if options.resume || options.from_step.is_some() {
+        BuildState::load(wks)?
+    } else {
+        BuildState::default()
+    };
+
+    let mut rerun = false;
+    for step in state::BUILD_STEPS.iter() {
+        let fingerprint = &fingerprints[step];
+        let skip = if rerun {
+            false
+        } else if let Some(from_step) = &options.from_step {
+            if step < from_step && !build_state.is_complete(name, step, fingerprint) {
+                return Err(miette::miette!(
+                    "the {} step has not completed for the current bundle of {}, use --resume to rerun all steps that are out of date",
+                    step,
+                    name
+                ));
+            }
+            step < from_step
+        } else {
+            options.resume && build_state.is_complete(name, step, fingerprint)
+        };
+
+        if skip {
+            println!("Skipping {} step of {} as it is up to date", step, name);
+        } else {
+            rerun = true;
+            build_state.invalidate_from(step);
+            build_state.save(wks)?;
+            let step_wks = wks.with_step_log(name, &step.to_string())?;
+            println!(
+                "Running {} step of {}, logging to {}",
+                step,
+                name,
+                step_wks.get_step_log().unwrap().display()
+            );
+            run_build_step(
+                step,
+                &step_wks,
+                settings,
+                package_bundle,
+                gate_data.clone(),
+                options,
+            )
+            .map_err(|err| logs::step_failed(&step_wks, name, &step.to_string(), err))?;
+            build_state.complete(name, step, fingerprint);
+            build_state.save(wks)?;
+        }
+
+        if options.stop_on_step.as_ref() == Some(step) {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+fn run_build_step(
+    step: &BuildSteps,
+    wks: &Workspace,
+    settings: &Settings,
+    package_bundle: &Bundle,
+    gate_data: Option<Gate>,
+    options: &BuildOptions,
+) -> miette::Result<()> {
+    let sources: Vec<SourceSection> = package_bundle.package_document.sources.clone();
+    let distribution_type = gate_data
+        .as_ref()
+        .and_then(|g| g.distribution.as_ref())
+        .map(|d| d.distribution_type.clone())
+        .unwrap_or_default();
+
+    match step {
+        BuildSteps::Download => match &options.vendor_dir {
+            Some(vendor_dir) => {
+                vendor::resolve_sources(package_bundle, vendor_dir, options.archive_clean)
+                    .wrap_err("resolving sources from the vendor directory failed")
+            }
+            None => download::download_and_verify(
+                wks,
+                package_bundle,
+                gate_data.as_ref(),
+                options.archive_clean,
+                options.allow_unverified,
+            )
+            .wrap_err("download and verify failed"),
+        },
+        BuildSteps::Unpack => unpack::unpack_sources(
+            wks,
+            package_bundle.package_document.name.clone(),
+            package_bundle.get_path(),
+            sources.as_slice(),
+            &PackageLock::open(package_bundle.get_lock_path())?,
+        )
+        .wrap_err("unpack step failed"),
+        BuildSteps::Build => {
+            let mut sandbox =
+                sandbox::Sandbox::new(wks, settings, options.sandbox, options.network)?;
+            if let Some(target) = &options.target {
+                let target = gate_data
+                    .as_ref()
+                    .and_then(|g| g.get_target(target))
+                    .ok_or(miette::miette!("the gate has no target {}", target))?;
+                sandbox.set_target(target);
+            }
+            staging::stage_dependencies(wks, package_bundle, gate_data.as_ref())
+                .wrap_err("staging the build dependencies failed")?
+                .apply(&mut sandbox);
+            build::build_package_sources(wks, package_bundle, &sandbox)
+                .wrap_err("configure step failed")
+        }
+        BuildSteps::Pack => match distribution_type {
+            gate::DistributionType::Tarbball => tarball::make_release_tarball(wks, package_bundle),
+            gate::DistributionType::IPS => run_ips_pack(
+                wks,
+                package_bundle,
+                gate_data,
+                &options.include_dir,
+                options.native,
+            ),
+        },
+        BuildSteps::Publish => match distribution_type {
+            gate::DistributionType::Tarbball => Ok(()),
+            gate::DistributionType::IPS => {
+                run_ips_publish(wks, package_bundle, gate_data, options.native)
+            }
+        },
+    }
+}
+
+/// Opens the package given on the command line either from the packages
+/// directory of the gate with the gate data merged into it or from the
+/// current directory.
+fn open_package(
+    gate: Option<PathBuf>,
+    package: Option<String>,
+) -> miette::Result<(Bundle, Option<Gate>)> {
+    let (package_bundle, gate_data) = if let Some(gate_path) = gate {
+        let gate_data = gate::Gate::new(&gate_path).wrap_err("could not open gate data")?;
+
+        let path = if let Some(package) = &package {
+            gate_package_path(&gate_path, package)
+        } else {
+            Path::new("./").to_path_buf()
+        };
+
+        let path = path.canonicalize().into_diagnostic().wrap_err(format!(
+            "Can not canonicalize path to package {}",
+            path.display()
+        ))?;
+
+        let mut package_bundle =
+            Bundle::open_local(path).wrap_err("could not open package.kdl of package")?;
+
+        if let Some(package) = &package {
+            if let Some(gate_package) = gate_data.get_package(package.as_str()) {
+                package_bundle
+                    .package_document
+                    .merge_into_mut(&gate_package)?;
+            }
+        }
+
+        (package_bundle, Some(gate_data))
+    } else {
+        let path = if let Some(package) = package {
+            let name = if package.contains("/") {
+                package.split_once('/').unwrap().1
+            } else {
+                package.as_str()
+            };
+            Path::new("./packages").join(name)
+        } else {
+            Path::new("./").to_path_buf()
+        };
+
+        let path = path.canonicalize().into_diagnostic().wrap_err(format!(
+            "Can not canonicalize path to package {}",
+            path.display()
+        ))?;
+
+        (
+            Bundle::open_local(path).wrap_err("could not open package.kdl of package")?,
+            None,
+        )
+    };
+
+    Ok((package_bundle, gate_data))
+}
+
+/// Opens the bundles of all packages of the gate with the gate data merged
+/// into them.
+fn open_gate_bundles(gate_path: &Path, gate_data: &Gate) -> miette::Result<Vec<Bundle>> {
+    let mut bundles = vec![];
+    for gate_package in gate_data.packages() {
+        let path = gate_package_path(gate_path, &gate_package.name);
+        let path = path.canonicalize().into_diagnostic().wrap_err(format!(
+            "Can not canonicalize path to package {}",
+            path.display()
+        ))?;
+
+        let mut package_bundle = Bundle::open_local(path).wrap_err(format!(
+            "could not open package.kdl of package {}",
+            gate_package.name
+        ))?;
+        package_bundle
+            .package_document
+            .merge_into_mut(gate_package)?;
+        bundles.push(package_bundle);
+    }
+    Ok(bundles)
+}
+
+/// Builds every package of the gate after the gate packages it depends on.
+/// Packages are published as part of their build and their prototypes are
+/// kept so their dependents can stage them. When a package fails all
+/// packages depending on it are skipped while the rest of the gate still
+/// gets built. Once every package is published the incorporation of the
+/// gate is published with them.
+fn build_gate(
+    wks: &Workspace,
+    settings: &Settings,
+    gate_path: &Path,
+    no_clean: bool,
+    options: &BuildOptions,
+) -> miette::Result<()> {
+    let gate_data = Gate::new(gate_path).wrap_err("could not open gate data")?;
+
+    let lock = if options.locked {
+        let lock = gate_lock::GateLock::load(&gate_data.get_lock_path())?;
+        gate_lock::check_transforms(&lock, options.transform_include_dir.as_deref())?;
+        Some(lock)
+    } else {
+        None
+    };
+
+    let mut bundles: HashMap<String, Bundle> = HashMap::new();
+    let mut packages = vec![];
+    for mut package_bundle in open_gate_bundles(gate_path, &gate_data)? {
+        if let Some(lock) = &lock {
+            gate_lock::check_and_pin(lock, &mut package_bundle)?;
+        }
+        packages.push(package_bundle.package_document.clone());
+        bundles.insert(package_bundle.package_document.name.clone(), package_bundle);
+    }
+
+    let order = gate::build_order(&packages)?;
+    println!(
+        "Building {} packages in order: {}",
+        order.len(),
+        order
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    );
+
+    // Fetch the archives of all packages up front so they download in
+    // parallel. Packages whose archives fail here report it in their own
+    // download step.
+    let archives = order
+        .iter()
+        .flat_map(|package| {
+            let package_bundle = &bundles[&package.name];
+            download::archives_of(package_bundle)
+                .into_iter()
+                .map(move |archive| (package_bundle, archive))
+        })
+        .collect::<Vec<_>>();
+    if options.vendor_dir.is_none() {
+        if let Err(err) = download::fetch_archives(
+            wks,
+            &archives,
+            Some(&gate_data),
+            options.archive_clean,
+            options.allow_unverified,
+        ) {
+            println!("Warning: not all archives could be prefetched: {}", err);
+        }
+    }
+    // Archives fetched above must not be thrown away again by every package
+    let options = &BuildOptions {
+        archive_clean: options.archive_clean && options.vendor_dir.is_some(),
+        ..options.clone()
+    };
+
+    // Prototypes kept from an earlier gate build are out of date
+    let gate_protos_dir = wks.get_gate_protos_dir();
+    if gate_protos_dir.exists() {
+        std::fs::remove_dir_all(&gate_protos_dir)
+            .into_diagnostic()
+            .wrap_err("could not clean the kept prototypes")?;
+    }
+
+    let mut succeeded: Vec<String> = vec![];
+    let mut failed: Vec<String> = vec![];
+    let mut skipped: Vec<(String, String)> = vec![];
+
+    for (idx, package) in order.iter().enumerate() {
+        let blocked_by = package
+            .dependencies
+            .iter()
+            .filter(|d| bundles.contains_key(&d.name) && !succeeded.contains(&d.name))
+            .map(|d| d.name.clone())
+            .collect::<Vec<String>>();
+
+        if !blocked_by.is_empty() {
+            println!(
+                "Skipping {} as its dependencies {} were not built",
+                package.name,
+                blocked_by.join(", ")
+            );
+            skipped.push((package.name.clone(), blocked_by.join(", ")));
+            continue;
+        }
+
+        println!("Building {}", package.name);
+        if idx > 0 || !no_clean {
+            clean_workspace(wks)?;
+        }
+
+        match build_package(
+            wks,
+            settings,
+            &bundles[&package.name],
+            Some(gate_data.clone()),
+            options,
+        ) {
+            Ok(_) => {
+                staging::keep_gate_proto(wks, &bundles[&package.name])?;
+                succeeded.push(package.name.clone())
+            }
+            Err(err) => {
+                eprintln!(
+                    "{:?}",
+                    err.wrap_err(format!("building {} failed", package.name))
+                );
+                failed.push(package.name.clone());
+            }
+        }
+    }
+
+    println!("Gate build finished");
+    for name in &succeeded {
+        println!("  succeeded: {}", name);
+    }
+    for name in &failed {
+        println!("  failed:    {}", name);
+    }
+    for (name, blocked_by) in &skipped {
+        println!("  skipped:   {} (needs {})", name, blocked_by);
+    }
+
+    if !failed.is_empty() || !skipped.is_empty() {
+        return Err(miette::miette!(
+            "{} of {} packages could not be built",
+            failed.len() + skipped.len(),
+            order.len()
+        ));
+    }
+
+    // The incorporation is only published for a complete release
+    let published = matches!(options.stop_on_step, None | Some(BuildSteps::Publish));
+    let distribution_type = gate_data
+        .distribution
+        .as_ref()
+        .map(|d| d.distribution_type.clone())
+        .unwrap_or_default();
+    if published && matches!(distribution_type, gate::DistributionType::IPS) {
+        let packages = bundles.into_values().collect::<Vec<Bundle>>();
+        ips::publish_incorporation(wks, &gate_data, &packages, options.native)
+            .wrap_err("publishing the incorporation failed")?;
+    }
+
+    Ok(())
+}
+
+fn run_ips_pack(
     wks: &Workspace,
     pkg: &Bundle,
     gate_data: Option<Gate>,
-    transform_include_dir: Option<PathBuf>,
+    include_dir: &Path,
+    native: bool,
 ) -> miette::Result<()> {
-    ips::run_generate_filelist(wks, pkg).wrap_err("generating filelist failed")?;
-    ips::run_mogrify(wks, pkg, gate_data.clone(), transform_include_dir)
-        .wrap_err("mogrify failed")?;
-    ips::run_generate_pkgdepend(wks, pkg).wrap_err("failed to generate dependency entries")?;
-    ips::run_resolve_dependencies(wks, pkg).wrap_err("failed to resolve dependencies")?;
-    ips::run_lint(wks, pkg).wrap_err("lint failed")?;
+    ips::run_generate_filelist(wks, pkg, native).wrap_err("generating filelist failed")?;
+    ips::run_mogrify(wks, pkg, gate_data, include_dir, native).wrap_err("mogrify failed")?;
+    if native {
+        println!("Skipping dependency resolution and lint as they need the pkg(5) tools");
+    } else {
+        ips::run_generate_pkgdepend(wks, pkg).wrap_err("failed to generate dependency entries")?;
+        ips::run_resolve_dependencies(wks, pkg).wrap_err("failed to resolve dependencies")?;
+        ips::run_lint(wks, pkg).wrap_err("lint failed")?;
+    }
+    Ok(())
+}
 
+fn run_ips_publish(
+    wks: &Workspace,
+    pkg: &Bundle,
+    gate_data: Option<Gate>,
+    native: bool,
+) -> miette::Result<()> {
     let publisher = &gate_data.unwrap_or(Gate::default()).publisher;
-    ips::ensure_repo_with_publisher_exists(&publisher)
+    ips::ensure_repo_with_publisher_exists(wks, &publisher, native)
         .wrap_err("failed to ensure repository exists")?;
-    ips::publish_package(wks, pkg, &publisher).wrap_err("package publish failed")?;
+    ips::publish_package(wks, pkg, &publisher, native).wrap_err("package publish failed")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    fn parse_build_options(args: &[&str]) -> BuildOptions {
+        let dir = test_dir("build-options");
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy().to_string();
+
+        let mut argv = vec!["pkgdev", "build", "-I", dir.as_str()];
+        argv.extend_from_slice(args);
+        let cli = Cli::try_parse_from(argv).unwrap();
+        build_options(&cli.command).unwrap()
+    }
+
+    #[test]
+    fn offline_disables_network() {
+        let options = parse_build_options(&["--offline", "/vendor"]);
+        assert!(!options.network);
+        assert_eq!(options.vendor_dir, Some(PathBuf::from("/vendor")));
+
+        let options = parse_build_options(&[]);
+        assert!(options.network);
+        assert_eq!(options.vendor_dir, None);
+    }
+}
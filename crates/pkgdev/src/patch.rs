@@ -0,0 +1,1049 @@
+use std::{
+    fs::{read_to_string, DirBuilder},
+    path::{Path, PathBuf},
+};
+
+use miette::{Diagnostic, NamedSource, SourceSpan};
+use thiserror::Error;
+
+/// How many context lines at the start and end of a hunk may be ignored
+/// when it does not apply as is, like the default of GNU patch.
+const MAX_FUZZ: usize = 2;
+
+/// The file listing the patches of a directory in the order to apply them.
+pub(crate) const SERIES_FILE: &str = "series";
+
+#[derive(Debug, Error, Diagnostic)]
+pub(crate) enum PatchError {
+    #[error(transparent)]
+    #[diagnostic(code(pkgdev::patch::io))]
+    IOError(#[from] std::io::Error),
+
+    #[error("could not parse patch {patch}: {reason}")]
+    #[diagnostic(code(pkgdev::patch::malformed))]
+    Malformed {
+        patch: String,
+        reason: String,
+        #[source_code]
+        src: NamedSource,
+        #[label("here")]
+        span: SourceSpan,
+    },
+
+    #[error("patch {patch} does not apply to {file}")]
+    #[diagnostic(
+        code(pkgdev::patch::failed),
+        help("refresh the patch against the current sources")
+    )]
+    Failed {
+        patch: String,
+        file: String,
+        #[related]
+        hunks: Vec<HunkError>,
+    },
+
+    #[error("{file} patched by {patch} does not exist")]
+    #[diagnostic(
+        code(pkgdev::patch::missing_file),
+        help("check the drop-directories of the patch, it is the amount of leading directories removed from the paths in the patch")
+    )]
+    MissingFile { patch: String, file: String },
+
+    #[error("patch {0} is already applied to some files but not to others")]
+    #[diagnostic(
+        code(pkgdev::patch::partially_applied),
+        help(
+            "unpack the sources again or remove the parts of the patch that are already upstream"
+        )
+    )]
+    PartiallyApplied(String),
+
+    #[error("drop-directories of patch {0} can not be negative")]
+    #[diagnostic(code(pkgdev::patch::negative_strip))]
+    NegativeStrip(String),
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("hunk #{number} does not apply to {file} around line {line}")]
+#[diagnostic(code(pkgdev::patch::hunk_failed))]
+pub(crate) struct HunkError {
+    number: usize,
+    file: String,
+    line: usize,
+    #[source_code]
+    src: NamedSource,
+    #[label("this hunk")]
+    span: SourceSpan,
+}
+
+type PatchResult<T> = std::result::Result<T, PatchError>;
+
+/// The index and expected line of every hunk that did not apply.
+type FailedHunks = Vec<(usize, usize)>;
+
+/// A unified diff read from a patch file.
+#[derive(Debug)]
+pub(crate) struct Patch {
+    path: PathBuf,
+    text: String,
+    files: Vec<FilePatch>,
+}
+
+#[derive(Debug)]
+struct FilePatch {
+    old_path: Option<String>,
+    new_path: Option<String>,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Debug)]
+struct Hunk {
+    old_start: usize,
+    new_start: usize,
+    lines: Vec<HunkLine>,
+    old_missing_newline: bool,
+    new_missing_newline: bool,
+    /// Byte range of the hunk in the patch file for diagnostics
+    span: (usize, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+/// Where a hunk applied compared to the line the patch expected.
+struct Applied {
+    offset: isize,
+    fuzz: usize,
+}
+
+/// The content of a file split into lines without line terminators.
+#[derive(Debug, Clone, PartialEq)]
+struct FileContent {
+    lines: Vec<String>,
+    ends_with_newline: bool,
+}
+
+impl FileContent {
+    fn parse(text: &str) -> Self {
+        if text.is_empty() {
+            return Self {
+                lines: vec![],
+                ends_with_newline: true,
+            };
+        }
+
+        let ends_with_newline = text.ends_with('\n');
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        Self {
+            lines: text.split('\n').map(|l| l.to_owned()).collect(),
+            ends_with_newline,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        if self.ends_with_newline && !self.lines.is_empty() {
+            text.push('\n');
+        }
+        text
+    }
+}
+
+impl Hunk {
+    /// The lines the hunk expects before and after applying it. Reversing
+    /// swaps them to check if the patch is already applied.
+    fn sides(&self, reverse: bool) -> (Vec<&str>, Vec<&str>) {
+        let mut old = vec![];
+        let mut new = vec![];
+        for line in &self.lines {
+            match line {
+                HunkLine::Context(l) => {
+                    old.push(l.as_str());
+                    new.push(l.as_str());
+                }
+                HunkLine::Remove(l) => old.push(l.as_str()),
+                HunkLine::Add(l) => new.push(l.as_str()),
+            }
+        }
+        if reverse {
+            (new, old)
+        } else {
+            (old, new)
+        }
+    }
+
+    fn start(&self, reverse: bool) -> usize {
+        if reverse {
+            self.new_start
+        } else {
+            self.old_start
+        }
+    }
+
+    fn leading_context(&self) -> usize {
+        self.lines
+            .iter()
+            .take_while(|l| matches!(l, HunkLine::Context(_)))
+            .count()
+    }
+
+    fn trailing_context(&self) -> usize {
+        self.lines
+            .iter()
+            .rev()
+            .take_while(|l| matches!(l, HunkLine::Context(_)))
+            .count()
+    }
+}
+
+impl Patch {
+    pub fn open<P: AsRef<Path>>(path: P) -> PatchResult<Self> {
+        let text = read_to_string(path.as_ref())?;
+        Self::parse(path.as_ref(), text)
+    }
+
+    fn parse(path: &Path, text: String) -> PatchResult<Self> {
+        let mut lines = vec![];
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            lines.push((offset, line.trim_end_matches('\n')));
+            offset += line.len();
+        }
+
+        let malformed = |offset: usize, len: usize, reason: &str| PatchError::Malformed {
+            patch: path.display().to_string(),
+            reason: reason.to_owned(),
+            src: NamedSource::new(path.display().to_string(), text.clone()),
+            span: (offset, len).into(),
+        };
+
+        let mut files = vec![];
+        let mut i = 0;
+        while i < lines.len() {
+            let (_, line) = lines[i];
+            let Some(old_name) = line.strip_prefix("--- ") else {
+                i += 1;
+                continue;
+            };
+            let Some(new_name) = lines.get(i + 1).and_then(|(_, l)| l.strip_prefix("+++ ")) else {
+                i += 1;
+                continue;
+            };
+            i += 2;
+
+            let mut file = FilePatch {
+                old_path: parse_file_name(old_name),
+                new_path: parse_file_name(new_name),
+                hunks: vec![],
+            };
+
+            while let Some((hunk_offset, header)) = lines.get(i).copied() {
+                if !header.starts_with("@@ ") {
+                    break;
+                }
+                let (old_start, old_len, new_start, new_len) = parse_hunk_header(header)
+                    .ok_or_else(|| malformed(hunk_offset, header.len(), "invalid hunk header"))?;
+                i += 1;
+
+                let mut hunk = Hunk {
+                    old_start,
+                    new_start,
+                    lines: vec![],
+                    old_missing_newline: false,
+                    new_missing_newline: false,
+                    span: (hunk_offset, 0),
+                };
+                let (mut old_left, mut new_left) = (old_len, new_len);
+                while old_left > 0 || new_left > 0 {
+                    let Some((line_offset, line)) = lines.get(i).copied() else {
+                        return Err(malformed(hunk_offset, header.len(), "hunk ends early"));
+                    };
+                    // Some editors strip the space of empty context lines
+                    let (kind, content) = match line.chars().next() {
+                        Some(kind) => (kind, &line[kind.len_utf8()..]),
+                        None => (' ', ""),
+                    };
+                    match kind {
+                        ' ' if old_left > 0 && new_left > 0 => {
+                            hunk.lines.push(HunkLine::Context(content.to_owned()));
+                            old_left -= 1;
+                            new_left -= 1;
+                        }
+                        '-' if old_left > 0 => {
+                            hunk.lines.push(HunkLine::Remove(content.to_owned()));
+                            old_left -= 1;
+                        }
+                        '+' if new_left > 0 => {
+                            hunk.lines.push(HunkLine::Add(content.to_owned()));
+                            new_left -= 1;
+                        }
+                        '\\' => mark_missing_newline(&mut hunk),
+                        _ => {
+                            return Err(malformed(
+                                line_offset,
+                                line.len(),
+                                "line does not match the counts of the hunk header",
+                            ))
+                        }
+                    }
+                    i += 1;
+                }
+
+                while let Some((_, marker)) = lines.get(i).copied() {
+                    if !marker.starts_with('\\') {
+                        break;
+                    }
+                    mark_missing_newline(&mut hunk);
+                    i += 1;
+                }
+
+                let end = lines.get(i).map(|(o, _)| *o).unwrap_or(text.len());
+                hunk.span = (hunk_offset, end - hunk_offset);
+                file.hunks.push(hunk);
+            }
+
+            files.push(file);
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            text,
+            files,
+        })
+    }
+
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn hunk_error(&self, number: usize, file: &str, line: usize, hunk: &Hunk) -> HunkError {
+        HunkError {
+            number,
+            file: file.to_owned(),
+            line,
+            src: NamedSource::new(self.name(), self.text.clone()),
+            span: hunk.span.into(),
+        }
+    }
+}
+
+/// Resolves the `--- a/x`/`+++ b/x` names of git diffs and the tab
+/// separated timestamps of diff -u.
+fn parse_file_name(name: &str) -> Option<String> {
+    let name = name.split('\t').next().unwrap_or(name).trim();
+    if name == "/dev/null" {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+/// Parses `@@ -old_start,old_len +new_start,new_len @@`, lengths default to 1.
+fn parse_hunk_header(header: &str) -> Option<(usize, usize, usize, usize)> {
+    let ranges = header.strip_prefix("@@ ")?.split(" @@").next()?;
+    let (old, new) = ranges.split_once(' ')?;
+    let parse_range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, old_len) = parse_range(old.strip_prefix('-')?)?;
+    let (new_start, new_len) = parse_range(new.strip_prefix('+')?)?;
+    Some((old_start, old_len, new_start, new_len))
+}
+
+/// Handles a `\\ No newline at end of file` marker which applies to the
+/// line before it.
+fn mark_missing_newline(hunk: &mut Hunk) {
+    match hunk.lines.last() {
+        Some(HunkLine::Remove(_)) => hunk.old_missing_newline = true,
+        Some(HunkLine::Add(_)) => hunk.new_missing_newline = true,
+        _ => {
+            hunk.old_missing_newline = true;
+            hunk.new_missing_newline = true;
+        }
+    }
+}
+
+/// Removes the given amount of leading directories from a path in the patch.
+/// Without a strip level only the file name is used like GNU patch does.
+fn strip_path(path: &str, strip: Option<usize>) -> PathBuf {
+    match strip {
+        Some(strip) => PathBuf::from(path.split('/').skip(strip).collect::<Vec<&str>>().join("/")),
+        None => PathBuf::from(path.rsplit('/').next().unwrap_or(path)),
+    }
+}
+
+/// Applies the hunks to the content and returns the patched content. Hunks
+/// that do not apply are returned by their index.
+fn apply_hunks(
+    content: &FileContent,
+    hunks: &[Hunk],
+    reverse: bool,
+    max_fuzz: usize,
+) -> std::result::Result<(FileContent, Vec<Applied>), FailedHunks> {
+    let mut patched = vec![];
+    let mut applied = vec![];
+    let mut failed = vec![];
+    let mut pos = 0;
+    let mut offset: isize = 0;
+    let mut ends_with_newline = content.ends_with_newline;
+
+    for (idx, hunk) in hunks.iter().enumerate() {
+        let (old, new) = hunk.sides(reverse);
+        // A hunk without old lines names the line to insert after
+        let start = if old.is_empty() {
+            hunk.start(reverse)
+        } else {
+            hunk.start(reverse).saturating_sub(1)
+        };
+        let expected = (start as isize + offset).max(0) as usize;
+
+        let found = (0..=max_fuzz).find_map(|fuzz| {
+            let lead = fuzz.min(hunk.leading_context());
+            let trail = fuzz.min(hunk.trailing_context());
+            // Context must be left to place a hunk that only adds lines
+            if lead + trail > old.len() || (lead + trail == old.len() && lead + trail > 0) {
+                return None;
+            }
+            let pattern = &old[lead..old.len() - trail];
+            find_lines(&content.lines, pattern, pos, expected + lead)
+                .map(|at| (at, fuzz, lead, trail))
+        });
+
+        let Some((at, fuzz, lead, trail)) = found else {
+            failed.push((idx, expected + 1));
+            continue;
+        };
+
+        patched.extend(content.lines[pos..at].iter().cloned());
+        patched.extend(new[lead..new.len() - trail].iter().map(|l| l.to_string()));
+        pos = at + old.len() - lead - trail;
+        offset = at as isize - (start + lead) as isize;
+
+        // Only a hunk that ends at the last line can change the final newline
+        let (old_missing, new_missing) = if reverse {
+            (hunk.new_missing_newline, hunk.old_missing_newline)
+        } else {
+            (hunk.old_missing_newline, hunk.new_missing_newline)
+        };
+        if pos == content.lines.len() && trail == 0 && old_missing != new_missing {
+            ends_with_newline = !new_missing;
+        }
+        applied.push(Applied { offset, fuzz });
+    }
+
+    if !failed.is_empty() {
+        return Err(failed);
+    }
+
+    patched.extend(content.lines[pos..].iter().cloned());
+    Ok((
+        FileContent {
+            lines: patched,
+            ends_with_newline,
+        },
+        applied,
+    ))
+}
+
+/// Finds the pattern at or after `from` closest to the line the hunk
+/// expects it at.
+fn find_lines(lines: &[String], pattern: &[&str], from: usize, expected: usize) -> Option<usize> {
+    if pattern.len() > lines.len().saturating_sub(from) {
+        return None;
+    }
+    let last = lines.len() - pattern.len();
+    (from..=last)
+        .filter(|at| {
+            lines[*at..*at + pattern.len()]
+                .iter()
+                .zip(pattern.iter())
+                .all(|(l, p)| l == p)
+        })
+        .min_by_key(|at| at.abs_diff(expected))
+}
+
+enum FileAction {
+    Write(PathBuf, FileContent),
+    Remove(PathBuf),
+}
+
+/// Applies the patch to the sources in `dir`. Nothing is changed unless every
+/// hunk of every file applies. A patch that is already applied completely is
+/// skipped.
+pub(crate) fn apply_patch(patch: &Patch, dir: &Path, strip: Option<usize>) -> PatchResult<()> {
+    apply(patch, dir, strip, false)
+}
+
+/// Takes the changes of an applied patch back out of the sources in `dir`.
+pub(crate) fn revert_patch(patch: &Patch, dir: &Path, strip: Option<usize>) -> PatchResult<()> {
+    apply(patch, dir, strip, true)
+}
+
+fn apply(patch: &Patch, dir: &Path, strip: Option<usize>, reverse: bool) -> PatchResult<()> {
+    let mut actions = vec![];
+    let mut already_applied = 0;
+
+    for file in &patch.files {
+        let (old_path, new_path) = if reverse {
+            (&file.new_path, &file.old_path)
+        } else {
+            (&file.old_path, &file.new_path)
+        };
+        let old_target = old_path.as_deref().map(|p| strip_path(p, strip));
+        let new_target = new_path.as_deref().map(|p| strip_path(p, strip));
+        let target = match (&old_target, &new_target) {
+            (Some(old), Some(new)) if !dir.join(old).exists() && dir.join(new).exists() => new,
+            (Some(old), _) => old,
+            (None, Some(new)) => new,
+            (None, None) => continue,
+        };
+        let target_path = dir.join(target);
+        let name = target.display().to_string();
+
+        if !target_path.exists() {
+            if old_target.is_none() {
+                let (new_content, _) =
+                    apply_hunks(&FileContent::parse(""), &file.hunks, reverse, 0)
+                        .map_err(|failed| failed_error(patch, &name, file, failed))?;
+                actions.push(FileAction::Write(target_path, new_content));
+                continue;
+            }
+            if new_target.is_none() {
+                already_applied += 1;
+                continue;
+            }
+            return Err(PatchError::MissingFile {
+                patch: patch.name(),
+                file: name,
+            });
+        }
+
+        let content = FileContent::parse(&read_to_string(&target_path)?);
+        if old_target.is_none() {
+            // A created file that already has the content of the patch
+            match apply_hunks(&FileContent::parse(""), &file.hunks, reverse, 0) {
+                Ok((new_content, _)) if new_content == content => {
+                    already_applied += 1;
+                    continue;
+                }
+                _ => return Err(failed_error(patch, &name, file, vec![(0, 1)])),
+            }
+        }
+
+        match apply_hunks(&content, &file.hunks, reverse, MAX_FUZZ) {
+            Ok((new_content, applied)) => {
+                for (idx, result) in applied.iter().enumerate() {
+                    if result.offset != 0 || result.fuzz != 0 {
+                        println!(
+                            "Hunk #{} of {} succeeded with offset {} and fuzz {}",
+                            idx + 1,
+                            name,
+                            result.offset,
+                            result.fuzz
+                        );
+                    }
+                }
+                if new_target.is_none() && new_content.lines.is_empty() {
+                    actions.push(FileAction::Remove(target_path));
+                } else {
+                    actions.push(FileAction::Write(target_path, new_content));
+                }
+            }
+            Err(failed) => {
+                if apply_hunks(&content, &file.hunks, !reverse, 0).is_ok() {
+                    already_applied += 1;
+                } else {
+                    return Err(failed_error(patch, &name, file, failed));
+                }
+            }
+        }
+    }
+
+    if already_applied > 0 {
+        if actions.is_empty() {
+            if reverse {
+                println!("Patch {} is not applied, skipping", patch.name());
+            } else {
+                println!("Patch {} is already applied, skipping", patch.name());
+            }
+            return Ok(());
+        }
+        return Err(PatchError::PartiallyApplied(patch.name()));
+    }
+
+    for action in actions {
+        match action {
+            FileAction::Write(path, content) => {
+                if let Some(parent) = path.parent() {
+                    DirBuilder::new().recursive(true).create(parent)?;
+                }
+                std::fs::write(path, content.to_text())?;
+            }
+            FileAction::Remove(path) => std::fs::remove_file(path)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn failed_error(patch: &Patch, name: &str, file: &FilePatch, failed: FailedHunks) -> PatchError {
+    PatchError::Failed {
+        patch: patch.name(),
+        file: name.to_owned(),
+        hunks: failed
+            .into_iter()
+            .map(|(idx, line)| patch.hunk_error(idx + 1, name, line, &file.hunks[idx]))
+            .collect(),
+    }
+}
+
+/// How many unchanged lines are written around every change of a diff.
+const DIFF_CONTEXT: usize = 3;
+
+/// A line of a file and whether it is the last line missing its newline.
+type DiffLine<'a> = (&'a str, bool);
+
+fn diff_lines(content: &FileContent) -> Vec<DiffLine<'_>> {
+    let last = content.lines.len().saturating_sub(1);
+    content
+        .lines
+        .iter()
+        .enumerate()
+        .map(|(idx, line)| (line.as_str(), idx == last && !content.ends_with_newline))
+        .collect()
+}
+
+/// Writes a unified diff of one file with the `a/` and `b/` prefixes of git
+/// so it applies with a drop-directories of 1. A missing old or new content
+/// is a created or removed file. Returns None if nothing changed.
+pub(crate) fn diff_file(path: &str, old: Option<&str>, new: Option<&str>) -> Option<String> {
+    let old_content = FileContent::parse(old.unwrap_or_default());
+    let new_content = FileContent::parse(new.unwrap_or_default());
+    let old_lines = diff_lines(&old_content);
+    let new_lines = diff_lines(&new_content);
+    let ops = shortest_edit(&old_lines, &new_lines);
+    if old.is_some() && new.is_some() && ops.iter().all(|op| matches!(op, DiffOp::Equal(_))) {
+        return None;
+    }
+
+    let mut text = format!(
+        "--- {}\n+++ {}\n",
+        old.map(|_| format!("a/{}", path))
+            .unwrap_or(String::from("/dev/null")),
+        new.map(|_| format!("b/{}", path))
+            .unwrap_or(String::from("/dev/null")),
+    );
+
+    // Group the changes whose context overlaps into hunks
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, DiffOp::Equal(_)))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<usize>>();
+    let mut groups: Vec<(usize, usize)> = vec![];
+    for idx in changes {
+        match groups.last_mut() {
+            Some((_, end)) if idx <= *end + 2 * DIFF_CONTEXT + 1 => *end = idx,
+            _ => groups.push((idx, idx)),
+        }
+    }
+
+    for (first, last) in groups {
+        let start = first.saturating_sub(DIFF_CONTEXT);
+        let end = (last + DIFF_CONTEXT + 1).min(ops.len());
+        let hunk_ops = &ops[start..end];
+
+        let old_start = ops[..start]
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Add(_)))
+            .count();
+        let new_start = ops[..start]
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Remove(_)))
+            .count();
+        let old_len = hunk_ops
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Add(_)))
+            .count();
+        let new_len = hunk_ops
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Remove(_)))
+            .count();
+        // An empty side names the line before it
+        text.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            if old_len == 0 {
+                old_start
+            } else {
+                old_start + 1
+            },
+            old_len,
+            if new_len == 0 {
+                new_start
+            } else {
+                new_start + 1
+            },
+            new_len
+        ));
+
+        for op in hunk_ops {
+            let (prefix, (line, missing_newline)) = match op {
+                DiffOp::Equal(o) => (' ', old_lines[*o]),
+                DiffOp::Remove(o) => ('-', old_lines[*o]),
+                DiffOp::Add(n) => ('+', new_lines[*n]),
+            };
+            text.push(prefix);
+            text.push_str(line);
+            text.push('\n');
+            if missing_newline {
+                text.push_str("\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    Some(text)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DiffOp {
+    /// Index of the line in the old file
+    Equal(usize),
+    Remove(usize),
+    Add(usize),
+}
+
+/// Finds the shortest edit turning `old` into `new` with the algorithm of
+/// Myers. Only the diagonals reached in each round are kept for the way back.
+fn shortest_edit(old: &[DiffLine], new: &[DiffLine]) -> Vec<DiffOp> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = n + m;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace: Vec<Vec<isize>> = vec![];
+
+    'search: for d in 0..=max {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut ops = vec![];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let at = |k: isize| v[(k + d) as usize];
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let (prev_x, prev_y) = if d == 0 {
+            (0, 0)
+        } else {
+            (at(prev_k), at(prev_k) - prev_k)
+        };
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            ops.push(DiffOp::Equal(x as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                ops.push(DiffOp::Add(prev_y as usize));
+            } else {
+                ops.push(DiffOp::Remove(prev_x as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// Returns the patches to apply for a patch node with their strip level. A
+/// directory or a file named series lists the patches to apply in order,
+/// one per line relative to the series file, optionally followed by a -pN
+/// strip level like quilt series files.
+pub(crate) fn patch_series(
+    path: &Path,
+    drop_directories: Option<i64>,
+) -> PatchResult<Vec<(PathBuf, Option<usize>)>> {
+    let strip = match drop_directories {
+        Some(d) if d < 0 => return Err(PatchError::NegativeStrip(path.display().to_string())),
+        Some(d) => Some(d as usize),
+        None => None,
+    };
+
+    let series_path = if path.is_dir() {
+        path.join(SERIES_FILE)
+    } else if path.file_name().map(|f| f == SERIES_FILE).unwrap_or(false) {
+        path.to_path_buf()
+    } else {
+        return Ok(vec![(path.to_path_buf(), strip)]);
+    };
+
+    let base = series_path.parent().unwrap_or(Path::new("./"));
+    let mut patches = vec![];
+    for line in read_to_string(&series_path)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut parts = line.split_whitespace();
+        let Some(patch) = parts.next() else {
+            continue;
+        };
+        let line_strip = parts
+            .find_map(|opt| opt.strip_prefix("-p").and_then(|p| p.parse().ok()))
+            .or(strip);
+        patches.push((base.join(patch), line_strip));
+    }
+    Ok(patches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    const ORIGINAL: &str = "one\ntwo\nthree\nfour\nfive\nsix\nseven\n";
+
+    const CHANGE_FOUR: &str = "--- a/src/numbers.txt
++++ b/src/numbers.txt
+@@ -2,5 +2,5 @@
+ two
+ three
+-four
++FOUR
+ five
+ six
+";
+
+    fn parse(text: &str) -> Patch {
+        Patch::parse(Path::new("test.patch"), text.to_owned()).unwrap()
+    }
+
+    fn write_sources(dir: &Path, files: &[(&str, &str)]) {
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    fn read(dir: &Path, name: &str) -> String {
+        read_to_string(dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn reports_offset_and_fuzz() {
+        let patch = parse(CHANGE_FOUR);
+        let hunks = &patch.files[0].hunks;
+
+        let shifted = FileContent::parse(&format!("zero\nhalf\n{}", ORIGINAL));
+        let (patched, applied) = apply_hunks(&shifted, hunks, false, MAX_FUZZ).unwrap();
+        assert_eq!(
+            patched.to_text(),
+            "zero\nhalf\none\ntwo\nthree\nFOUR\nfive\nsix\nseven\n"
+        );
+        assert_eq!((applied[0].offset, applied[0].fuzz), (2, 0));
+
+        let changed_context = FileContent::parse(&ORIGINAL.replace("two", "TWO"));
+        let (patched, applied) = apply_hunks(&changed_context, hunks, false, MAX_FUZZ).unwrap();
+        assert_eq!(
+            patched.to_text(),
+            "one\nTWO\nthree\nFOUR\nfive\nsix\nseven\n"
+        );
+        assert_eq!((applied[0].offset, applied[0].fuzz), (0, 1));
+
+        assert_eq!(
+            apply_hunks(&changed_context, hunks, false, 0).err(),
+            Some(vec![(0, 2)])
+        );
+    }
+
+    #[test]
+    fn skips_applied_patch() -> miette::Result<()> {
+        let dir = test_dir("patch-skips-applied");
+        write_sources(&dir, &[("src/numbers.txt", ORIGINAL)]);
+        let patch = parse(CHANGE_FOUR);
+
+        apply_patch(&patch, &dir, Some(1))?;
+        let patched = read(&dir, "src/numbers.txt");
+        assert_eq!(patched, ORIGINAL.replace("four", "FOUR"));
+
+        // A resumed build applies the patches again
+        apply_patch(&patch, &dir, Some(1))?;
+        assert_eq!(read(&dir, "src/numbers.txt"), patched);
+
+        revert_patch(&patch, &dir, Some(1))?;
+        assert_eq!(read(&dir, "src/numbers.txt"), ORIGINAL);
+        revert_patch(&patch, &dir, Some(1))?;
+        assert_eq!(read(&dir, "src/numbers.txt"), ORIGINAL);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_partially_applied_patch() {
+        let dir = test_dir("patch-partially-applied");
+        write_sources(
+            &dir,
+            &[
+                ("src/numbers.txt", ORIGINAL),
+                ("src/copy.txt", &ORIGINAL.replace("four", "FOUR")),
+            ],
+        );
+        let patch = parse(&format!(
+            "{}{}",
+            CHANGE_FOUR,
+            CHANGE_FOUR.replace("numbers.txt", "copy.txt")
+        ));
+
+        let err = apply_patch(&patch, &dir, Some(1)).unwrap_err();
+        assert!(matches!(err, PatchError::PartiallyApplied(_)));
+        assert_eq!(read(&dir, "src/numbers.txt"), ORIGINAL);
+    }
+
+    #[test]
+    fn rejects_mismatched_hunk() {
+        let dir = test_dir("patch-mismatched-hunk");
+        let sources = ORIGINAL.replace("four", "vier");
+        write_sources(&dir, &[("src/numbers.txt", &sources)]);
+        let patch = parse(CHANGE_FOUR);
+
+        match apply_patch(&patch, &dir, Some(1)).unwrap_err() {
+            PatchError::Failed { file, hunks, .. } => {
+                assert_eq!(file, "src/numbers.txt");
+                assert_eq!(hunks.len(), 1);
+                assert_eq!((hunks[0].number, hunks[0].line), (1, 2));
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(read(&dir, "src/numbers.txt"), sources);
+    }
+
+    #[test]
+    fn strips_leading_directories() {
+        assert_eq!(
+            strip_path("a/src/lib/x.c", Some(0)),
+            Path::new("a/src/lib/x.c")
+        );
+        assert_eq!(
+            strip_path("a/src/lib/x.c", Some(1)),
+            Path::new("src/lib/x.c")
+        );
+        assert_eq!(strip_path("a/src/lib/x.c", Some(2)), Path::new("lib/x.c"));
+        assert_eq!(strip_path("a/src/lib/x.c", None), Path::new("x.c"));
+
+        let dir = test_dir("patch-strip");
+        write_sources(&dir, &[("numbers.txt", ORIGINAL)]);
+        let patch = parse(CHANGE_FOUR);
+        assert!(matches!(
+            apply_patch(&patch, &dir, Some(1)).unwrap_err(),
+            PatchError::MissingFile { .. }
+        ));
+        apply_patch(&patch, &dir, Some(2)).unwrap();
+        assert_eq!(read(&dir, "numbers.txt"), ORIGINAL.replace("four", "FOUR"));
+    }
+
+    #[test]
+    fn reads_series_in_order() -> miette::Result<()> {
+        let dir = test_dir("patch-series");
+        let patches = dir.join("patches");
+        write_sources(
+            &patches,
+            &[(
+                SERIES_FILE,
+                "# applied top to bottom\n02-second.patch\n\n01-first.patch -p0\n03-third.patch # upstream soon\n",
+            )],
+        );
+
+        let expected = vec![
+            (patches.join("02-second.patch"), Some(1)),
+            (patches.join("01-first.patch"), Some(0)),
+            (patches.join("03-third.patch"), Some(1)),
+        ];
+        assert_eq!(patch_series(&patches, Some(1))?, expected);
+        assert_eq!(patch_series(&patches.join(SERIES_FILE), Some(1))?, expected);
+
+        let single = patches.join("01-first.patch");
+        assert_eq!(patch_series(&single, None)?, vec![(single.clone(), None)]);
+        assert!(matches!(
+            patch_series(&single, Some(-1)).unwrap_err(),
+            PatchError::NegativeStrip(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn applies_pure_insertion() {
+        let patch = parse(
+            "--- a/numbers.txt
++++ b/numbers.txt
+@@ -2,0 +3,2 @@
++two and a half
++two and three quarters
+@@ -7,0 +10 @@
++eight
+",
+        );
+        let content = FileContent::parse(ORIGINAL);
+        let (patched, applied) =
+            apply_hunks(&content, &patch.files[0].hunks, false, MAX_FUZZ).unwrap();
+        assert_eq!(
+            patched.to_text(),
+            "one\ntwo\ntwo and a half\ntwo and three quarters\nthree\nfour\nfive\nsix\nseven\neight\n"
+        );
+        assert!(applied.iter().all(|a| a.offset == 0 && a.fuzz == 0));
+
+        let (reverted, _) = apply_hunks(&patched, &patch.files[0].hunks, true, 0).unwrap();
+        assert_eq!(reverted, content);
+    }
+
+    #[test]
+    fn handles_missing_newline_markers() {
+        let patch = parse(
+            "--- a/numbers.txt
++++ b/numbers.txt
+@@ -2,2 +2,2 @@
+ two
+-three
+\\ No newline at end of file
++three
+",
+        );
+        let hunk = &patch.files[0].hunks[0];
+        assert!(hunk.old_missing_newline);
+        assert!(!hunk.new_missing_newline);
+
+        let content = FileContent::parse("one\ntwo\nthree");
+        let (patched, _) = apply_hunks(&content, &patch.files[0].hunks, false, 0).unwrap();
+        assert_eq!(patched.to_text(), "one\ntwo\nthree\n");
+
+        let (reverted, _) = apply_hunks(&patched, &patch.files[0].hunks, true, 0).unwrap();
+        assert_eq!(reverted.to_text(), "one\ntwo\nthree");
+    }
+}
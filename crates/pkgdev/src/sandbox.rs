@@ -0,0 +1,290 @@
+use std::{
+    collections::BTreeMap, ffi::OsStr, fmt::Display, fs::DirBuilder, path::PathBuf,
+    process::Command,
+};
+
+use crate::{config::Settings, workspace::Workspace};
+use clap::ValueEnum;
+use gate::Target;
+use miette::{Diagnostic, IntoDiagnostic, Result};
+use thiserror::Error;
+
+#[derive(Debug, Error, Diagnostic)]
+pub(crate) enum SandboxError {
+    #[error("the {0} sandbox can not block network access")]
+    #[diagnostic(
+        code(pkgdev::sandbox::network),
+        help("build with --sandbox namespaces to run without network access")
+    )]
+    NetworkNotBlockable(SandboxBackend),
+    #[error("the {0} sandbox is not supported on this operating system")]
+    #[diagnostic(code(pkgdev::sandbox::unsupported))]
+    Unsupported(SandboxBackend),
+    #[error("bwrap is needed for the namespaces sandbox but was not found in PATH")]
+    #[diagnostic(
+        code(pkgdev::sandbox::missing_bwrap),
+        help("install bubblewrap or build with --sandbox host")
+    )]
+    MissingBwrap,
+}
+
+/// Where the tools of the build step run. Every backend gets the same
+/// controlled environment, they differ in how much of the host the build
+/// can see and change. Builds are isolated by default where a backend that
+/// isolates them exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SandboxBackend {
+    /// Directly on the host without any isolation
+    Host,
+    /// In Linux namespaces through bubblewrap. The host filesystem is read only and only the
+    /// workspace can be written
+    Namespaces,
+}
+
+impl Default for SandboxBackend {
+    fn default() -> Self {
+        if cfg!(target_os = "linux") {
+            SandboxBackend::Namespaces
+        } else {
+            SandboxBackend::Host
+        }
+    }
+}
+
+impl Display for SandboxBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SandboxBackend::Host => write!(f, "host"),
+            SandboxBackend::Namespaces => write!(f, "namespaces"),
+        }
+    }
+}
+
+impl SandboxBackend {
+    /// Returns the isolation of the backend after checking it can be used
+    /// on this host.
+    fn isolation(self) -> Result<Box<dyn Isolation>> {
+        match self {
+            SandboxBackend::Host => Ok(Box::new(Host)),
+            SandboxBackend::Namespaces => Ok(Box::new(Namespaces::new()?)),
+        }
+    }
+}
+
+/// The parts of the workspace a backend has to make available to the build.
+#[derive(Debug)]
+pub(crate) struct SandboxConfig {
+    pub workspace: PathBuf,
+    pub sysroot: PathBuf,
+    pub network: bool,
+}
+
+/// How a backend isolates the tools of a build from the host.
+pub(crate) trait Isolation: std::fmt::Debug {
+    /// Whether builds can run without network access.
+    fn can_block_network(&self) -> bool;
+
+    /// Returns the command running the program isolated by the backend. The
+    /// environment is set by the sandbox afterwards.
+    fn wrap(&self, program: &OsStr, config: &SandboxConfig) -> Command;
+}
+
+/// Runs the tools directly on the host.
+#[derive(Debug)]
+pub(crate) struct Host;
+
+impl Isolation for Host {
+    fn can_block_network(&self) -> bool {
+        false
+    }
+
+    fn wrap(&self, program: &OsStr, _config: &SandboxConfig) -> Command {
+        Command::new(program)
+    }
+}
+
+/// Runs the tools in Linux namespaces through bubblewrap with a read only
+/// view of the host and a writable workspace.
+#[derive(Debug)]
+pub(crate) struct Namespaces {
+    /// bwrap is looked up in the PATH of pkgdev, not the one of the build
+    bwrap: PathBuf,
+}
+
+impl Namespaces {
+    pub fn new() -> Result<Self> {
+        if !cfg!(target_os = "linux") {
+            return Err(SandboxError::Unsupported(SandboxBackend::Namespaces).into());
+        }
+        Ok(Self {
+            bwrap: find_in_path("bwrap").ok_or(SandboxError::MissingBwrap)?,
+        })
+    }
+}
+
+impl Isolation for Namespaces {
+    fn can_block_network(&self) -> bool {
+        true
+    }
+
+    fn wrap(&self, program: &OsStr, config: &SandboxConfig) -> Command {
+        let workspace = config.workspace.as_os_str();
+        let sysroot = config.sysroot.as_os_str();
+        let mut cmd = Command::new(&self.bwrap);
+        cmd.args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"]);
+        cmd.args(["--tmpfs", "/tmp"]);
+        cmd.arg("--bind").arg(workspace).arg(workspace);
+        cmd.arg("--ro-bind").arg(sysroot).arg(sysroot);
+        cmd.args(["--unshare-all", "--die-with-parent", "--new-session"]);
+        if config.network {
+            cmd.arg("--share-net");
+        }
+        cmd.arg(program);
+        cmd
+    }
+}
+
+/// Runs the tools of a build with an environment made from scratch, a
+/// private home and temporary directory and the staged dependencies in the
+/// sysroot of the workspace.
+#[derive(Debug)]
+pub(crate) struct Sandbox {
+    isolation: Box<dyn Isolation>,
+    config: SandboxConfig,
+    env: BTreeMap<String, String>,
+    target: Option<Target>,
+}
+
+impl Sandbox {
+    pub fn new(
+        wks: &Workspace,
+        settings: &Settings,
+        backend: SandboxBackend,
+        network: bool,
+    ) -> Result<Self> {
+        let isolation = backend.isolation()?;
+        if !network && !isolation.can_block_network() {
+            return Err(SandboxError::NetworkNotBlockable(backend).into());
+        }
+
+        let root = wks.get_or_create_sandbox_dir()?;
+        let home = root.join("home");
+        let tmp = root.join("tmp");
+        for dir in [&home, &tmp] {
+            if dir.exists() {
+                std::fs::remove_dir_all(dir).into_diagnostic()?;
+            }
+            DirBuilder::new()
+                .recursive(true)
+                .create(dir)
+                .into_diagnostic()?;
+        }
+
+        let mut env = BTreeMap::new();
+        env.insert(String::from("PATH"), settings.get_search_path().join(":"));
+        env.insert(String::from("HOME"), home.to_string_lossy().to_string());
+        env.insert(String::from("TMPDIR"), tmp.to_string_lossy().to_string());
+        env.insert(String::from("LC_ALL"), String::from("C"));
+
+        Ok(Self {
+            isolation,
+            config: SandboxConfig {
+                workspace: wks.get_path().to_path_buf(),
+                sysroot: wks.get_or_create_sysroot_dir()?,
+                network,
+            },
+            env,
+            target: None,
+        })
+    }
+
+    pub fn get_env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    pub fn get_target(&self) -> Option<&Target> {
+        self.target.as_ref()
+    }
+
+    /// Cross compiles for the target by pointing the build at its tools and
+    /// sysroot. The sysroot has to be readable inside the sandbox.
+    pub fn set_target(&mut self, target: Target) {
+        self.env.extend(target.environment());
+        self.target = Some(target);
+    }
+
+    /// Puts the value in front of the one the variable already has so it is
+    /// searched first.
+    pub fn prepend_env(&mut self, name: &str, value: &str, separator: &str) {
+        let value = match self.env.get(name) {
+            Some(existing) if !existing.is_empty() => {
+                format!("{}{}{}", value, separator, existing)
+            }
+            _ => value.to_owned(),
+        };
+        self.env.insert(name.to_owned(), value);
+    }
+
+    /// Returns the command running the program in the sandbox. Arguments and
+    /// variables added to it afterwards are passed on to the program.
+    pub fn command<S: AsRef<OsStr>>(&self, program: S) -> Command {
+        let mut cmd = self.isolation.wrap(program.as_ref(), &self.config);
+        cmd.env_clear();
+        cmd.envs(&self.env);
+        cmd
+    }
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH").and_then(|path| {
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(network: bool) -> SandboxConfig {
+        SandboxConfig {
+            workspace: PathBuf::from("/wks"),
+            sysroot: PathBuf::from("/wks/sysroot"),
+            network,
+        }
+    }
+
+    #[test]
+    fn host_runs_program_directly() {
+        let cmd = Host.wrap(OsStr::new("make"), &config(true));
+        assert_eq!(cmd.get_program(), "make");
+        assert_eq!(cmd.get_args().count(), 0);
+        assert!(!Host.can_block_network());
+    }
+
+    #[test]
+    fn namespaces_share_network_only_when_allowed() {
+        let namespaces = Namespaces {
+            bwrap: PathBuf::from("/usr/bin/bwrap"),
+        };
+        assert!(namespaces.can_block_network());
+
+        let cmd = namespaces.wrap(OsStr::new("make"), &config(false));
+        let args = cmd.get_args().collect::<Vec<_>>();
+        assert_eq!(cmd.get_program(), "/usr/bin/bwrap");
+        assert_eq!(args.last(), Some(&OsStr::new("make")));
+        assert!(args.windows(3).any(|w| w == ["--bind", "/wks", "/wks"]));
+        assert!(!args.contains(&OsStr::new("--share-net")));
+
+        let cmd = namespaces.wrap(OsStr::new("make"), &config(true));
+        assert!(cmd.get_args().any(|arg| arg == "--share-net"));
+    }
+
+    #[test]
+    fn isolates_by_default_on_linux() {
+        if cfg!(target_os = "linux") {
+            assert_eq!(SandboxBackend::default(), SandboxBackend::Namespaces);
+        }
+    }
+}
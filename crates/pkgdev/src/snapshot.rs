@@ -0,0 +1,317 @@
+use std::{
+    collections::BTreeSet,
+    fs::{read_dir, read_to_string, DirBuilder, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{derive_source_name, patch, workspace::Workspace};
+use bundle::{Bundle, PatchSource, SourceNode};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+/// Records which package the snapshot in the workspace was taken of so the
+/// patch can be written into its bundle.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub package: String,
+    pub bundle_path: PathBuf,
+    /// The directory of the unpacked sources in the build directory
+    pub source_name: String,
+    /// The patch taken out of the sources before the snapshot to refresh it
+    pub patch: Option<String>,
+}
+
+impl Snapshot {
+    pub fn load(wks: &Workspace) -> Result<Self> {
+        let path = wks.get_snapshot_path();
+        let contents = read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err("there is no snapshot in the workspace, run pkgdev build --edit first")?;
+        serde_json::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err(format!("could not read snapshot {}", path.display()))
+    }
+
+    pub fn save(&self, wks: &Workspace) -> Result<()> {
+        let mut f = File::create(wks.get_snapshot_path()).into_diagnostic()?;
+        f.write_all(
+            serde_json::to_string_pretty(self)
+                .into_diagnostic()?
+                .as_bytes(),
+        )
+        .into_diagnostic()?;
+        Ok(())
+    }
+}
+
+/// Copies the unpacked sources of the first source section of the package
+/// so the edits made to them afterwards can be turned into a patch. To
+/// refresh a patch it is taken back out of the sources before the copy is
+/// made.
+pub(crate) fn take(wks: &Workspace, pkg: &Bundle, refresh_patch: Option<&str>) -> Result<()> {
+    let section = pkg
+        .package_document
+        .sources
+        .first()
+        .ok_or(miette::miette!("package {} has no sources", pkg.get_name()))?;
+    let source_name = derive_source_name(pkg.get_name(), section);
+    let source_dir = wks.get_or_create_build_dir()?.join(&source_name);
+
+    if let Some(name) = refresh_patch {
+        let (patch_path, strip) = find_patch(pkg, name)?.ok_or(miette::miette!(
+            "patch {} is not a source of {}",
+            name,
+            pkg.get_name()
+        ))?;
+        println!("Reverting patch {} to refresh it", patch_path.display());
+        let patch_file = patch::Patch::open(&patch_path)?;
+        patch::revert_patch(&patch_file, &source_dir, strip)?;
+    }
+
+    let snapshot_dir = wks.get_snapshot_dir();
+    if snapshot_dir.exists() {
+        std::fs::remove_dir_all(&snapshot_dir).into_diagnostic()?;
+    }
+    DirBuilder::new()
+        .recursive(true)
+        .create(&snapshot_dir)
+        .into_diagnostic()?;
+
+    fs_extra::dir::copy(
+        &source_dir,
+        &snapshot_dir,
+        &fs_extra::dir::CopyOptions::default(),
+    )
+    .into_diagnostic()
+    .wrap_err("could not snapshot the sources")?;
+
+    Snapshot {
+        package: pkg.get_name(),
+        bundle_path: pkg.get_path().to_path_buf(),
+        source_name,
+        patch: refresh_patch.map(|p| p.to_owned()),
+    }
+    .save(wks)?;
+
+    println!(
+        "Edit the sources in {} and run pkgdev refresh to write the patch",
+        source_dir.display()
+    );
+    Ok(())
+}
+
+/// Compares the edited sources against the snapshot and writes the changes
+/// as a patch into the bundle. A new patch is added to package.kdl, one
+/// that is already a source of the package is updated.
+pub(crate) fn refresh(wks: &Workspace, name: Option<String>) -> Result<PathBuf> {
+    let snapshot = Snapshot::load(wks)?;
+    let name = name.or(snapshot.patch.clone()).ok_or(miette::miette!(
+        "the snapshot was not taken to refresh a patch, name the patch to write"
+    ))?;
+
+    let old_dir = wks.get_snapshot_dir().join(&snapshot.source_name);
+    let new_dir = wks.get_or_create_build_dir()?.join(&snapshot.source_name);
+    let diff = diff_tree(&old_dir, &new_dir)?;
+
+    if diff.is_empty() {
+        return Err(miette::miette!(
+            "the sources of {} did not change since the snapshot",
+            snapshot.package
+        ));
+    }
+
+    let mut pkg = Bundle::open_local(&snapshot.bundle_path)?;
+    let patch_path = pkg.get_path().join(&name);
+    if let Some(parent) = patch_path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .create(parent)
+            .into_diagnostic()?;
+    }
+    std::fs::write(&patch_path, diff)
+        .into_diagnostic()
+        .wrap_err(format!("could not write patch {}", patch_path.display()))?;
+
+    let is_node = pkg
+        .package_document
+        .sources
+        .iter()
+        .flat_map(|section| section.sources.iter())
+        .any(|src| matches!(src, SourceNode::Patch(p) if p.get_bundle_path(pkg.get_path()) == patch_path));
+    match find_patch(&pkg, &name)? {
+        // Patches listed in a series file keep their entry there
+        Some((_, strip)) if !is_node => {
+            if strip != Some(1) {
+                println!(
+                    "Patch {} is written for a drop-directories of 1, update its entry in the series file",
+                    name
+                );
+            }
+        }
+        _ => pkg.add_source(SourceNode::Patch(PatchSource::new(&name, Some(1))?))?,
+    }
+
+    Ok(patch_path)
+}
+
+/// Writes the differences between the files of the two directories as one
+/// patch. Files that are not text are skipped.
+fn diff_tree(old_dir: &Path, new_dir: &Path) -> Result<String> {
+    let mut files = BTreeSet::new();
+    collect_files(old_dir, old_dir, &mut files)?;
+    collect_files(new_dir, new_dir, &mut files)?;
+
+    let mut diff = String::new();
+    for file in files {
+        let read = |dir: &Path| -> Result<Option<String>> {
+            let path = dir.join(&file);
+            if !path.exists() {
+                return Ok(None);
+            }
+            match read_to_string(&path) {
+                Ok(contents) => Ok(Some(contents)),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    Err(miette::miette!("{} is not a text file", path.display()))
+                }
+                Err(e) => Err(e).into_diagnostic(),
+            }
+        };
+        let (old, new) = match (read(old_dir), read(new_dir)) {
+            (Ok(old), Ok(new)) => (old, new),
+            (Err(e), _) | (_, Err(e)) => {
+                println!("Skipping {}: {}", file, e);
+                continue;
+            }
+        };
+        if let Some(file_diff) = patch::diff_file(&file, old.as_deref(), new.as_deref()) {
+            println!("Changed {}", file);
+            diff.push_str(&file_diff);
+        }
+    }
+    Ok(diff)
+}
+
+/// Finds the patch among the patch sources of the package including the
+/// patches listed in series files.
+fn find_patch(pkg: &Bundle, name: &str) -> Result<Option<(PathBuf, Option<usize>)>> {
+    let wanted = pkg.get_path().join(name);
+    for section in &pkg.package_document.sources {
+        for src in &section.sources {
+            if let SourceNode::Patch(patch_src) = src {
+                let series = patch::patch_series(
+                    &patch_src.get_bundle_path(pkg.get_path()),
+                    patch_src.drop_directories,
+                )?;
+                if let Some(found) = series.into_iter().find(|(path, _)| path == &wanted) {
+                    return Ok(Some(found));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Collects the relative paths of all files below the directory, links are
+/// not followed.
+fn collect_files(root: &Path, dir: &Path, files: &mut BTreeSet<String>) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in read_dir(dir).into_diagnostic()? {
+        let entry = entry.into_diagnostic()?;
+        let file_type = entry.file_type().into_diagnostic()?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            collect_files(root, &path, files)?;
+        } else if file_type.is_file() {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            files.insert(relative.to_string_lossy().to_string());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    fn write_tree(dir: &Path, files: &[(&str, &str)]) {
+        for (name, content) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    fn read_tree(dir: &Path) -> Vec<(String, String)> {
+        let mut files = BTreeSet::new();
+        collect_files(dir, dir, &mut files).unwrap();
+        files
+            .into_iter()
+            .map(|file| {
+                let contents = read_to_string(dir.join(&file)).unwrap();
+                (file, contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_applies_to_snapshot() -> Result<()> {
+        let dir = test_dir("snapshot-diff");
+        let old_dir = dir.join("snapshot");
+        let new_dir = dir.join("build");
+        let long = (1..=20)
+            .map(|n| format!("line {}\n", n))
+            .collect::<String>();
+        write_tree(
+            &old_dir,
+            &[
+                ("Makefile", "all:\n\tcc -o hello hello.c\n"),
+                ("src/long.txt", &long),
+                ("src/removed.c", "int removed;\n"),
+                ("README", "hello"),
+            ],
+        );
+        write_tree(
+            &new_dir,
+            &[
+                ("Makefile", "all:\n\t$(CC) -o hello hello.c\n"),
+                (
+                    "src/long.txt",
+                    &long
+                        .replace("line 2\n", "line 2\nline 2.5\n")
+                        .replace("line 18\n", ""),
+                ),
+                ("src/added/new.h", "#define NEW 1\n"),
+                ("README", "hello\nworld\n"),
+            ],
+        );
+
+        let diff = diff_tree(&old_dir, &new_dir)?;
+        // Two changes far apart in one file are separate hunks
+        assert_eq!(diff.matches("@@ -").count(), 6);
+        let patch_path = dir.join("edits.patch");
+        std::fs::write(&patch_path, diff).into_diagnostic()?;
+        let patch = patch::Patch::open(&patch_path)?;
+
+        let sources = dir.join("sources");
+        fs_extra::dir::copy(
+            &old_dir,
+            &sources,
+            &fs_extra::dir::CopyOptions {
+                copy_inside: true,
+                ..Default::default()
+            },
+        )
+        .into_diagnostic()?;
+
+        patch::apply_patch(&patch, &sources, Some(1))?;
+        assert_eq!(read_tree(&sources), read_tree(&new_dir));
+
+        patch::revert_patch(&patch, &sources, Some(1))?;
+        assert_eq!(read_tree(&sources), read_tree(&old_dir));
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::BTreeSet,
+    fs::{read_dir, read_link, read_to_string, DirBuilder, Permissions},
+    os::unix::fs::{symlink, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{config::Settings, sandbox::Sandbox, workspace::Workspace};
+use bundle::{Bundle, DependencyKind};
+use gate::Gate;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use pkg5::{repository::FileRepository, Action, ActionKind, Fmri};
+
+/// The directories of the sysroot the staged dependencies put headers,
+/// libraries and pkg-config files into.
+#[derive(Debug, Default)]
+pub(crate) struct StagedDependencies {
+    sysroot: PathBuf,
+    include_dirs: BTreeSet<PathBuf>,
+    lib_dirs: BTreeSet<PathBuf>,
+    pkg_config_dirs: BTreeSet<PathBuf>,
+}
+
+impl StagedDependencies {
+    /// Points the compiler, linker and pkg-config of the sandbox at the
+    /// staged dependencies before the locations they would use otherwise.
+    pub fn apply(&self, sandbox: &mut Sandbox) {
+        let include_flags = self
+            .include_dirs
+            .iter()
+            .map(|dir| format!("-I{}", self.sysroot.join(dir).display()))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let lib_flags = self
+            .lib_dirs
+            .iter()
+            .map(|dir| format!("-L{}", self.sysroot.join(dir).display()))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let pkg_config_path = self
+            .pkg_config_dirs
+            .iter()
+            .map(|dir| self.sysroot.join(dir).to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join(":");
+
+        for (name, value, separator) in [
+            ("CPPFLAGS", &include_flags, " "),
+            ("CFLAGS", &include_flags, " "),
+            ("CXXFLAGS", &include_flags, " "),
+            ("LDFLAGS", &lib_flags, " "),
+            ("PKG_CONFIG_PATH", &pkg_config_path, ":"),
+        ] {
+            if !value.is_empty() {
+                sandbox.prepend_env(name, value, separator);
+            }
+        }
+    }
+
+    /// Remembers where a staged file went by its path relative to the
+    /// sysroot.
+    fn record(&mut self, path: &Path) {
+        let Some(parent) = path.parent() else {
+            return;
+        };
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if let Some(include_dir) = path
+            .ancestors()
+            .find(|dir| dir.file_name().map(|n| n == "include").unwrap_or(false))
+        {
+            self.include_dirs.insert(include_dir.to_path_buf());
+        } else if file_name.ends_with(".pc")
+            && parent
+                .file_name()
+                .map(|n| n == "pkgconfig")
+                .unwrap_or(false)
+        {
+            self.pkg_config_dirs.insert(parent.to_path_buf());
+        } else if file_name.ends_with(".so")
+            || file_name.contains(".so.")
+            || file_name.ends_with(".a")
+        {
+            self.lib_dirs.insert(parent.to_path_buf());
+        }
+    }
+}
+
+/// Whether the package needs the dependency to be built. Dev dependencies
+/// are only needed to build while required packages are also linked
+/// against, incorporations and optional packages are left out.
+fn is_build_dependency(dev: bool, kind: Option<&DependencyKind>) -> bool {
+    dev || matches!(kind, None | Some(DependencyKind::Require))
+}
+
+/// Stages the build dependencies of the package into a fresh sysroot in the
+/// workspace. A gate package built earlier in the same gate build is taken
+/// from its kept prototype, everything else from the newest version in the
+/// local repository. Dependencies found in neither are expected to be
+/// installed on the host.
+pub(crate) fn stage_dependencies(
+    wks: &Workspace,
+    pkg: &Bundle,
+    gate_data: Option<&Gate>,
+) -> Result<StagedDependencies> {
+    let sysroot = wks.get_or_create_sysroot_dir()?;
+    std::fs::remove_dir_all(&sysroot)
+        .into_diagnostic()
+        .wrap_err("could not clean the sysroot")?;
+    let sysroot = wks.get_or_create_sysroot_dir()?;
+
+    let mut staged = StagedDependencies {
+        sysroot: sysroot.clone(),
+        ..Default::default()
+    };
+
+    let dependencies = pkg
+        .package_document
+        .dependencies
+        .iter()
+        .filter(|dep| is_build_dependency(dep.dev, dep.kind.as_ref()))
+        .collect::<Vec<_>>();
+    if dependencies.is_empty() {
+        return Ok(staged);
+    }
+
+    let publisher = gate_data
+        .map(|g| g.publisher.clone())
+        .unwrap_or(Gate::default().publisher);
+    let repo_dir = Settings::get_or_create_repo_dir()?;
+    let repo = FileRepository::open(&repo_dir)
+        .ok()
+        .filter(|repo| repo.has_publisher(&publisher));
+
+    for dep in dependencies {
+        let proto_dir = wks.get_gate_proto_dir(&dep.name);
+        if proto_dir.is_dir() {
+            println!("Staging {} from the prototype of its gate build", dep.name);
+            copy_tree(&proto_dir, &proto_dir, &sysroot, &mut staged)
+                .wrap_err(format!("could not stage {}", dep.name))?;
+            continue;
+        }
+
+        let manifest = repo
+            .as_ref()
+            .and_then(|repo| repo.manifest(&publisher, &Fmri::new(&dep.name, None)).ok());
+        match (&repo, manifest) {
+            (Some(repo), Some(manifest)) => {
+                println!(
+                    "Staging {} from {}",
+                    manifest.fmri().unwrap_or(&dep.name),
+                    repo_dir.display()
+                );
+                stage_manifest(repo, &publisher, manifest.actions(), &sysroot, &mut staged)
+                    .wrap_err(format!("could not stage {}", dep.name))?;
+            }
+            _ => println!(
+                "Dependency {} is not in the repository, using the one installed on the host",
+                dep.name
+            ),
+        }
+    }
+
+    relocate_pkg_config_files(&staged)?;
+
+    Ok(staged)
+}
+
+/// Recreates the directories, files and links of a published package in
+/// the sysroot. Hardlinks come last as their targets have to exist.
+fn stage_manifest<'a, I: Iterator<Item = &'a Action>>(
+    repo: &FileRepository,
+    publisher: &str,
+    actions: I,
+    sysroot: &Path,
+    staged: &mut StagedDependencies,
+) -> Result<()> {
+    let mut hardlinks = vec![];
+    for action in actions {
+        let Some(path) = action.key() else {
+            continue;
+        };
+        let relative = Path::new(path.trim_start_matches('/'));
+        let dest = sysroot.join(relative);
+        match action.kind {
+            ActionKind::Dir => create_dir(&dest)?,
+            ActionKind::File => {
+                let hash = action
+                    .payload
+                    .as_deref()
+                    .ok_or(miette::miette!("file action for {} has no payload", path))?;
+                create_parent(&dest)?;
+                remove_existing(&dest)?;
+                std::fs::write(&dest, repo.file(publisher, hash)?)
+                    .into_diagnostic()
+                    .wrap_err(format!("could not write {}", dest.display()))?;
+                if let Some(mode) = action
+                    .get("mode")
+                    .and_then(|m| u32::from_str_radix(m, 8).ok())
+                {
+                    // Staged files are only read, keep them writable so the
+                    // next staging can remove them
+                    std::fs::set_permissions(&dest, Permissions::from_mode(mode | 0o200))
+                        .into_diagnostic()?;
+                }
+                staged.record(relative);
+            }
+            ActionKind::Link => {
+                let target = action
+                    .get("target")
+                    .ok_or(miette::miette!("link action for {} has no target", path))?;
+                create_parent(&dest)?;
+                remove_existing(&dest)?;
+                symlink(target, &dest)
+                    .into_diagnostic()
+                    .wrap_err(format!("could not link {}", dest.display()))?;
+                staged.record(relative);
+            }
+            ActionKind::Hardlink => hardlinks.push((relative, action)),
+            _ => {}
+        }
+    }
+
+    for (relative, action) in hardlinks {
+        let dest = sysroot.join(relative);
+        let target = action.get("target").ok_or(miette::miette!(
+            "hardlink action for {} has no target",
+            relative.display()
+        ))?;
+        let target = match target.strip_prefix('/') {
+            Some(absolute) => sysroot.join(absolute),
+            None => dest.parent().unwrap_or(sysroot).join(target),
+        };
+        create_parent(&dest)?;
+        remove_existing(&dest)?;
+        std::fs::hard_link(&target, &dest)
+            .into_diagnostic()
+            .wrap_err(format!("could not link {}", dest.display()))?;
+        staged.record(relative);
+    }
+
+    Ok(())
+}
+
+/// Copies a prototype directory into the sysroot keeping symbolic links as
+/// they are.
+fn copy_tree(
+    root: &Path,
+    dir: &Path,
+    sysroot: &Path,
+    staged: &mut StagedDependencies,
+) -> Result<()> {
+    for entry in read_dir(dir).into_diagnostic()? {
+        let entry = entry.into_diagnostic()?;
+        let file_type = entry.file_type().into_diagnostic()?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let dest = sysroot.join(relative);
+        if file_type.is_dir() {
+            create_dir(&dest)?;
+            copy_tree(root, &path, sysroot, staged)?;
+        } else if file_type.is_symlink() {
+            remove_existing(&dest)?;
+            symlink(read_link(&path).into_diagnostic()?, &dest).into_diagnostic()?;
+            staged.record(relative);
+        } else {
+            remove_existing(&dest)?;
+            std::fs::copy(&path, &dest)
+                .into_diagnostic()
+                .wrap_err(format!("could not copy {}", path.display()))?;
+            staged.record(relative);
+        }
+    }
+    Ok(())
+}
+
+/// Points the prefix of the staged pkg-config files into the sysroot so the
+/// flags they hand out find the staged headers and libraries.
+fn relocate_pkg_config_files(staged: &StagedDependencies) -> Result<()> {
+    for dir in &staged.pkg_config_dirs {
+        for entry in read_dir(staged.sysroot.join(dir)).into_diagnostic()? {
+            let path = entry.into_diagnostic()?.path();
+            if path.extension().map(|e| e != "pc").unwrap_or(true) || !path.is_file() {
+                continue;
+            }
+            let contents = read_to_string(&path).into_diagnostic()?;
+            let relocated = contents
+                .lines()
+                .map(|line| match line.strip_prefix("prefix=/") {
+                    Some(prefix) => format!("prefix={}", staged.sysroot.join(prefix).display()),
+                    None => line.to_owned(),
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+            // Files staged from prototypes may be links into the sysroot
+            std::fs::remove_file(&path).into_diagnostic()?;
+            std::fs::write(&path, relocated + "\n")
+                .into_diagnostic()
+                .wrap_err(format!("could not relocate {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn create_dir(path: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .create(path)
+        .into_diagnostic()
+        .wrap_err(format!("could not create {}", path.display()))
+}
+
+/// Removes a file or link an earlier dependency put at the path.
+fn remove_existing(path: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(meta) if !meta.is_dir() => std::fs::remove_file(path)
+            .into_diagnostic()
+            .wrap_err(format!("could not replace {}", path.display())),
+        _ => Ok(()),
+    }
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) => create_dir(parent),
+        None => Ok(()),
+    }
+}
+
+/// Keeps the prototype of a gate package that was just built so packages
+/// built after it in the same gate build can stage it.
+pub(crate) fn keep_gate_proto(wks: &Workspace, pkg: &Bundle) -> Result<()> {
+    let proto_dir = wks.get_or_create_prototype_dir()?;
+    // Nothing to keep when the build stopped before installing
+    if read_dir(&proto_dir).into_diagnostic()?.next().is_none() {
+        return Ok(());
+    }
+    let kept_dir = wks.get_gate_proto_dir(&pkg.get_name());
+    if kept_dir.exists() {
+        std::fs::remove_dir_all(&kept_dir).into_diagnostic()?;
+    }
+    create_dir(&kept_dir)?;
+    copy_tree(
+        &proto_dir,
+        &proto_dir,
+        &kept_dir,
+        &mut StagedDependencies::default(),
+    )
+    .wrap_err(format!(
+        "could not keep the prototype of {}",
+        pkg.get_name()
+    ))
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::BTreeMap,
+    fs::{read_dir, read_to_string, File},
+    io::Write,
+    path::Path,
+};
+
+use crate::{download, workspace::Workspace, BuildOptions, BuildSteps};
+use bundle::Bundle;
+use gate::Gate;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The steps of a build in the order they run.
+pub(crate) const BUILD_STEPS: [BuildSteps; 5] = [
+    BuildSteps::Download,
+    BuildSteps::Unpack,
+    BuildSteps::Build,
+    BuildSteps::Pack,
+    BuildSteps::Publish,
+];
+
+/// Records which steps of a build completed in a workspace together with a
+/// fingerprint of everything the step used as input. A step only counts as
+/// done as long as its fingerprint matches the current bundle.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct BuildState {
+    package: String,
+    steps: BTreeMap<String, String>,
+}
+
+impl BuildState {
+    pub fn load(wks: &Workspace) -> Result<Self> {
+        let path = wks.get_build_state_path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = read_to_string(&path).into_diagnostic()?;
+        serde_json::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err(format!("could not read build state {}", path.display()))
+    }
+
+    pub fn save(&self, wks: &Workspace) -> Result<()> {
+        let mut f = File::create(wks.get_build_state_path()).into_diagnostic()?;
+        f.write_all(
+            serde_json::to_string_pretty(self)
+                .into_diagnostic()?
+                .as_bytes(),
+        )
+        .into_diagnostic()?;
+        Ok(())
+    }
+
+    pub fn is_complete(&self, package: &str, step: &BuildSteps, fingerprint: &str) -> bool {
+        self.package == package
+            && self.steps.get(&step.to_string()).map(|f| f.as_str()) == Some(fingerprint)
+    }
+
+    pub fn complete(&mut self, package: &str, step: &BuildSteps, fingerprint: &str) {
+        if self.package != package {
+            self.package = package.to_owned();
+            self.steps.clear();
+        }
+        self.steps.insert(step.to_string(), fingerprint.to_owned());
+    }
+
+    /// Forgets the given step and all steps after it as running a step again
+    /// changes the inputs of the later ones.
+    pub fn invalidate_from(&mut self, step: &BuildSteps) {
+        for later in BUILD_STEPS.iter().filter(|s| *s >= step) {
+            self.steps.remove(&later.to_string());
+        }
+    }
+}
+
+/// Computes the fingerprint of every step. Each one includes the fingerprint
+/// of the step before it so a change invalidates all steps from the first
+/// one that uses the changed input:
+/// the sources for the download, the files next to the package.kdl like
+/// patches for unpacking, the package document for the build and the gate
+/// transforms and manifest for packing and publishing.
+pub(crate) fn step_fingerprints(
+    pkg: &Bundle,
+    gate_data: Option<&Gate>,
+    options: &BuildOptions,
+) -> Result<BTreeMap<BuildSteps, String>> {
+    let mut fingerprints = BTreeMap::new();
+
+    let mut hasher = Sha256::new();
+    hasher.update(
+        serde_json::to_string(&pkg.package_document.sources)
+            .into_diagnostic()?
+            .as_bytes(),
+    );
+    for trusted_key in &pkg.package_document.trusted_keys {
+        hasher.update(trusted_key.as_bytes());
+    }
+    if let Some(keyring) = download::find_keyring(pkg, gate_data).filter(|k| k.exists()) {
+        hasher.update(std::fs::read(keyring).into_diagnostic()?);
+    }
+    fingerprints.insert(
+        BuildSteps::Download,
+        format!("{:x}", hasher.clone().finalize()),
+    );
+
+    hash_files(
+        &mut hasher,
+        pkg.get_path(),
+        pkg.get_path(),
+        &UNHASHED_BUNDLE_FILES,
+    )?;
+    fingerprints.insert(
+        BuildSteps::Unpack,
+        format!("{:x}", hasher.clone().finalize()),
+    );
+
+    hasher.update(
+        serde_json::to_string(&pkg.package_document)
+            .into_diagnostic()?
+            .as_bytes(),
+    );
+    if let Some(target) = &options.target {
+        hasher.update(target.as_bytes());
+        if let Some(target) = gate_data.and_then(|g| g.get_target(target)) {
+            hasher.update(serde_json::to_string(&target).into_diagnostic()?.as_bytes());
+        }
+    }
+    hasher.update(options.sandbox.to_string().as_bytes());
+    hasher.update([options.network as u8]);
+    fingerprints.insert(
+        BuildSteps::Build,
+        format!("{:x}", hasher.clone().finalize()),
+    );
+
+    if let Some(manifest) = pkg.get_mogrify_manifest() {
+        hasher.update(std::fs::read(manifest).into_diagnostic()?);
+    }
+    if let Some(gate_data) = gate_data {
+        hasher.update(gate_data.version.as_bytes());
+        hasher.update(gate_data.branch.as_bytes());
+        hasher.update(gate_data.publisher.as_bytes());
+        if let Some(distribution) = &gate_data.distribution {
+            hasher.update(distribution.distribution_type.to_string().as_bytes());
+        }
+        for transform in &gate_data.default_transforms {
+            hasher.update(transform.to_string().as_bytes());
+        }
+    }
+    hash_transform_includes(&mut hasher, gate_data, options)?;
+    hasher.update([options.native as u8]);
+    let pack = format!("{:x}", hasher.finalize());
+    fingerprints.insert(BuildSteps::Pack, pack.clone());
+    fingerprints.insert(BuildSteps::Publish, pack);
+
+    Ok(fingerprints)
+}
+
+/// Hashes the names and contents of the transform files packing includes.
+/// A given include directory is hashed as a whole, without one only the
+/// files the gate includes are looked up next to the gate file.
+fn hash_transform_includes(
+    hasher: &mut Sha256,
+    gate_data: Option<&Gate>,
+    options: &BuildOptions,
+) -> Result<()> {
+    if options.transform_include_dir.is_some() {
+        return hash_files(hasher, &options.include_dir, &options.include_dir, &[]);
+    }
+
+    for include in gate_data
+        .iter()
+        .flat_map(|g| g.default_transforms.iter())
+        .filter_map(|tr| tr.get_include())
+    {
+        let path = options.include_dir.join(include.trim_matches('"'));
+        if path.is_file() {
+            hasher.update(include.as_bytes());
+            hasher.update(std::fs::read(&path).into_diagnostic()?);
+        }
+    }
+    Ok(())
+}
+
+/// Files of the bundle that are not hashed with the others. The package.kdl
+/// and manifest.mog are part of later steps and the package.lock is written
+/// by the download itself.
+const UNHASHED_BUNDLE_FILES: [&str; 3] = ["package.kdl", "package.lock", "manifest.mog"];
+
+/// Hashes the names and contents of all files below the directory except
+/// the skipped ones given relative to the root.
+fn hash_files(hasher: &mut Sha256, root: &Path, dir: &Path, skip: &[&str]) -> Result<()> {
+    let mut entries = read_dir(dir)
+        .into_diagnostic()?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .into_diagnostic()?;
+    entries.sort();
+
+    for path in entries {
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if skip.iter().any(|name| relative == Path::new(name)) {
+            continue;
+        }
+
+        if path.is_dir() {
+            hash_files(hasher, root, &path, skip)?;
+        } else if path.is_file() {
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update(std::fs::read(&path).into_diagnostic()?);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    fn digest(dir: &Path, skip: &[&str]) -> String {
+        let mut hasher = Sha256::new();
+        hash_files(&mut hasher, dir, dir, skip).unwrap();
+        format!("{:x}", hasher.finalize())
+    }
+
+    const GATE: &str = r#"
+name "test"
+version "0.5.11"
+branch "2024.1.0"
+publisher "test.org"
+transform include="local"
+"#;
+
+    fn options(include_dir: &Path) -> BuildOptions {
+        BuildOptions {
+            stop_on_step: None,
+            from_step: None,
+            resume: true,
+            archive_clean: false,
+            allow_unverified: false,
+            transform_include_dir: None,
+            include_dir: include_dir.to_path_buf(),
+            native: true,
+            vendor_dir: None,
+            locked: false,
+            target: None,
+            sandbox: crate::sandbox::SandboxBackend::Host,
+            network: true,
+        }
+    }
+
+    #[test]
+    fn fingerprints_without_build_dir() -> Result<()> {
+        let dir = test_dir("state-include-dir");
+        std::fs::write(dir.join("test.kdl"), GATE).into_diagnostic()?;
+        std::fs::write(dir.join("local"), "<transform file -> drop>\n").into_diagnostic()?;
+        for name in ["first", "second"] {
+            let package_dir = dir.join("packages").join(name);
+            std::fs::create_dir_all(&package_dir).into_diagnostic()?;
+            std::fs::write(
+                package_dir.join("package.kdl"),
+                format!("name \"{}\"\nproject-name \"{}\"\n", name, name),
+            )
+            .into_diagnostic()?;
+        }
+        let gate = Gate::new(dir.join("test.kdl"))?;
+        let first = Bundle::open_local(dir.join("packages/first"))?;
+        let second = Bundle::open_local(dir.join("packages/second"))?;
+        let options = options(&dir);
+
+        // The build of the first package leaves the process in its build
+        // directory which is removed before the next package is built
+        let previous_dir = std::env::current_dir().into_diagnostic()?;
+        let build_dir = dir.join("build");
+        std::fs::create_dir_all(&build_dir).into_diagnostic()?;
+        std::env::set_current_dir(&build_dir).into_diagnostic()?;
+        let result = step_fingerprints(&first, Some(&gate), &options).and_then(|_| {
+            std::fs::remove_dir_all(&build_dir).into_diagnostic()?;
+            step_fingerprints(&second, Some(&gate), &options)
+        });
+        std::env::set_current_dir(previous_dir).into_diagnostic()?;
+        let before = result?;
+
+        std::fs::write(dir.join("local"), "<transform dir -> drop>\n").into_diagnostic()?;
+        let after = step_fingerprints(&second, Some(&gate), &options)?;
+        assert_eq!(before[&BuildSteps::Build], after[&BuildSteps::Build]);
+        assert_ne!(before[&BuildSteps::Pack], after[&BuildSteps::Pack]);
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_file_names_and_contents() {
+        let dir = test_dir("state-hash-files");
+        std::fs::create_dir_all(dir.join("patches")).unwrap();
+        std::fs::write(dir.join("patches/01-fix.patch"), "old").unwrap();
+        std::fs::write(dir.join("package.lock"), "first").unwrap();
+        let first = digest(&dir, &UNHASHED_BUNDLE_FILES);
+
+        std::fs::write(dir.join("package.lock"), "second").unwrap();
+        assert_eq!(digest(&dir, &UNHASHED_BUNDLE_FILES), first);
+        assert_ne!(digest(&dir, &[]), digest(&dir, &["package.lock"]));
+
+        std::fs::write(dir.join("patches/01-fix.patch"), "new").unwrap();
+        let changed = digest(&dir, &UNHASHED_BUNDLE_FILES);
+        assert_ne!(changed, first);
+
+        std::fs::rename(
+            dir.join("patches/01-fix.patch"),
+            dir.join("patches/02-fix.patch"),
+        )
+        .unwrap();
+        assert_ne!(digest(&dir, &UNHASHED_BUNDLE_FILES), changed);
+    }
+}
@@ -1,6 +1,6 @@
 use std::process::{Command, Stdio};
 
-use crate::workspace::Workspace;
+use crate::{logs::run_logged, workspace::Workspace};
 use bundle::Bundle;
 use miette::IntoDiagnostic;
 
@@ -44,14 +44,14 @@ pub fn make_release_tarball(wks: &Workspace, pkg: &Bundle) -> miette::Result<()>
             .as_slice(),
     );
     tar_cmd.stdout(Stdio::inherit());
-    let tar_cmd_status = tar_cmd.status().into_diagnostic()?;
+    let tar_cmd_status = run_logged(wks, &mut tar_cmd)?;
 
     if tar_cmd_status.success() {
         println!("Generated Output tarball {}", tarball_path_string);
         Ok(())
     } else {
         Err(miette::miette!(
-            "gtar returned error code check above for error"
+            "gtar returned error code check the step log for the error"
         ))
     }
 }
@@ -1,19 +1,19 @@
 use std::{
     fs::{read_dir, DirBuilder},
     path::{Path, PathBuf},
-    process::Command,
 };
 
-use bundle::SourceSection;
+use bundle::{PackageLock, SourceSection};
 use miette::{IntoDiagnostic, Result, WrapErr};
 
-use crate::{config::Settings, derive_source_name, path::add_extension, workspace::Workspace};
+use crate::{cache, derive_source_name, download, patch, workspace::Workspace};
 
 pub fn unpack_sources<P: AsRef<Path>>(
     wks: &Workspace,
     package_name: String,
     bundle_path: P,
     sources: &[SourceSection],
+    lock: &PackageLock,
 ) -> Result<()> {
     let bundle_path = bundle_path.as_ref();
     let build_dir = wks.get_or_create_build_dir()?;
@@ -26,19 +26,19 @@ pub fn unpack_sources<P: AsRef<Path>>(
         for (node_idx, src) in source.sources.clone().into_iter().enumerate() {
             match src {
                 bundle::SourceNode::Archive(archive) => {
-                    let src_url: url::Url = archive.src.parse().into_diagnostic()?;
-
-                    let local_file = wks.get_file_path(src_url.clone())?;
-
-                    let file_name = local_file.file_name().ok_or(miette::miette!("Archive must have a file_name. A Folder with / at the end can not be an archive"))?;
-                    let archive_path =
-                        Settings::get_or_create_archives_dir()?.join(Path::new(file_name));
+                    let archive_path = cache::lookup(&archive)?.ok_or(miette::miette!(
+                        "archive {} is not in the cache, run the download step first",
+                        archive.src
+                    ))?;
 
                     archive_unpack(&archive_path, &unpack_path, &package_name)?;
                 }
                 bundle::SourceNode::Git(git_src) => {
-                    let file_name = add_extension(git_src.get_repo_prefix(), "tar.gz");
-                    let archive_path = Settings::get_or_create_archives_dir()?.join(&file_name);
+                    let commit = download::locked_commit(lock, &git_src).ok_or(miette::miette!(
+                        "git source {} has no commit in package.lock, run the download step first",
+                        git_src.repository
+                    ))?;
+                    let archive_path = download::git_archive_path(&git_src, &commit)?;
                     if node_idx == 0 && source_idx == 0 {
                         archive_unpack(&archive_path, &unpack_path, &package_name)?;
                     } else {
@@ -74,25 +74,13 @@ pub fn unpack_sources<P: AsRef<Path>>(
                     std::fs::copy(src_path, final_path).into_diagnostic()?;
                 }
                 bundle::SourceNode::Patch(patch) => {
-                    let src_path = patch
-                        .get_bundle_path(bundle_path)
-                        .to_string_lossy()
-                        .to_string();
-                    let unpack_arg = unpack_path.to_string_lossy().to_string();
-                    let mut patch_cmd = Command::new("gpatch");
-                    patch_cmd.arg("-d");
-                    patch_cmd.arg(&unpack_arg);
-                    if let Some(drop_directories) = patch.drop_directories {
-                        let strip_arg = format!("-p{}", drop_directories);
-                        patch_cmd.arg(&strip_arg);
-                    }
-                    patch_cmd.arg("-i");
-                    patch_cmd.arg(&src_path);
-
-                    let status = patch_cmd.status().into_diagnostic()?;
-
-                    if !status.success() {
-                        return Err(miette::miette!("failed to patch sources"));
+                    let src_path = patch.get_bundle_path(bundle_path);
+                    for (patch_path, strip) in
+                        patch::patch_series(&src_path, patch.drop_directories)?
+                    {
+                        println!("Applying patch {}", patch_path.display());
+                        let patch_file = patch::Patch::open(&patch_path)?;
+                        patch::apply_patch(&patch_file, &unpack_path, strip)?;
                     }
                 }
                 bundle::SourceNode::Overlay(overlay) => {
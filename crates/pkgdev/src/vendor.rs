@@ -0,0 +1,274 @@
+use std::{
+    collections::BTreeMap,
+    fs::{read_to_string, DirBuilder, File},
+    io::Write,
+    path::Path,
+};
+
+use crate::{
+    cache::{self, CacheEntry},
+    download,
+    workspace::Workspace,
+};
+use bundle::{ArchiveSource, Bundle, GitSource, PackageLock};
+use gate::Gate;
+use miette::{Diagnostic, IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const INDEX_FILE: &str = "index.json";
+const ARCHIVES_DIR: &str = "sha256";
+const GIT_DIR: &str = "git";
+
+#[derive(Debug, Error, Diagnostic)]
+pub(crate) enum VendorError {
+    #[error("{0} is not vendored in {1}")]
+    #[diagnostic(
+        code(pkgdev::vendor::missing),
+        help("run pkgdev vendor for this package on a machine with network access and copy the directory over")
+    )]
+    NotVendored(String, String),
+    #[error("vendored archive {0} does not match its checksum")]
+    #[diagnostic(
+        code(pkgdev::vendor::corrupt),
+        help("the vendor directory was damaged while copying it, vendor the sources again")
+    )]
+    Corrupt(String),
+}
+
+/// The index of a vendor directory. Archives are stored under their sha256
+/// like in the archive cache, git sources as the archive made from the
+/// repository at the commit they resolved to.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct VendorIndex {
+    pub archives: BTreeMap<String, CacheEntry>,
+    pub git: Vec<VendoredGit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct VendoredGit {
+    pub repository: String,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub commit: String,
+}
+
+impl VendoredGit {
+    fn matches(&self, git: &GitSource) -> bool {
+        self.repository == git.repository && self.branch == git.branch && self.tag == git.tag
+    }
+}
+
+impl VendorIndex {
+    pub fn load(vendor_dir: &Path) -> Result<Self> {
+        let path = vendor_dir.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = read_to_string(&path).into_diagnostic()?;
+        serde_json::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err(format!("could not read vendor index {}", path.display()))
+    }
+
+    pub fn save(&self, vendor_dir: &Path) -> Result<()> {
+        let mut f = File::create(vendor_dir.join(INDEX_FILE)).into_diagnostic()?;
+        f.write_all(
+            serde_json::to_string_pretty(self)
+                .into_diagnostic()?
+                .as_bytes(),
+        )
+        .into_diagnostic()?;
+        Ok(())
+    }
+}
+
+/// Downloads all sources of the packages and copies them into the vendor
+/// directory. Sources already in the directory are kept so several gates
+/// can share one.
+pub(crate) fn vendor_packages(
+    wks: &Workspace,
+    packages: &[Bundle],
+    gate_data: Option<&Gate>,
+    vendor_dir: &Path,
+    allow_unverified: bool,
+) -> Result<()> {
+    for dir in [ARCHIVES_DIR, GIT_DIR] {
+        DirBuilder::new()
+            .recursive(true)
+            .create(vendor_dir.join(dir))
+            .into_diagnostic()?;
+    }
+
+    let archives = packages
+        .iter()
+        .flat_map(|pkg| {
+            download::archives_of(pkg)
+                .into_iter()
+                .map(move |archive| (pkg, archive))
+        })
+        .collect::<Vec<_>>();
+    download::fetch_archives(wks, &archives, gate_data, false, allow_unverified)?;
+
+    let mut index = VendorIndex::load(vendor_dir)?;
+    let cache_index = cache::CacheIndex::load()?;
+    for (_, archive) in &archives {
+        let cached_path = cache::lookup(archive)?.ok_or(miette::miette!(
+            "archive {} is missing from the cache after downloading it",
+            archive.src
+        ))?;
+        let entry = match cache_index.archives.get(&archive.src) {
+            Some(entry) => entry.clone(),
+            None => {
+                let (sha256, sha512) = cache::file_digests(&cached_path)?;
+                CacheEntry {
+                    sha256,
+                    sha512,
+                    file_name: archive
+                        .src
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default()
+                        .to_owned(),
+                }
+            }
+        };
+
+        let vendored_path = vendor_dir.join(ARCHIVES_DIR).join(&entry.sha256);
+        if !vendored_path.exists() {
+            std::fs::copy(&cached_path, &vendored_path).into_diagnostic()?;
+        }
+        println!("Vendored {}", archive.src);
+        index.archives.insert(archive.src.clone(), entry);
+    }
+
+    for pkg in packages {
+        download::download_and_verify(wks, pkg, gate_data, false, allow_unverified)?;
+        let lock = PackageLock::open(pkg.get_lock_path())?;
+        for git in download::git_sources_of(pkg) {
+            let commit = download::locked_commit(&lock, git).ok_or(miette::miette!(
+                "git source {} was fetched without resolving its commit",
+                git.repository
+            ))?;
+            let archive_path = download::git_archive_path(git, &commit)?;
+            let file_name = archive_path.file_name().unwrap_or_default();
+            std::fs::copy(&archive_path, vendor_dir.join(GIT_DIR).join(file_name))
+                .into_diagnostic()
+                .wrap_err(format!("could not vendor git source {}", git.repository))?;
+            println!("Vendored {} at {}", git.repository, commit);
+            index.git.retain(|v| !v.matches(git) || v.commit != commit);
+            index.git.push(VendoredGit {
+                repository: git.repository.clone(),
+                branch: git.branch.clone(),
+                tag: git.tag.clone(),
+                commit,
+            });
+        }
+    }
+
+    index.save(vendor_dir)
+}
+
+/// Puts all sources of the package from the vendor directory into the
+/// archive cache without touching the network. Archives are checked against
+/// the checksums recorded when they were vendored.
+pub(crate) fn resolve_sources(pkg: &Bundle, vendor_dir: &Path, archive_clean: bool) -> Result<()> {
+    let index = VendorIndex::load(vendor_dir)?;
+    let vendor_name = vendor_dir.display().to_string();
+
+    for archive in download::archives_of(pkg) {
+        if !archive_clean && cache::lookup(archive)?.is_some() {
+            println!("Archive {} is cached skipping", archive.src);
+            continue;
+        }
+
+        let entry = vendored_archive(&index, archive).ok_or(VendorError::NotVendored(
+            archive.src.clone(),
+            vendor_name.clone(),
+        ))?;
+        let vendored_path = vendor_dir.join(ARCHIVES_DIR).join(&entry.sha256);
+        if !vendored_path.exists() {
+            return Err(VendorError::NotVendored(archive.src.clone(), vendor_name.clone()).into());
+        }
+
+        let (sha256, sha512) = cache::file_digests(&vendored_path)?;
+        if sha256 != entry.sha256 || !sha512.eq_ignore_ascii_case(&entry.sha512) {
+            return Err(VendorError::Corrupt(vendored_path.display().to_string()).into());
+        }
+
+        cache::import(&vendored_path, Some(&archive.src))?;
+        println!("Using vendored archive {}", archive.src);
+    }
+
+    let mut lock = PackageLock::open(pkg.get_lock_path())?;
+    let mut lock_changed = false;
+    for git in download::git_sources_of(pkg) {
+        // Without a pinned or locked commit use the one that was vendored
+        let commit = match download::locked_commit(&lock, git) {
+            Some(commit) => commit,
+            None => {
+                let vendored = index.git.iter().rev().find(|v| v.matches(git)).ok_or(
+                    VendorError::NotVendored(git.repository.clone(), vendor_name.clone()),
+                )?;
+                lock.set_commit(git, &vendored.commit);
+                lock_changed = true;
+                vendored.commit.clone()
+            }
+        };
+
+        let archive_path = download::git_archive_path(git, &commit)?;
+        if archive_clean {
+            std::fs::remove_file(&archive_path).ok();
+        }
+        if archive_path.exists() {
+            continue;
+        }
+
+        let vendored_path = vendor_dir
+            .join(GIT_DIR)
+            .join(archive_path.file_name().unwrap_or_default());
+        if !vendored_path.exists() {
+            return Err(VendorError::NotVendored(
+                format!("{} at {}", git.repository, commit),
+                vendor_name.clone(),
+            )
+            .into());
+        }
+
+        std::fs::copy(&vendored_path, &archive_path).into_diagnostic()?;
+        println!("Using vendored git source {} at {}", git.repository, commit);
+    }
+
+    if lock_changed {
+        lock.save(pkg.get_lock_path())?;
+    }
+
+    Ok(())
+}
+
+/// Finds the vendored copy by the declared sha256 or else by url as long as
+/// all declared checksums match.
+fn vendored_archive<'a>(index: &'a VendorIndex, archive: &ArchiveSource) -> Option<&'a CacheEntry> {
+    let matches = |entry: &&CacheEntry| {
+        archive
+            .sha256
+            .as_ref()
+            .map_or(true, |sha256| sha256.eq_ignore_ascii_case(&entry.sha256))
+            && archive
+                .sha512
+                .as_ref()
+                .map_or(true, |sha512| sha512.eq_ignore_ascii_case(&entry.sha512))
+    };
+
+    index
+        .archives
+        .get(&archive.src)
+        .filter(matches)
+        .or_else(|| {
+            archive
+                .sha256
+                .as_ref()
+                .and_then(|_| index.archives.values().find(matches))
+        })
+}
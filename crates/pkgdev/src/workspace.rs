@@ -3,7 +3,7 @@ use miette::Diagnostic;
 use sha2::Digest;
 use std::{
     fs::DirBuilder,
-    io::Write,
+    io::{Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
 };
 use thiserror::Error;
@@ -21,9 +21,10 @@ pub enum WorkspaceError {
 
 type Result<T> = miette::Result<T, WorkspaceError>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Workspace {
     path: PathBuf,
+    step_log: Option<PathBuf>,
 }
 
 impl Workspace {
@@ -32,7 +33,10 @@ impl Workspace {
             DirBuilder::new().recursive(true).create(path.as_ref())?;
         }
         let full_path = std::fs::canonicalize(path.as_ref())?;
-        Ok(Self { path: full_path })
+        Ok(Self {
+            path: full_path,
+            step_log: None,
+        })
     }
 
     pub fn get_or_create_download_dir(&self) -> Result<PathBuf> {
@@ -52,13 +56,20 @@ impl Workspace {
         ))
     }
 
+    /// Opens the download file for the url continuing a partial download.
+    /// The name starts with a hash of the url so files with the same name
+    /// from different urls can be downloaded at the same time.
     pub fn open_local_file(&self, url: url::Url, hasher_kind: HasherKind) -> Result<DownloadFile> {
         let download_dir = self.get_or_create_download_dir()?;
-        let p = download_dir.join(
-            Path::new(url.path())
-                .file_name()
-                .ok_or(WorkspaceError::InvalidURLError(url.clone()))?,
-        );
+        let file_name = Path::new(url.path())
+            .file_name()
+            .ok_or(WorkspaceError::InvalidURLError(url.clone()))?;
+        let url_hash = format!("{:x}", sha2::Sha256::digest(url.as_str()));
+        let p = download_dir.join(format!(
+            "{}-{}",
+            &url_hash[..16],
+            file_name.to_string_lossy()
+        ));
         DownloadFile::new(p, hasher_kind)
     }
 
@@ -79,6 +90,10 @@ impl Workspace {
         DownloadFile::new(p, hasher_kind)
     }
 
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn get_name(&self) -> String {
         let name_path = self.path.file_name().unwrap();
         name_path.to_string_lossy().to_string()
@@ -107,6 +122,82 @@ impl Workspace {
         }
         Ok(p)
     }
+
+    /// The private home and temporary directories of sandboxed builds.
+    pub fn get_or_create_sandbox_dir(&self) -> Result<PathBuf> {
+        let p = self.path.join("sandbox");
+        if !p.exists() {
+            DirBuilder::new().recursive(true).create(&p)?;
+        }
+        Ok(p)
+    }
+
+    /// The dependencies a package is built against are staged here.
+    pub fn get_or_create_sysroot_dir(&self) -> Result<PathBuf> {
+        let p = self.path.join("sysroot");
+        if !p.exists() {
+            DirBuilder::new().recursive(true).create(&p)?;
+        }
+        Ok(p)
+    }
+
+    pub fn get_or_create_log_dir(&self) -> Result<PathBuf> {
+        let p = self.path.join("logs");
+        if !p.exists() {
+            DirBuilder::new().recursive(true).create(&p)?;
+        }
+        Ok(p)
+    }
+
+    pub fn get_package_log_dir(&self, package: &str) -> Result<PathBuf> {
+        Ok(self
+            .get_or_create_log_dir()?
+            .join(package.replace('/', "_")))
+    }
+
+    /// Returns the workspace with a fresh log file for the given step of a
+    /// package. Tools run through [`crate::logs::run_logged`] write their
+    /// output into it instead of the terminal.
+    pub fn with_step_log(&self, package: &str, step: &str) -> Result<Self> {
+        let log_dir = self.get_package_log_dir(package)?;
+        if !log_dir.exists() {
+            DirBuilder::new().recursive(true).create(&log_dir)?;
+        }
+        let log_path = log_dir.join(format!("{}.log", step));
+        std::fs::File::create(&log_path)?;
+        Ok(Self {
+            path: self.path.clone(),
+            step_log: Some(log_path),
+        })
+    }
+
+    pub fn get_step_log(&self) -> Option<&Path> {
+        self.step_log.as_deref()
+    }
+
+    /// The file recording which build steps completed in this workspace.
+    pub fn get_build_state_path(&self) -> PathBuf {
+        self.path.join("build-state.json")
+    }
+
+    /// The copy of the unpacked sources edits are compared against to write
+    /// patches.
+    pub fn get_snapshot_dir(&self) -> PathBuf {
+        self.path.join("snapshot")
+    }
+
+    pub fn get_snapshot_path(&self) -> PathBuf {
+        self.path.join("snapshot.json")
+    }
+
+    /// The prototypes of the packages built so far in a gate build.
+    pub fn get_gate_protos_dir(&self) -> PathBuf {
+        self.path.join("gate-protos")
+    }
+
+    pub fn get_gate_proto_dir(&self, package: &str) -> PathBuf {
+        self.get_gate_protos_dir().join(package)
+    }
 }
 
 #[allow(dead_code)]
@@ -122,31 +213,80 @@ pub struct DownloadFile {
     hasher256: sha2::Sha256,
     hasher_kind: HasherKind,
     error: Option<String>,
+    resumed_from: u64,
 }
 
 impl DownloadFile {
+    /// Opens the download file. The content of a partial download that is
+    /// already there is fed to the hashers so the download can be resumed
+    /// at its end.
     fn new<P: AsRef<Path>>(path: P, kind: HasherKind) -> Result<Self> {
+        let mut handle = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path.as_ref())?;
+
+        let mut hasher512 = sha2::Sha512::new();
+        let mut hasher256 = sha2::Sha256::new();
+        let mut buf = [0u8; 64 * 1024];
+        let mut resumed_from = 0;
+        loop {
+            let len = handle.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher512.update(&buf[..len]);
+            hasher256.update(&buf[..len]);
+            resumed_from += len as u64;
+        }
+
         Ok(DownloadFile {
             path: path.as_ref().clone().to_path_buf(),
-            handle: std::fs::File::options()
-                .read(true)
-                .write(true)
-                .create_new(true)
-                .open(path)?,
+            handle,
             hasher_kind: kind,
-            hasher512: sha2::Sha512::new(),
-            hasher256: sha2::Sha256::new(),
+            hasher512,
+            hasher256,
             error: None,
+            resumed_from,
         })
     }
 
+    /// The amount of bytes that were already downloaded before.
+    pub fn resumed_from(&self) -> u64 {
+        self.resumed_from
+    }
+
+    /// Throws away the partial download for servers that do not support
+    /// range requests and send the whole file again.
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.handle.set_len(0)?;
+        self.handle.seek(SeekFrom::Start(0))?;
+        self.hasher512 = sha2::Sha512::new();
+        self.hasher256 = sha2::Sha256::new();
+        self.resumed_from = 0;
+        Ok(())
+    }
+
+    pub fn get_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
     pub fn get_hash(&mut self) -> String {
         match self.hasher_kind {
-            HasherKind::Sha256 => format!("{:x}", self.hasher256.clone().finalize()),
-            HasherKind::Sha512 => format!("{:x}", self.hasher512.clone().finalize()),
+            HasherKind::Sha256 => self.get_sha256(),
+            HasherKind::Sha512 => self.get_sha512(),
         }
     }
 
+    pub fn get_sha256(&self) -> String {
+        format!("{:x}", self.hasher256.clone().finalize())
+    }
+
+    pub fn get_sha512(&self) -> String {
+        format!("{:x}", self.hasher512.clone().finalize())
+    }
+
     pub fn get_path(&self) -> PathBuf {
         self.path.clone().to_path_buf()
     }
@@ -170,11 +310,29 @@ impl Handler for DownloadFile {
                 return Err(WriteError::Pause);
             }
         };
-        match self.hasher_kind {
-            HasherKind::Sha256 => self.hasher256.update(data),
-            HasherKind::Sha512 => self.hasher512.update(data),
-        }
+        // Both digests are computed so an archive can be checked against
+        // every checksum its source declares.
+        self.hasher256.update(&data[..len]);
+        self.hasher512.update(&data[..len]);
 
         Ok(len)
     }
+
+    fn header(&mut self, data: &[u8]) -> bool {
+        let line = String::from_utf8_lossy(data);
+        if self.resumed_from > 0
+            && line.starts_with("HTTP/")
+            && line.split_whitespace().nth(1) == Some("200")
+        {
+            if let Err(e) = self.restart() {
+                self.error = Some(format!(
+                    "could not restart download of {}: {}",
+                    self.path.display(),
+                    e
+                ));
+                return false;
+            }
+        }
+        true
+    }
 }